@@ -3,16 +3,15 @@ use lofty::file::TaggedFileExt;
 use lofty::prelude::ItemKey;
 use lofty::file::AudioFile;
 use clap::{Parser, Subcommand, ArgAction};
-use serde::Deserialize;
-use walkdir;
+use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 use std::fs;
-use strsim;
+use std::io::{IsTerminal, Write};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use std::collections::HashMap;
 use globset::{Glob, GlobSetBuilder};
 use rayon::prelude::*;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 use std::thread;
@@ -20,10 +19,10 @@ use std::thread;
 // Helper functions to replace removed dependencies
 
 fn expand_tilde(path: &str) -> String {
-    if path.starts_with('~') {
-        if let Some(home) = std::env::var_os("HOME") {
-            return path.replacen('~', &home.to_string_lossy(), 1);
-        }
+    if path.starts_with('~')
+        && let Some(home) = std::env::var_os("HOME")
+    {
+        return path.replacen('~', &home.to_string_lossy(), 1);
     }
     path.to_string()
 }
@@ -50,7 +49,17 @@ fn get_dir_size(path: &str) -> std::io::Result<u64> {
     Ok(total)
 }
 
-// Simple ANSI color helpers
+// Simple ANSI color helpers, disabled by --no-color, NO_COLOR or a non-TTY stdout
+static COLOR_ENABLED: AtomicBool = AtomicBool::new(true);
+
+fn paint(s: &str, code: &str) -> String {
+    if COLOR_ENABLED.load(Ordering::Relaxed) {
+        format!("\x1b[{}m{}\x1b[0m", code, s)
+    } else {
+        s.to_string()
+    }
+}
+
 trait Colorize {
     fn red(&self) -> String;
     fn green(&self) -> String;
@@ -61,28 +70,192 @@ trait Colorize {
 }
 
 impl Colorize for str {
-    fn red(&self) -> String { format!("\x1b[31m{}\x1b[0m", self) }
-    fn green(&self) -> String { format!("\x1b[32m{}\x1b[0m", self) }
-    fn yellow(&self) -> String { format!("\x1b[33m{}\x1b[0m", self) }
-    fn cyan(&self) -> String { format!("\x1b[36m{}\x1b[0m", self) }
-    fn bold(&self) -> String { format!("\x1b[1m{}\x1b[0m", self) }
-    fn underline(&self) -> String { format!("\x1b[4m{}\x1b[0m", self) }
+    fn red(&self) -> String { paint(self, "31") }
+    fn green(&self) -> String { paint(self, "32") }
+    fn yellow(&self) -> String { paint(self, "33") }
+    fn cyan(&self) -> String { paint(self, "36") }
+    fn bold(&self) -> String { paint(self, "1") }
+    fn underline(&self) -> String { paint(self, "4") }
+}
+
+fn terminal_width() -> usize {
+    console::Term::stdout()
+        .size_checked()
+        .map(|(_, cols)| cols as usize)
+        .unwrap_or(120)
+}
+
+fn truncate_to_width(s: &str, width: usize) -> String {
+    if s.chars().count() <= width {
+        return s.to_string();
+    }
+    let mut truncated: String = s.chars().take(width.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (i, cell) in row.iter().enumerate() {
+            widths[i] = widths[i].max(cell.chars().count());
+        }
+    }
+
+    // Shrink the widest column until the table fits in the terminal
+    let available = terminal_width().saturating_sub(2 * (widths.len() - 1));
+    while widths.iter().sum::<usize>() > available {
+        let (widest, width) = widths.iter().copied().enumerate().max_by_key(|(_, w)| *w).unwrap();
+        if width <= 8 {
+            break;
+        }
+        widths[widest] -= 1;
+    }
+
+    let header: Vec<String> = headers.iter().zip(&widths)
+        .map(|(h, w)| format!("{:<w$}", truncate_to_width(h, *w), w = *w))
+        .collect();
+    println!("{}", header.join("  ").trim_end().bold());
+    let rule: Vec<String> = widths.iter().map(|w| "─".repeat(*w)).collect();
+    println!("{}", rule.join("  "));
+
+    for row in rows {
+        let cells: Vec<String> = row.iter().zip(&widths)
+            .map(|(c, w)| format!("{:<w$}", truncate_to_width(c, *w), w = *w))
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
 }
 
-fn write_csv_row<W: std::io::Write>(writer: &mut W, fields: &[&str]) -> std::io::Result<()> {
+fn write_csv_row<W: std::io::Write>(writer: &mut W, fields: &[&str], delimiter: char) -> std::io::Result<()> {
     let escaped: Vec<String> = fields.iter().map(|f| {
-        if f.contains(',') || f.contains('"') || f.contains('\n') {
+        if f.contains(delimiter) || f.contains('"') || f.contains('\n') {
             format!("\"{}\"", f.replace('"', "\"\""))
         } else {
             f.to_string()
         }
     }).collect();
-    writeln!(writer, "{}", escaped.join(","))
+    writeln!(writer, "{}", escaped.join(&delimiter.to_string()))
+}
+
+// How list-producing commands print their results, picked with the global --output-format.
+// Text keeps each command's own layout; the other formats go through `render`.
+#[derive(Clone, Copy, Default, PartialEq)]
+enum OutputFormat {
+    #[default]
+    Text,
+    Table,
+    Csv,
+    Json,
+}
+
+impl OutputFormat {
+    fn from_arg(value: &str) -> Self {
+        match value {
+            "table" => OutputFormat::Table,
+            "csv" => OutputFormat::Csv,
+            "json" => OutputFormat::Json,
+            _ => OutputFormat::Text,
+        }
+    }
+}
+
+static OUTPUT_FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+
+fn output_format() -> OutputFormat {
+    OUTPUT_FORMAT.get().copied().unwrap_or_default()
+}
+
+// A result row a command hands to `render`; JSON serializes the struct itself,
+// table and CSV use the headers and cells
+trait Renderable: Serialize {
+    fn headers() -> &'static [&'static str];
+    fn cells(&self) -> Vec<String>;
+}
+
+// Print `items` as a table, CSV or JSON; an empty list still prints the headers (or `[]`)
+fn render<T: Renderable>(items: &[T], format: OutputFormat) {
+    match format {
+        OutputFormat::Text | OutputFormat::Table => {
+            let rows: Vec<Vec<String>> = items.iter().map(Renderable::cells).collect();
+            print_table(T::headers(), &rows);
+        }
+        OutputFormat::Csv => {
+            let mut stdout = std::io::stdout().lock();
+            write_csv_row(&mut stdout, T::headers(), ',').expect("Failed to write CSV");
+            for item in items {
+                let cells = item.cells();
+                let fields: Vec<&str> = cells.iter().map(String::as_str).collect();
+                write_csv_row(&mut stdout, &fields, ',').expect("Failed to write CSV");
+            }
+        }
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(items).expect("Failed to serialize output"));
+        }
+    }
+}
+
+// Process exit codes, documented in the CLI's after_help
+const EXIT_SUCCESS: i32 = 0;
+const EXIT_ERROR: i32 = 1;
+const EXIT_CONFIG: i32 = 2;
+const EXIT_DATABASE: i32 = 3;
+const EXIT_PLAYBACK: i32 = 4;
+const EXIT_ISSUES_FOUND: i32 = 5;
+
+const EXIT_CODES_HELP: &str = "Exit codes:
+  0  success
+  1  generic error
+  2  configuration error
+  3  database error
+  4  playback error (Preview)
+  5  issues found (Dupes, Verify, Doctor)";
+
+fn exit_with_error(code: i32, message: &str) -> ! {
+    eprintln!("{}", format!("Error: {}", message).red());
+    std::process::exit(code);
 }
 
 /// Search for a pattern in a file and display the lines that contain it.
 #[derive(Parser)]
+#[command(after_help = EXIT_CODES_HELP)]
 struct Cli {
+    /// Disable colored output
+    #[arg(long, global = true, action = ArgAction::SetTrue)]
+    no_color: bool,
+
+    /// Use this database instead of the configured database_name
+    #[arg(long, global = true, value_name = "PATH")]
+    db: Option<String>,
+
+    /// Use this music directory instead of the configured music_directory
+    #[arg(long, global = true, value_name = "PATH")]
+    music_dir: Option<String>,
+
+    /// Split "Artist<SEP>Title" filenames on this exact string instead of the usual separators
+    #[arg(long, global = true, value_name = "SEP")]
+    artist_sep: Option<String>,
+
+    /// Print Ls, Search, Genres, Dupes and Stats results as text, a table, CSV or JSON
+    #[arg(long, global = true, value_name = "FORMAT", default_value = "text", value_parser = ["text", "table", "csv", "json"])]
+    output_format: String,
+
+    /// Copy the database to <db>.bak-<timestamp> before a destructive command runs
+    #[arg(long, global = true, action = ArgAction::SetTrue, conflicts_with = "no_backup")]
+    backup_db: bool,
+
+    /// Don't copy the database first, even when backup_db is set in the config
+    #[arg(long, global = true, action = ArgAction::SetTrue)]
+    no_backup: bool,
+
+    /// Copy the database into memory and read from the copy (read-only commands; faster on network mounts)
+    #[arg(long, global = true, action = ArgAction::SetTrue)]
+    in_memory: bool,
+
+    /// Open the database read-only, so nothing (not even a cache) is written to it
+    #[arg(long, global = true, action = ArgAction::SetTrue)]
+    read_only: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -91,15 +264,91 @@ struct Cli {
 enum Commands {
     /// Index the music library and playlists
     Index {
-        /// Show what would be moved but don't actually move files
+        /// Only index this file or subdirectory of the music directory (skips playlists)
+        #[arg(value_name = "PATH")]
+        path: Option<String>,
+
+        /// Move files into place according to the configured file_pattern
         #[arg(long, action = ArgAction::SetTrue)]
+        organize: bool,
+
+        /// Show what would be moved but don't actually move files
+        #[arg(long, action = ArgAction::SetTrue, requires = "organize")]
         dry_run: bool,
+
+        /// Print the resulting directory tree instead of moving files
+        #[arg(long, action = ArgAction::SetTrue, requires = "organize")]
+        preview_tree: bool,
+
+        /// Report files that could not be organized at the end and keep going (the default)
+        #[arg(long, action = ArgAction::SetTrue, requires = "organize", conflicts_with = "fail_fast")]
+        keep_going: bool,
+
+        /// Stop at the first file that cannot be organized, without updating the database
+        #[arg(long, action = ArgAction::SetTrue, requires = "organize")]
+        fail_fast: bool,
+
+        /// What to do when a different file is already at the destination (default: ask in a terminal, skip otherwise)
+        #[arg(long, value_name = "POLICY", value_parser = ["skip", "suffix", "overwrite", "ask"], requires = "organize")]
+        on_conflict: Option<String>,
+
+        /// Store a blake3 checksum of every file for Verify --checksums (reads each file in full)
+        #[arg(long, action = ArgAction::SetTrue)]
+        checksum: bool,
+
+        /// Maximum number of files read at once (default: parallel_io_limit from the config, or one per CPU core)
+        #[arg(long, value_name = "N")]
+        parallel_io_limit: Option<usize>,
+
+        /// Prompt for missing playlist entries even when a suggestion is above the auto-replace threshold
+        #[arg(long, action = ArgAction::SetTrue)]
+        confirm_auto: bool,
+
+        /// Don't index files missing a require_tags tag (artist and title when unset)
+        #[arg(long, action = ArgAction::SetTrue)]
+        strict: bool,
     },
     /// Find duplicate tracks
     Dupes {
         /// Interactively fix duplicates
         #[arg(long, action = ArgAction::SetTrue)]
         fix: bool,
+
+        /// Ignore a track id in future scans (ignored copies don't count toward a group's size)
+        #[arg(long, value_name = "ID")]
+        ignore: Vec<i64>,
+
+        /// Remove all ignored track ids
+        #[arg(long, action = ArgAction::SetTrue)]
+        clear_ignores: bool,
+
+        /// Show the folders each duplicate group lives in, flagging copies that share a folder
+        #[arg(long, action = ArgAction::SetTrue, conflicts_with = "fix")]
+        by_folder: bool,
+
+        /// Print exact and lower-quality duplicate groups as JSON instead of text
+        #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["fix", "by_folder"])]
+        json: bool,
+
+        /// Put the best copy of each quality duplicate at its file_pattern path and relocate the others to archive_directory
+        #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["fix", "by_folder", "json"])]
+        promote: bool,
+
+        /// Show what --promote would move but don't move anything
+        #[arg(long, action = ArgAction::SetTrue, requires = "promote")]
+        dry_run: bool,
+
+        /// Group tracks missing an artist or title tag by their normalized filename instead
+        #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["fix", "by_folder", "json", "promote"])]
+        by_filename: bool,
+
+        /// Only report groups with at least this many copies
+        #[arg(long, value_name = "N", default_value_t = 2, conflicts_with = "promote")]
+        min_count: usize,
+
+        /// List the most duplicated groups first
+        #[arg(long, action = ArgAction::SetTrue, conflicts_with = "promote")]
+        sort_by_count: bool,
     },
     /// List all tracks
     Ls {
@@ -110,19 +359,153 @@ enum Commands {
         /// Filter by genre
         #[arg(long)]
         genre: Option<String>,
+
+        /// Show each track's ReplayGain and flag unanalyzed or extreme values
+        #[arg(long, action = ArgAction::SetTrue)]
+        show_gain: bool,
+
+        /// Show each track's note
+        #[arg(long, action = ArgAction::SetTrue)]
+        notes: bool,
+
+        /// Only list tracks rated at least this many stars (0-5)
+        #[arg(long, value_name = "STARS", value_parser = clap::value_parser!(u8).range(0..=5))]
+        min_rating: Option<u8>,
+
+        /// Group tracks under "Album artist — Album (year)" headers in track-number order
+        #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["show_gain", "notes"])]
+        group_albums: bool,
+    },
+    /// Export tracks to CSV, or the whole catalog to a single file with --archive
+    Export {
+        /// Prepend a UTF-8 byte order mark so Excel detects the encoding
+        #[arg(long, action = ArgAction::SetTrue)]
+        bom: bool,
+
+        /// Field delimiter (e.g. ';' for locales that use a decimal comma)
+        #[arg(long, default_value_t = ',')]
+        delimiter: char,
+
+        /// Columns to export, in order, optionally renamed (e.g. "track_name=title,artist,album")
+        #[arg(long)]
+        columns: Option<String>,
+
+        /// Write tracks, favorites and playlists to this archive file instead of a CSV
+        #[arg(long, value_name = "FILE", conflicts_with_all = ["bom", "delimiter", "columns"])]
+        archive: Option<PathBuf>,
+    },
+    /// Restore tracks, favorites and playlists from an Export --archive file
+    Import {
+        /// Archive written by Export --archive
+        #[arg(long, required = true, value_name = "FILE")]
+        archive: PathBuf,
+
+        /// Overwrite playlist files that already exist
+        #[arg(long, action = ArgAction::SetTrue)]
+        force: bool,
+
+        /// Rewrite paths starting with OLD to start with NEW, e.g. for an archive from another machine (repeatable)
+        #[arg(long, value_name = "OLD=NEW", value_parser = parse_relocation)]
+        relocate: Vec<(String, String)>,
+
+        /// What to do with an archived track whose artist and title match a library track at another path
+        #[arg(long, value_name = "POLICY", value_parser = ["skip", "add", "replace"], default_value = "skip")]
+        on_duplicate: String,
     },
-    /// Export tracks to CSV
-    Export,
     /// Show statistics
-    Stats,
+    Stats {
+        /// List tracks with suspiciously short or long durations
+        #[arg(long, action = ArgAction::SetTrue)]
+        outliers: bool,
+
+        /// Break the library down by file format
+        #[arg(long, action = ArgAction::SetTrue)]
+        formats: bool,
+
+        /// Show how many tracks were added per week
+        #[arg(long, action = ArgAction::SetTrue)]
+        activity: bool,
+
+        /// Number of weeks shown by --activity
+        #[arg(long, default_value_t = 12)]
+        weeks: i64,
+
+        /// Count duplicate groups and the space freed by keeping only the best copy
+        #[arg(long, action = ArgAction::SetTrue)]
+        duplicates_summary: bool,
+
+        /// List artists that have tracks with a blank album
+        #[arg(long, action = ArgAction::SetTrue)]
+        artists_without_album: bool,
+
+        /// Show how much listening time was added in each calendar year
+        #[arg(long, action = ArgAction::SetTrue)]
+        per_year_added: bool,
+
+        /// Show track count and listening time per release decade
+        #[arg(long, action = ArgAction::SetTrue)]
+        by_decade: bool,
+
+        /// Show track counts per artist, crediting every artist split off by artist_delimiters
+        #[arg(long, action = ArgAction::SetTrue)]
+        by_artist: bool,
+
+        /// With --by-artist, count "Main feat. Guest", "Main & Guest" and the like under Main
+        #[arg(long, action = ArgAction::SetTrue, requires = "by_artist")]
+        primary_artist: bool,
+
+        /// List albums with fewer indexed tracks than their tagged track total
+        #[arg(long, action = ArgAction::SetTrue)]
+        incomplete_albums: bool,
+
+        /// List the N longest tracks
+        #[arg(long, value_name = "N")]
+        longest: Option<usize>,
+
+        /// List the N shortest tracks
+        #[arg(long, value_name = "N")]
+        shortest: Option<usize>,
+
+        /// Recompute the headline numbers instead of using the cached ones
+        #[arg(long, action = ArgAction::SetTrue)]
+        refresh: bool,
+
+        /// Draw a bar chart of tracks by duration, bitrate or decade
+        #[arg(long, value_name = "DIMENSION", value_parser = ["duration", "bitrate", "year"])]
+        histogram: Option<String>,
+
+        /// Total leading and trailing silence, measuring tracks not analyzed before with ffmpeg
+        #[arg(long, action = ArgAction::SetTrue)]
+        silence: bool,
+
+        /// Analyze at most this many new tracks for --silence in this run
+        #[arg(long, value_name = "N", requires = "silence")]
+        silence_limit: Option<usize>,
+    },
+    /// Probe durations that are still missing, optionally only for recently added tracks
+    Backfill {
+        /// Only tracks added on or after this date (YYYY-MM-DD)
+        #[arg(long, value_name = "DATE")]
+        since: Option<String>,
+
+        /// Only tracks added before this date (YYYY-MM-DD)
+        #[arg(long, value_name = "DATE")]
+        before: Option<String>,
+    },
     /// Search library
     Search {
-        /// Search Query
+        /// Search Query, matched as a substring or as word prefixes (`*` and `?` are wildcards; `%` and `_` match literally)
         #[arg(required = true)]
         query: String,
+
+        /// Treat the query as a regular expression (scans the whole library)
+        #[arg(long, action = ArgAction::SetTrue)]
+        regex: bool,
     },
     /// List all genres
     Genres,
+    /// Interactively create the config file
+    Setup,
     /// Compress audio files to mp3 for mobile sync
     Compress {
         /// Output directory for compressed files
@@ -149,6 +532,239 @@ enum Commands {
         #[arg()]
         query: Option<String>,
     },
+    /// Append a track to an existing playlist
+    PlaylistAdd {
+        /// Name of the playlist (as indexed)
+        #[arg(required = true)]
+        playlist: String,
+
+        /// Search query used to find the track
+        #[arg(required = true)]
+        track_query: String,
+    },
+    /// Report the most similar playlist pairs (Jaccard similarity) and the tracks in the most playlists
+    Playlists {
+        /// How many pairs and tracks to list
+        #[arg(long, value_name = "N", default_value_t = 10)]
+        top: usize,
+    },
+    /// Check indexed files for problems
+    Verify {
+        /// Recompute checksums and report files whose contents changed without a new mtime
+        #[arg(long, action = ArgAction::SetTrue)]
+        checksums: bool,
+
+        /// Stop hashing at the first changed file instead of scanning the whole library
+        #[arg(long, action = ArgAction::SetTrue, requires = "checksums")]
+        fail_fast: bool,
+    },
+    /// Check the library's tags for inconsistencies (runs every check when none is picked)
+    Doctor {
+        /// Report albums whose tracks disagree on album artist, year or genre
+        #[arg(long, action = ArgAction::SetTrue)]
+        album_consistency: bool,
+
+        /// Report files below the [quality] bitrate, sample rate or bit depth floors
+        #[arg(long, action = ArgAction::SetTrue)]
+        low_quality: bool,
+
+        /// Set each disagreeing field to the album's most common value, in the files and the database
+        #[arg(long, action = ArgAction::SetTrue)]
+        fix: bool,
+    },
+    /// Manage a persistent selection of favorite tracks
+    Fav {
+        #[command(subcommand)]
+        action: FavAction,
+    },
+    /// Attach free-text notes to tracks (kept in the database, never written to files)
+    Note {
+        #[command(subcommand)]
+        action: NoteAction,
+    },
+    /// Fix missing playlist entries against the indexed tracks without re-indexing
+    RepairPlaylists {
+        /// Apply confident replacements only, without prompting
+        #[arg(long, action = ArgAction::SetTrue)]
+        auto: bool,
+
+        /// Show what would be replaced without changing any playlist
+        #[arg(long, action = ArgAction::SetTrue)]
+        dry_run: bool,
+
+        /// First re-point entries moved by the last Index --organize, then repair what is still missing
+        #[arg(long, action = ArgAction::SetTrue)]
+        after_move: bool,
+    },
+    /// List playlist entries that Index auto-replaced, optionally reverting some
+    ReviewAutofixes {
+        /// Restore the original entry for a logged auto-replacement
+        #[arg(long, value_name = "ID")]
+        revert: Vec<i64>,
+    },
+    /// Fill in empty artist/title tags from "Artist - Title" filenames
+    TagFromFilename {
+        /// Print the proposed tags without writing anything
+        #[arg(long, action = ArgAction::SetTrue)]
+        dry_run: bool,
+    },
+    /// Embed each album folder's cover.jpg or folder.jpg into its tracks that have no art
+    EmbedArt {
+        /// List the files that would get art without writing anything
+        #[arg(long, action = ArgAction::SetTrue)]
+        dry_run: bool,
+
+        /// Replace the front cover of files that already have embedded art
+        #[arg(long, action = ArgAction::SetTrue)]
+        force: bool,
+    },
+    /// Clean up artist, album artist, album, title and genre tags using the [normalize] rules
+    Normalize {
+        /// Print the proposed changes without writing anything
+        #[arg(long, action = ArgAction::SetTrue)]
+        dry_run: bool,
+
+        /// Apply every change without asking track by track
+        #[arg(long, action = ArgAction::SetTrue, conflicts_with = "dry_run")]
+        yes: bool,
+
+        /// Also strip track-number prefixes and bracketed junk such as "[Official Video]" from titles
+        #[arg(long, action = ArgAction::SetTrue)]
+        strip_disc_from_title: bool,
+
+        /// Also repair mojibake such as "BeyoncÃ©" left by tags decoded as CP1252 instead of UTF-8
+        #[arg(long, alias = "repair-encoding", action = ArgAction::SetTrue)]
+        fix_encoding: bool,
+
+        /// Also move guests out of the artist tag into the title: "Main feat. Guest" - "Song"
+        /// becomes "Main" - "Song (feat. Guest)"
+        #[arg(long, action = ArgAction::SetTrue)]
+        canonicalize_feat: bool,
+    },
+    /// Remove junk tracks, such as silent clips or failed download fragments, from disk and the database
+    Trim {
+        /// Remove tracks of at most this many bytes
+        #[arg(long, value_name = "BYTES", required_unless_present = "max_duration")]
+        max_size: Option<u64>,
+
+        /// Remove tracks of at most this many seconds (tracks without a known duration are kept)
+        #[arg(long, value_name = "SECS")]
+        max_duration: Option<u64>,
+
+        /// List the tracks that would be removed without removing anything
+        #[arg(long, action = ArgAction::SetTrue)]
+        dry_run: bool,
+
+        /// Remove without asking for confirmation
+        #[arg(long, action = ArgAction::SetTrue, conflicts_with = "dry_run")]
+        yes: bool,
+    },
+    /// Play a short snippet of a track through ffplay, e.g. to tell duplicates apart
+    Preview {
+        /// Search query for the track, or #ID as shown by Dupes
+        #[arg(required = true)]
+        query: String,
+
+        /// How many seconds to play
+        #[arg(long, value_name = "SECS", default_value_t = 15)]
+        seconds: u32,
+
+        /// Where to start, in seconds from the beginning of the track
+        #[arg(long, value_name = "SECS", default_value_t = 30)]
+        from: u32,
+    },
+    /// Decode each track with ffmpeg and store its waveform peaks for external players, skipping tracks already done
+    Analyze {
+        /// How many peak values to store per track
+        #[arg(long, value_name = "N", default_value_t = 1000, value_parser = clap::value_parser!(u32).range(1..))]
+        resolution: u32,
+
+        /// Recompute peaks that are already stored
+        #[arg(long, action = ArgAction::SetTrue)]
+        force: bool,
+    },
+    /// Serve the library as a read-only JSON API: /tracks, /search?q=, /stats, /playlists and /peaks?path=
+    Serve {
+        /// Address to listen on; 0.0.0.0 makes the API reachable from other devices on the network
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+
+        /// Port to listen on
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+    },
+}
+
+#[derive(Subcommand)]
+enum FavAction {
+    /// Add the track best matching a query to favorites
+    Add {
+        #[arg(required = true)]
+        query: String,
+    },
+    /// Remove the track best matching a query from favorites
+    Rm {
+        #[arg(required = true)]
+        query: String,
+    },
+    /// List favorites
+    Ls,
+    /// Write favorites to an m3u playlist
+    Playlist {
+        /// Path of the playlist to write
+        #[arg(long, short = 'o')]
+        output: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum NoteAction {
+    /// Set the note of the track best matching a query (an empty note removes it)
+    Set {
+        #[arg(required = true)]
+        query: String,
+
+        #[arg(required = true)]
+        text: String,
+    },
+    /// Show the note of the track best matching a query
+    Show {
+        #[arg(required = true)]
+        query: String,
+    },
+}
+
+impl Commands {
+    // Commands that write to the database or move/delete files take the instance lock
+    fn mutates_library(&self) -> bool {
+        match self {
+            Commands::Index { .. } | Commands::PlaylistAdd { .. } | Commands::Backfill { .. } | Commands::Import { .. } => true,
+            Commands::Analyze { .. } => true,
+            Commands::TagFromFilename { dry_run } | Commands::EmbedArt { dry_run, .. } => !dry_run,
+            Commands::Doctor { fix, .. } => *fix,
+            Commands::Normalize { dry_run, .. } | Commands::Trim { dry_run, .. } => !dry_run,
+            Commands::ReviewAutofixes { revert } => !revert.is_empty(),
+            Commands::RepairPlaylists { dry_run, .. } => !dry_run,
+            Commands::Fav { action } => matches!(action, FavAction::Add { .. } | FavAction::Rm { .. }),
+            Commands::Note { action } => matches!(action, NoteAction::Set { .. }),
+            Commands::Dupes { fix, ignore, clear_ignores, promote, dry_run, .. } => {
+                *fix || !ignore.is_empty() || *clear_ignores || (*promote && !dry_run)
+            }
+            _ => false,
+        }
+    }
+
+    // Commands that move files or delete rows, so --backup-db snapshots the database before them
+    fn is_destructive(&self) -> bool {
+        match self {
+            Commands::Index { organize, dry_run, .. } => *organize && !dry_run,
+            Commands::ReviewAutofixes { revert } => !revert.is_empty(),
+            Commands::Dupes { fix, promote, dry_run, .. } => *fix || (*promote && !dry_run),
+            Commands::Import { .. } => true,
+            Commands::Trim { dry_run, .. } => !dry_run,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -157,65 +773,486 @@ struct FilesConfig {
     database_name: String,
     file_pattern: Option<String>,
     ignore: Option<Vec<String>>, // <-- Add this line
+    playlist_paths: Option<String>, // "relative" (default) or "absolute"
+    organize_mode: Option<String>, // "move" (default), "copy", "hardlink" or "symlink"
+    parallel_io_limit: Option<usize>, // concurrent file reads while indexing; keep it low on network mounts
+    archive_directory: Option<String>, // where Dupes --promote relocates lower-quality copies
+    genre_roots: Option<HashMap<String, String>>, // genre -> base directory for organized files, relative to music_directory
+    backup_db: Option<bool>, // snapshot the database before destructive commands (default false)
+    trash_directory: Option<String>, // where Trim moves removed files (keeping their layout) instead of deleting them
+    index_batch_size: Option<usize>, // tracks Index saves per transaction (default 1000)
+    busy_timeout_ms: Option<u64>, // how long a write waits for another connection's lock (default 5000)
 }
 
-#[derive(Debug, Deserialize)]
-struct Settings {
-    files: FilesConfig,
-    replace: Option<HashMap<String, String>>
-}
-
-fn sanitize_filename_component(s: &str, replacements: &Option<HashMap<String, String>>) -> String {
-    let mut result = String::with_capacity(s.len());
-    for c in s.chars() {
-        let mut replaced = false;
-        if let Some(map) = replacements {
-            for (from, to) in map {
-                if from.chars().count() == 1 && c == from.chars().next().unwrap() {
-                    result.push_str(to);
-                    replaced = true;
-                    break;
-                }
+const DEFAULT_INDEX_BATCH_SIZE: usize = 1000;
+const DEFAULT_BUSY_TIMEOUT_MS: u64 = 5000;
+
+// Set from files.busy_timeout_ms in main; every connection open_db returns waits this long
+static BUSY_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+// How many times a write that still finds the database locked after the busy timeout is retried
+const BUSY_RETRIES: u32 = 3;
+
+// Run a write, retrying with a growing pause while SQLite reports the database busy or locked,
+// e.g. while the WAL of an Index that just finished is still being checkpointed
+fn retry_when_busy<T>(mut write: impl FnMut() -> rusqlite::Result<T>) -> rusqlite::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match write() {
+            Err(e)
+                if attempt < BUSY_RETRIES
+                    && matches!(e.sqlite_error_code(), Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked)) =>
+            {
+                attempt += 1;
+                thread::sleep(Duration::from_millis(200 * u64::from(attempt)));
             }
-        }
-        if !replaced {
-            result.push(c);
+            result => return result,
         }
     }
-    result
 }
 
-fn index_library(settings: &Settings, dry_run: bool) {
-    let music_dir = expand_tilde(&settings.files.music_directory);
-    let db_path = expand_tilde(&settings.files.database_name);
-    let file_pattern = settings.files.file_pattern.as_deref();
+// Base directory an organized track's file_pattern path is joined to: the root of the first of
+// its genres listed in genre_roots, otherwise the music directory. Roots should stay inside the
+// music directory, since that is all Index scans.
+fn organize_root(files: &FilesConfig, music_dir: &str, genre: &str) -> PathBuf {
+    let root = files.genre_roots.as_ref().and_then(|roots| {
+        genre.split(',').map(str::trim).find_map(|g| {
+            roots.iter().find(|(name, _)| name.eq_ignore_ascii_case(g)).map(|(_, root)| root)
+        })
+    });
+    match root {
+        Some(root) => Path::new(music_dir).join(expand_tilde(root)),
+        None => PathBuf::from(music_dir),
+    }
+}
 
-    // Build ignore matcher
-    let mut glob_builder = GlobSetBuilder::new();
-    if let Some(ignore_patterns) = &settings.files.ignore {
-        for pattern in ignore_patterns {
-            if let Ok(glob) = Glob::new(pattern) {
-                glob_builder.add(glob);
-            }
+// How --organize places a file at its file_pattern path
+#[derive(Clone, Copy, PartialEq)]
+enum OrganizeMode {
+    Move,
+    Copy,
+    Hardlink,
+    Symlink,
+}
+
+impl OrganizeMode {
+    fn from_config(value: Option<&str>) -> Result<Self, String> {
+        match value.map(str::to_lowercase).as_deref() {
+            None | Some("move") => Ok(OrganizeMode::Move),
+            Some("copy") => Ok(OrganizeMode::Copy),
+            Some("hardlink") => Ok(OrganizeMode::Hardlink),
+            Some("symlink") => Ok(OrganizeMode::Symlink),
+            Some(other) => Err(format!(
+                "unknown organize_mode '{}' (expected move, copy, hardlink or symlink)",
+                other
+            )),
         }
     }
-    let glob_set = glob_builder.build().unwrap();
 
-    let entries: Vec<_> = walkdir::WalkDir::new(&music_dir)
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter(|e| e.file_type().is_file())
-        .filter(|e| {
-            // Get the path relative to music_dir for matching
-            let rel_path = e.path().strip_prefix(&music_dir).unwrap_or(e.path());
-            !glob_set.is_match(rel_path)
-        })
-        .collect();
+    fn verb(self) -> &'static str {
+        match self {
+            OrganizeMode::Move => "move",
+            OrganizeMode::Copy => "copy",
+            OrganizeMode::Hardlink => "hardlink",
+            OrganizeMode::Symlink => "symlink",
+        }
+    }
+}
 
-    // create or open the database
-    let mut conn = rusqlite::Connection::open(db_path).expect("Failed to open database");
+// What --organize does when its destination is already taken by another file
+#[derive(Clone, Copy, PartialEq)]
+enum OnConflict {
+    Skip,
+    Suffix,    // keep both, placing the new file at "name (2).ext"
+    Overwrite,
+    Ask,
+}
 
-    conn.execute(
+impl OnConflict {
+    fn from_arg(value: Option<&str>) -> Self {
+        match value {
+            Some("skip") => OnConflict::Skip,
+            Some("suffix") => OnConflict::Suffix,
+            Some("overwrite") => OnConflict::Overwrite,
+            Some("ask") => OnConflict::Ask,
+            // Scripted runs must never block on a prompt
+            _ if std::io::stdin().is_terminal() => OnConflict::Ask,
+            _ => OnConflict::Skip,
+        }
+    }
+}
+
+// Settle a conflict as skip, suffix or overwrite, prompting when the policy is Ask
+fn resolve_organize_conflict(policy: OnConflict, source: &Path, existing: &Path) -> OnConflict {
+    if policy != OnConflict::Ask {
+        return policy;
+    }
+    const KEEP_BOTH: &str = "Keep both (add a suffix)";
+    let options = vec![KEEP_BOTH, "Overwrite", "Skip", "Compare"];
+    loop {
+        let question = format!("{} already exists. What should happen to {}?", existing.display(), source.display());
+        match inquire::Select::new(&question, options.clone()).prompt() {
+            Ok(KEEP_BOTH) => return OnConflict::Suffix,
+            Ok("Overwrite") => return OnConflict::Overwrite,
+            Ok("Compare") => {
+                for (label, path) in [("source", source), ("existing", existing)] {
+                    let size = fs::metadata(path).map(|m| format_bytes(m.len() as f64)).unwrap_or_else(|_| "?".to_string());
+                    let bitrate = get_bitrate_with_lofty(path).map(|kbps| format!("{}k", kbps)).unwrap_or_else(|| "?".to_string());
+                    println!("  {:<9} {:>10}  {:>6}  {}", label, size, bitrate, path.display());
+                }
+            }
+            _ => return OnConflict::Skip,
+        }
+    }
+}
+
+// "song.flac" -> "song (2).flac", or the first higher number that is free
+fn suffixed_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+    (2..)
+        .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, ext)))
+        .find(|candidate| !candidate.exists())
+        .expect("some numbered file name is free")
+}
+
+fn same_file_content(a: &Path, b: &Path) -> bool {
+    let size = |p: &Path| fs::metadata(p).map(|m| m.len()).ok();
+    size(a).is_some() && size(a) == size(b) && matches!((file_checksum(a), file_checksum(b)), (Ok(x), Ok(y)) if x == y)
+}
+
+// String similarity used to suggest replacements for missing playlist entries
+#[derive(Clone, Copy, Default)]
+enum SimilarityMetric {
+    Jaro,
+    #[default]
+    JaroWinkler, // rewards shared prefixes, which suits track titles
+    Levenshtein,
+    SorensenDice,
+}
+
+impl SimilarityMetric {
+    fn from_config(value: Option<&str>) -> Result<Self, String> {
+        match value.map(str::to_lowercase).as_deref() {
+            None | Some("jaro_winkler") => Ok(SimilarityMetric::JaroWinkler),
+            Some("jaro") => Ok(SimilarityMetric::Jaro),
+            Some("levenshtein") => Ok(SimilarityMetric::Levenshtein),
+            Some("sorensen_dice") => Ok(SimilarityMetric::SorensenDice),
+            Some(other) => Err(format!(
+                "unknown similarity_metric '{}' (expected jaro, jaro_winkler, levenshtein or sorensen_dice)",
+                other
+            )),
+        }
+    }
+
+    // Similarity between 0.0 and 1.0
+    fn score(self, a: &str, b: &str) -> f64 {
+        match self {
+            SimilarityMetric::Jaro => strsim::jaro(a, b),
+            SimilarityMetric::JaroWinkler => strsim::jaro_winkler(a, b),
+            SimilarityMetric::Levenshtein => strsim::normalized_levenshtein(a, b),
+            SimilarityMetric::SorensenDice => strsim::sorensen_dice(a, b),
+        }
+    }
+}
+
+fn organize_file(from: &Path, to: &Path, mode: OrganizeMode) -> std::io::Result<()> {
+    let result = place_file(from, to, mode);
+    log_mutation(mode.verb(), &[from, to], &result);
+    result
+}
+
+fn place_file(from: &Path, to: &Path, mode: OrganizeMode) -> std::io::Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    match mode {
+        OrganizeMode::Move => {
+            // rename fails across filesystems, so fall back to copying and removing the source
+            if std::fs::rename(from, to).is_err() {
+                std::fs::copy(from, to)?;
+                std::fs::remove_file(from)?;
+            }
+            Ok(())
+        }
+        OrganizeMode::Copy => std::fs::copy(from, to).map(|_| ()),
+        OrganizeMode::Hardlink => std::fs::hard_link(from, to),
+        OrganizeMode::Symlink => {
+            let target = std::fs::canonicalize(from)?;
+            #[cfg(unix)]
+            return std::os::unix::fs::symlink(target, to);
+            #[cfg(windows)]
+            return std::os::windows::fs::symlink_file(target, to);
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Settings {
+    files: FilesConfig,
+    replace: Option<HashMap<String, String>>,
+    dupe_key: Option<Vec<String>>,
+    stats: Option<StatsConfig>,
+    tag_preference: Option<Vec<String>>, // tag types to read from, highest priority first
+    artist_separator: Option<String>,     // filename separator between artist and title; detected when unset
+    artist_delimiters: Option<Vec<String>>, // split "A; B" artist tags on these at index time; off when unset
+    artist_exceptions: Option<Vec<String>>, // artists never split, e.g. "AC/DC"
+    require_tags: Option<Vec<String>>,      // tags every indexed file should have; see Index --strict
+    various_artists_aliases: Option<Vec<String>>, // album artists that mean a compilation, e.g. "VA"
+    various_artists_label: Option<String>,        // what the aliases are stored as; "Various Artists" when unset
+    post_index_hook: Option<String>, // shell command run after Index, e.g. "mpc update"; gets APOLLO_ADDED etc.
+    #[serde(default)]
+    unknown_label: UnknownLabels,
+    #[serde(default)]
+    ui: UiConfig,
+    #[serde(default)]
+    normalize: NormalizeConfig,
+    #[serde(default)]
+    quality: QualityConfig,
+    similarity_metric: Option<String>, // "jaro", "jaro_winkler" (default), "levenshtein" or "sorensen_dice"
+}
+
+// Tag-cleaning rules applied by Normalize; each can be turned off on its own
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct NormalizeConfig {
+    trim: bool,              // leading and trailing whitespace
+    collapse_spaces: bool,   // runs of whitespace inside a value
+    featuring: bool,         // "ft.", "Feat", "featuring" -> "feat."
+    strip_junk: bool,        // stray separators such as "-", "_" or "|" at either end
+    the_prefix: Option<String>, // artists' "The ": "keep" (default), "strip", "suffix" (X, The) or "prefix" (The X)
+    strip_title_noise: bool, // off by default, like --strip-disc-from-title; see TITLE_NOISE_PATTERNS
+    title_noise_patterns: Vec<String>, // extra regexes removed from titles on top of the built-in ones
+    fix_encoding: bool,      // off by default, like --fix-encoding; see repair_mojibake
+}
+
+impl Default for NormalizeConfig {
+    fn default() -> Self {
+        NormalizeConfig {
+            trim: true,
+            collapse_spaces: true,
+            featuring: true,
+            strip_junk: true,
+            the_prefix: None,
+            strip_title_noise: false,
+            title_noise_patterns: Vec::new(),
+            fix_encoding: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ThePrefix {
+    Keep,
+    Strip,
+    Suffix,
+    Prefix,
+}
+
+impl ThePrefix {
+    fn from_config(value: Option<&str>) -> Result<Self, String> {
+        match value.map(str::to_lowercase).as_deref() {
+            None | Some("keep") => Ok(ThePrefix::Keep),
+            Some("strip") => Ok(ThePrefix::Strip),
+            Some("suffix") => Ok(ThePrefix::Suffix),
+            Some("prefix") => Ok(ThePrefix::Prefix),
+            Some(other) => Err(format!(
+                "unknown normalize.the_prefix '{}' (expected keep, strip, suffix or prefix)",
+                other
+            )),
+        }
+    }
+}
+
+const DEFAULT_VARIOUS_ARTISTS_ALIASES: &[&str] = &["Various Artists", "Various", "VA", "V/A", "V.A."];
+
+impl Settings {
+    fn various_artists_label(&self) -> &str {
+        self.various_artists_label.as_deref().unwrap_or("Various Artists")
+    }
+
+    // Case-insensitive; the configured label always counts, whatever the aliases are
+    fn is_various_artists(&self, albumartist: &str) -> bool {
+        let albumartist = albumartist.trim();
+        if albumartist.eq_ignore_ascii_case(self.various_artists_label()) {
+            return true;
+        }
+        match &self.various_artists_aliases {
+            Some(aliases) => aliases.iter().any(|alias| alias.trim().eq_ignore_ascii_case(albumartist)),
+            None => DEFAULT_VARIOUS_ARTISTS_ALIASES.iter().any(|alias| alias.eq_ignore_ascii_case(albumartist)),
+        }
+    }
+}
+
+// Shown and used in organized paths in place of blank tags
+#[derive(Debug, Deserialize, Default)]
+struct UnknownLabels {
+    artist: Option<String>,
+    album: Option<String>,
+    title: Option<String>,
+}
+
+impl UnknownLabels {
+    fn artist_or<'a>(&'a self, artist: &'a str) -> &'a str {
+        or_label(artist, self.artist.as_deref().unwrap_or("Unknown Artist"))
+    }
+
+    fn album_or<'a>(&'a self, album: &'a str) -> &'a str {
+        or_label(album, self.album.as_deref().unwrap_or("Unknown Album"))
+    }
+
+    fn title_or<'a>(&'a self, title: &'a str) -> &'a str {
+        or_label(title, self.title.as_deref().unwrap_or("Unknown Title"))
+    }
+}
+
+fn or_label<'a>(value: &'a str, label: &'a str) -> &'a str {
+    if value.trim().is_empty() { label } else { value }
+}
+
+// Progress bar shown by Index, Backfill, Verify --checksums and Compress
+#[derive(Debug, Deserialize, Default)]
+struct UiConfig {
+    progress_template: Option<String>, // an indicatif template; replaces the default bar and the flags below
+    show_eta: Option<bool>,
+    show_rate: Option<bool>, // items per second
+}
+
+const DEFAULT_PROGRESS_TEMPLATE: &str = "[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len}{extra} {msg}";
+
+// Set once from [ui] in main; make_progress_bar falls back to the default bar before that
+static PROGRESS_TEMPLATE: OnceLock<String> = OnceLock::new();
+
+fn progress_template(ui: &UiConfig) -> String {
+    if let Some(template) = &ui.progress_template {
+        if ProgressStyle::with_template(template).is_ok() {
+            return template.clone();
+        }
+        eprintln!("{}", "Warning: ignoring invalid [ui] progress_template".yellow());
+    }
+    let mut extra = String::new();
+    if ui.show_rate == Some(true) {
+        extra.push_str(" ({per_sec})");
+    }
+    if ui.show_eta == Some(true) {
+        extra.push_str(" ETA {eta}");
+    }
+    DEFAULT_PROGRESS_TEMPLATE.replace("{extra}", &extra)
+}
+
+fn make_progress_bar(len: usize) -> ProgressBar {
+    let template = PROGRESS_TEMPLATE.get().cloned().unwrap_or_else(|| progress_template(&UiConfig::default()));
+    let pb = ProgressBar::new(len as u64);
+    pb.set_style(ProgressStyle::with_template(&template)
+        .expect("progress template was validated")
+        .progress_chars("##-"));
+    pb
+}
+
+// Floors used by Doctor --low-quality
+#[derive(Debug, Deserialize, Default)]
+struct QualityConfig {
+    min_bitrate: Option<HashMap<String, u32>>, // kbps per format, e.g. { mp3 = 192 }; replaces DEFAULT_MIN_BITRATES
+    min_sample_rate: Option<u32>, // Hz, any format (default 44100)
+    min_bit_depth: Option<u8>,    // lossless formats only (default 16)
+}
+
+const DEFAULT_MIN_BITRATES: &[(&str, u32)] = &[("mp3", 192), ("m4a", 160), ("aac", 160), ("ogg", 160), ("opus", 96)];
+const LOSSLESS_FORMATS: &[&str] = &["flac", "wav", "aiff", "ape", "wv"];
+
+#[derive(Debug, Deserialize, Default)]
+struct StatsConfig {
+    outlier_min_secs: Option<i64>,
+    outlier_max_secs: Option<i64>,
+    cache_ttl_secs: Option<i64>, // how long the cached headline numbers stay valid
+}
+
+fn sanitize_filename_component(s: &str, replacements: &Option<HashMap<String, String>>) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        let mut replaced = false;
+        if let Some(map) = replacements {
+            for (from, to) in map {
+                if from.chars().count() == 1 && c == from.chars().next().unwrap() {
+                    result.push_str(to);
+                    replaced = true;
+                    break;
+                }
+            }
+        }
+        if !replaced {
+            result.push(c);
+        }
+    }
+    result
+}
+
+// Columns added after the original tracks schema; applied to older databases on open
+const TRACK_COLUMN_MIGRATIONS: &[(&str, &str)] = &[
+    ("ext", "TEXT"),
+    ("size", "INTEGER"),
+    ("date_added", "INTEGER"), // unix timestamp; NULL for rows indexed before it existed
+    ("real_format", "TEXT"),   // container detected from file contents
+    ("replaygain_track_gain", "REAL"), // dB from the REPLAYGAIN_TRACK_GAIN tag; NULL when unanalyzed
+    ("track_number", "INTEGER"),
+    ("total_tracks", "INTEGER"),
+    ("disc_number", "INTEGER"),
+    ("total_discs", "INTEGER"),
+    ("mtime", "INTEGER"),   // unix timestamp of the file's last modification
+    ("checksum", "TEXT"),   // blake3 of the contents; only computed by Index --checksum
+    ("notes", "TEXT"),      // set with the Note command; indexing never touches it
+    ("rating", "INTEGER"),  // 1-5 stars from a POPM frame or RATING tag; NULL when unrated
+    ("artists", "TEXT"),    // JSON array of every credited artist when artist_delimiters split the tag
+    ("silence_secs", "REAL"), // leading plus trailing silence measured by Stats --silence; NULL until then
+    ("mb_track_id", "TEXT"),  // MusicBrainz recording id (Picard's MUSICBRAINZ_TRACKID)
+    ("mb_release_id", "TEXT"), // MusicBrainz release id (MUSICBRAINZ_ALBUMID)
+];
+
+// Set by --in-memory: the database file that was copied, and a connection that keeps the
+// shared in-memory copy alive so every open_db of that file connects to the copy instead
+static IN_MEMORY_DB: OnceLock<(String, Mutex<rusqlite::Connection>)> = OnceLock::new();
+const IN_MEMORY_URI: &str = "file:apollo-in-memory?mode=memory&cache=shared";
+
+// Set by --read-only: open_db opens the database read-only and the commands that would
+// otherwise write caches or backfill columns leave it untouched
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+fn read_only() -> bool {
+    READ_ONLY.load(Ordering::Relaxed)
+}
+
+fn load_db_into_memory(db_path: &str) {
+    if !Path::new(db_path).exists() {
+        exit_with_error(EXIT_DATABASE, &format!("--in-memory: database {} does not exist", db_path));
+    }
+    let mut keeper = rusqlite::Connection::open(IN_MEMORY_URI)
+        .unwrap_or_else(|e| exit_with_error(EXIT_DATABASE, &format!("failed to create in-memory database: {}", e)));
+    keeper
+        .restore("main", db_path, None::<fn(rusqlite::backup::Progress)>)
+        .unwrap_or_else(|e| exit_with_error(EXIT_DATABASE, &format!("failed to copy {} into memory: {}", db_path, e)));
+    IN_MEMORY_DB.set((db_path.to_string(), Mutex::new(keeper))).ok();
+}
+
+fn open_db(db_path: &str) -> rusqlite::Connection {
+    let source = match IN_MEMORY_DB.get() {
+        Some((copied, _)) if copied == db_path => IN_MEMORY_URI,
+        _ => db_path,
+    };
+    let opened = if read_only() {
+        let flags = rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY
+            | rusqlite::OpenFlags::SQLITE_OPEN_URI
+            | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        rusqlite::Connection::open_with_flags(source, flags)
+    } else {
+        rusqlite::Connection::open(source)
+    };
+    let conn = opened
+        .unwrap_or_else(|e| exit_with_error(EXIT_DATABASE, &format!("failed to open database {}: {}", db_path, e)));
+    let busy_timeout = BUSY_TIMEOUT.get().copied().unwrap_or(Duration::from_millis(DEFAULT_BUSY_TIMEOUT_MS));
+    conn.busy_timeout(busy_timeout)
+        .unwrap_or_else(|e| exit_with_error(EXIT_DATABASE, &format!("failed to configure database {}: {}", db_path, e)));
+
+    create_table(&conn,
         "CREATE TABLE IF NOT EXISTS tracks (
             id INTEGER PRIMARY KEY,
             path TEXT NOT NULL UNIQUE,
@@ -227,832 +1264,5954 @@ fn index_library(settings: &Settings, dry_run: bool) {
             year INTEGER,
             genre TEXT
         )",
-        [],
-    ).expect("Failed to create table");
+    ).unwrap_or_else(|e| exit_with_error(EXIT_DATABASE, &format!("failed to create tracks table: {}", e)));
+
+    let existing: Vec<String> = {
+        let mut stmt = conn.prepare("PRAGMA table_info(tracks)").expect("Failed to read tracks schema");
+        stmt.query_map([], |row| row.get(1))
+            .expect("Failed to read tracks schema")
+            .filter_map(Result::ok)
+            .collect()
+    };
+    for (column, decl) in TRACK_COLUMN_MIGRATIONS {
+        if !existing.iter().any(|c| c == column) {
+            if read_only() {
+                exit_with_error(EXIT_DATABASE, &format!(
+                    "database {} needs upgrading (missing column {}); run apollo once without --read-only",
+                    db_path, column
+                ));
+            }
+            conn.execute(&format!("ALTER TABLE tracks ADD COLUMN {} {}", column, decl), [])
+                .expect("Failed to migrate tracks table");
+        }
+    }
+    if !read_only() {
+        create_tracks_fts(&conn);
+    }
 
-    let tx = conn.transaction().expect("Failed to start transaction");
+    conn
+}
 
-    println!("Indexing music files in directory: {}", music_dir);
+// CREATE TABLE IF NOT EXISTS that also works under --read-only: a table the database doesn't
+// have yet is created as an empty temp table for this connection instead
+fn create_table(conn: &rusqlite::Connection, ddl: &str) -> rusqlite::Result<usize> {
+    match conn.execute(ddl, []) {
+        Err(_) if read_only() => conn.execute(&ddl.replacen("CREATE TABLE", "CREATE TEMP TABLE", 1), []),
+        result => result,
+    }
+}
 
-    // Collect all files first to know the total count
-    let pb = Arc::new(ProgressBar::new(entries.len() as u64));
-    pb.set_style(ProgressStyle::with_template("[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}")
-        .unwrap()
-        .progress_chars("##-"));
+// Full-text index over artist, album and title for Search, kept in sync by triggers.
+// SQLite builds without FTS5 simply don't get one and Search keeps using LIKE.
+fn create_tracks_fts(conn: &rusqlite::Connection) {
+    if has_tracks_fts(conn) {
+        return;
+    }
+    let created = conn.execute_batch(
+        "CREATE VIRTUAL TABLE tracks_fts USING fts5(artist, album, title, content='tracks', content_rowid='id');
+         CREATE TRIGGER tracks_fts_insert AFTER INSERT ON tracks BEGIN
+             INSERT INTO tracks_fts(rowid, artist, album, title) VALUES (new.id, new.artist, new.album, new.title);
+         END;
+         CREATE TRIGGER tracks_fts_delete AFTER DELETE ON tracks BEGIN
+             INSERT INTO tracks_fts(tracks_fts, rowid, artist, album, title) VALUES ('delete', old.id, old.artist, old.album, old.title);
+         END;
+         CREATE TRIGGER tracks_fts_update AFTER UPDATE OF artist, album, title ON tracks BEGIN
+             INSERT INTO tracks_fts(tracks_fts, rowid, artist, album, title) VALUES ('delete', old.id, old.artist, old.album, old.title);
+             INSERT INTO tracks_fts(rowid, artist, album, title) VALUES (new.id, new.artist, new.album, new.title);
+         END;
+         INSERT INTO tracks_fts(tracks_fts) VALUES ('rebuild');",
+    );
+    if created.is_err() {
+        // Leave no half-created table or triggers behind
+        conn.execute_batch(
+            "DROP TRIGGER IF EXISTS tracks_fts_insert;
+             DROP TRIGGER IF EXISTS tracks_fts_delete;
+             DROP TRIGGER IF EXISTS tracks_fts_update;
+             DROP TABLE IF EXISTS tracks_fts;",
+        ).ok();
+    }
+}
 
-    // Start background ticker thread to keep progress bar updating smoothly
-    let ticker_running = Arc::new(AtomicBool::new(true));
-    let ticker_running_clone = Arc::clone(&ticker_running);
-    let pb_ticker = Arc::clone(&pb);
+fn has_tracks_fts(conn: &rusqlite::Connection) -> bool {
+    conn.query_row("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'tracks_fts'", [], |_| Ok(()))
+        .is_ok()
+}
 
-    let ticker_handle = thread::spawn(move || {
-        while ticker_running_clone.load(Ordering::Relaxed) {
-            pb_ticker.tick();
-            thread::sleep(Duration::from_millis(100));
+// Metadata gathered for one audio file during indexing
+struct IndexedTrack {
+    path: String,
+    artist: String,
+    albumartist: String,
+    album: String,
+    title: String,
+    year: i32,
+    genre: String,
+    ext: String,
+    size: u64,
+    real_format: Option<String>,
+    replaygain_track_gain: Option<f64>,
+    numbers: TrackNumbers,
+    mtime: Option<i64>,
+    checksum: Option<String>,
+    rating: Option<u8>,
+    artists: Option<String>,
+    mbids: MusicBrainzIds,
+}
+
+// Tags require_tags can name, and the ones Index --strict checks when it names none
+const REQUIRABLE_TAGS: &[&str] = &["artist", "albumartist", "album", "title", "year", "genre"];
+const DEFAULT_REQUIRED_TAGS: &[&str] = &["artist", "title"];
+
+impl IndexedTrack {
+    fn has_tag(&self, tag: &str) -> bool {
+        match tag {
+            "artist" => !self.artist.trim().is_empty(),
+            "albumartist" => !self.albumartist.trim().is_empty(),
+            "album" => !self.album.trim().is_empty(),
+            "title" => !self.title.trim().is_empty(),
+            "year" => self.year > 0,
+            "genre" => !self.genre.trim().is_empty(),
+            _ => true,
         }
-    });
+    }
+}
 
-    // Process files in parallel to read metadata
-    let pb_clone = Arc::clone(&pb);
-    let tracks: Vec<_> = entries.par_iter().filter_map(|entry| {
-        let path = entry.path();
-        let (artist, album, albumartist, title, year, genre) = match lofty::read_from_path(path) {
-            Ok(tagged_file) => {
-                let tag = tagged_file.primary_tag();
-                let artist = tag.and_then(|t| t.get_string(&ItemKey::TrackArtist)).unwrap_or("").to_string();
-                let albumartist = tag.and_then(|t| t.get_string(&ItemKey::AlbumArtist)).unwrap_or("").to_string();
-                let album = tag.and_then(|t| t.get_string(&ItemKey::AlbumTitle)).unwrap_or("").to_string();
-                let title = tag.and_then(|t| t.get_string(&ItemKey::TrackTitle)).unwrap_or("").to_string();
-                let year = tag
-                    .and_then(|t| t.get_string(&ItemKey::Year))
-                    .and_then(|s| s.parse::<i32>().ok())
-                    .unwrap_or(0);
-                let genre = tag.and_then(|t| t.get_string(&ItemKey::Genre)).unwrap_or("").to_string();
-                (artist, album, albumartist, title, year, genre)
+fn required_tags(settings: &Settings, strict: bool) -> Result<Vec<&'static str>, String> {
+    match &settings.require_tags {
+        Some(tags) => tags
+            .iter()
+            .map(|tag| {
+                REQUIRABLE_TAGS
+                    .iter()
+                    .find(|t| t.eq_ignore_ascii_case(tag.trim()))
+                    .copied()
+                    .ok_or_else(|| format!("Unknown require_tags entry '{}'; expected one of {}", tag, REQUIRABLE_TAGS.join(", ")))
+            })
+            .collect(),
+        None if strict => Ok(DEFAULT_REQUIRED_TAGS.to_vec()),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn file_mtime(metadata: &std::fs::Metadata) -> Option<i64> {
+    let modified = metadata.modified().ok()?;
+    Some(modified.duration_since(std::time::UNIX_EPOCH).ok()?.as_secs() as i64)
+}
+
+fn file_checksum(path: &Path) -> std::io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(fs::File::open(path)?)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+// Track and disc position as tagged; any of them may be missing
+#[derive(Clone, Copy, Default)]
+struct TrackNumbers {
+    track_number: Option<u32>,
+    total_tracks: Option<u32>,
+    disc_number: Option<u32>,
+    total_discs: Option<u32>,
+}
+
+impl TrackNumbers {
+    fn from_tag(tag: &lofty::tag::Tag) -> Self {
+        use lofty::tag::Accessor;
+        TrackNumbers {
+            track_number: tag.track(),
+            total_tracks: tag.track_total(),
+            disc_number: tag.disk(),
+            total_discs: tag.disk_total(),
+        }
+    }
+}
+
+// MusicBrainz ids as written by Picard, read from the preferred tag or else any other tag
+#[derive(Default)]
+struct MusicBrainzIds {
+    track: Option<String>,   // recording id
+    release: Option<String>,
+}
+
+impl MusicBrainzIds {
+    fn from_tags(preferred: Option<&lofty::tag::Tag>, tags: &[lofty::tag::Tag]) -> Self {
+        let read = |key: &ItemKey| {
+            preferred
+                .into_iter()
+                .chain(tags)
+                .find_map(|tag| tag.get_string(key))
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(str::to_lowercase)
+        };
+        MusicBrainzIds { track: read(&ItemKey::MusicBrainzRecordingId), release: read(&ItemKey::MusicBrainzReleaseId) }
+    }
+}
+
+// Star rating (1-5) from a tag's rating item. ID3v2 keeps the raw POPM frame, whose 0-255
+// byte is mapped the way Windows Media Player and most taggers do; text ratings are either
+// stars (up to 5) or a percentage. 0 means unrated.
+fn tag_rating(tag: &lofty::tag::Tag) -> Option<u8> {
+    use lofty::tag::ItemValue;
+
+    let stars = match tag.get(&ItemKey::Popularimeter)?.value() {
+        ItemValue::Binary(frame) => {
+            // email, NUL terminator, rating byte, play counter
+            let rating = *frame.get(frame.iter().position(|b| *b == 0)? + 1)?;
+            match rating {
+                0 => 0,
+                1..=31 => 1,
+                32..=95 => 2,
+                96..=159 => 3,
+                160..=223 => 4,
+                _ => 5,
             }
-            Err(_) => {
-                pb_clone.inc(1);
-                return None;
+        }
+        ItemValue::Text(text) => {
+            let value: f64 = text.trim().parse().ok()?;
+            if value <= 5.0 { value.round() as u8 } else { (value.min(100.0) / 20.0).round() as u8 }
+        }
+        ItemValue::Locator(_) => return None,
+    };
+    (stars > 0).then_some(stars)
+}
+
+// Split a multi-artist tag such as "A; B" on the configured delimiters. Delimiters inside
+// an exception ("AC/DC") are left alone, so "AC/DC; Queen" still splits in two.
+fn split_artists(artist: &str, delimiters: &[String], exceptions: &[String]) -> Vec<String> {
+    let lower = artist.to_ascii_lowercase();
+    let protected: Vec<std::ops::Range<usize>> = exceptions
+        .iter()
+        .filter(|e| !e.is_empty())
+        .flat_map(|e| {
+            let e = e.to_ascii_lowercase();
+            lower.match_indices(&e).map(|(start, m)| start..start + m.len()).collect::<Vec<_>>()
+        })
+        .collect();
+
+    let mut artists = Vec::new();
+    let (mut start, mut i) = (0, 0);
+    while i < artist.len() {
+        let delimiter = delimiters
+            .iter()
+            .filter(|d| !d.is_empty())
+            .find(|d| artist[i..].starts_with(d.as_str()) && !protected.iter().any(|r| r.contains(&i)));
+        match delimiter {
+            Some(d) => {
+                artists.push(artist[start..i].trim().to_string());
+                i += d.len();
+                start = i;
             }
-        };
+            None => i += artist[i..].chars().next().map_or(1, char::len_utf8),
+        }
+    }
+    artists.push(artist[start..].trim().to_string());
+    artists.retain(|a| !a.is_empty());
+    artists
+}
+
+// Split "Main feat. Guest", "Main (ft. Guest)", "Main featuring Guest", "Main with Guest" or
+// "Main & Guest" into the main artist and the guests. artist_exceptions such as "Simon &
+// Garfunkel" are never split.
+fn split_featured_artist<'a>(artist: &'a str, exceptions: &[String]) -> Option<(&'a str, &'a str)> {
+    static FEATURING: OnceLock<regex::Regex> = OnceLock::new();
+    let featuring = FEATURING.get_or_init(|| {
+        regex::Regex::new(r"(?i)\s+([(\[])?(?:featuring|feat\.?|ft\.?|with|&)\s+").expect("valid featuring regex")
+    });
+    let lower = artist.to_ascii_lowercase();
+    let protected: Vec<std::ops::Range<usize>> = exceptions
+        .iter()
+        .filter(|e| !e.is_empty())
+        .flat_map(|e| {
+            let e = e.to_ascii_lowercase();
+            lower.match_indices(&e).map(|(start, m)| start..start + m.len()).collect::<Vec<_>>()
+        })
+        .collect();
+
+    let caps = featuring
+        .captures_iter(artist)
+        .find(|caps| !protected.iter().any(|r| r.contains(&caps.get(0).map_or(0, |m| m.start()))))?;
+    let separator = caps.get(0)?;
+    let primary = artist[..separator.start()].trim();
+    let mut guests = artist[separator.end()..].trim();
+    if let Some(open) = caps.get(1) {
+        let close = if open.as_str() == "(" { ')' } else { ']' };
+        guests = guests.strip_suffix(close).unwrap_or(guests).trim();
+    }
+    (!primary.is_empty() && !guests.is_empty()).then_some((primary, guests))
+}
+
+// Parse a ReplayGain tag value such as "-6.52 dB"
+fn parse_replaygain(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let number = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .unwrap_or(value);
+    number.trim().parse::<f64>().ok()
+}
+
+fn parse_tag_type(name: &str) -> Result<lofty::tag::TagType, String> {
+    use lofty::tag::TagType;
+    match name.to_lowercase().as_str() {
+        "id3v2" => Ok(TagType::Id3v2),
+        "id3v1" => Ok(TagType::Id3v1),
+        "ape" => Ok(TagType::Ape),
+        "vorbis" => Ok(TagType::VorbisComments),
+        "mp4" => Ok(TagType::Mp4Ilst),
+        "riff" => Ok(TagType::RiffInfo),
+        "aiff" => Ok(TagType::AiffText),
+        other => Err(format!(
+            "unknown tag_preference entry '{}' (expected one of: id3v2, id3v1, ape, vorbis, mp4, riff, aiff)",
+            other
+        )),
+    }
+}
+
+// The first tag present in preference order, falling back to lofty's primary tag
+fn preferred_tag<'a>(tagged_file: &'a lofty::file::TaggedFile, preference: &[lofty::tag::TagType]) -> Option<&'a lofty::tag::Tag> {
+    preference
+        .iter()
+        .find_map(|tag_type| tagged_file.tag(*tag_type))
+        .or_else(|| tagged_file.primary_tag())
+}
+
+// Canonical extension for a detected container, used to spot mislabeled files
+fn file_type_name(file_type: lofty::file::FileType) -> Option<&'static str> {
+    use lofty::file::FileType;
+    match file_type {
+        FileType::Aac => Some("aac"),
+        FileType::Aiff => Some("aiff"),
+        FileType::Ape => Some("ape"),
+        FileType::Flac => Some("flac"),
+        FileType::Mpeg => Some("mp3"),
+        FileType::Mp4 => Some("m4a"),
+        FileType::Mpc => Some("mpc"),
+        FileType::Opus => Some("opus"),
+        FileType::Vorbis => Some("ogg"),
+        FileType::Speex => Some("spx"),
+        FileType::Wav => Some("wav"),
+        FileType::WavPack => Some("wv"),
+        _ => None,
+    }
+}
+
+// Print relative paths as an indented tree, one level per path component
+fn print_path_tree(paths: &[String]) {
+    let mut paths: Vec<Vec<&str>> = paths.iter().map(|p| p.split('/').collect()).collect();
+    paths.sort();
+    paths.dedup();
+    let mut previous: &[&str] = &[];
+    for components in &paths {
+        // Components shared with the previous path have already been printed
+        let shared = previous.iter().zip(components).take_while(|(a, b)| a == b).count();
+        for (depth, component) in components.iter().enumerate().skip(shared) {
+            let suffix = if depth + 1 == components.len() { "" } else { "/" };
+            println!("{}{}{}", "  ".repeat(depth + 1), component, suffix);
+        }
+        previous = components;
+    }
+}
+
+// Resolve an Index path argument to the form the full walk stores, so its rows can be
+// matched by prefix
+fn index_scope(music_dir: &str, path: &str) -> Result<PathBuf, String> {
+    let music_root = fs::canonicalize(music_dir)
+        .map_err(|e| format!("cannot read music directory {}: {}", music_dir, e))?;
+    let target = fs::canonicalize(expand_tilde(path)).map_err(|e| format!("cannot index {}: {}", path, e))?;
+    let rel_path = target
+        .strip_prefix(&music_root)
+        .map_err(|_| format!("{} is not inside the music directory {}", path, music_dir))?;
+    if rel_path.as_os_str().is_empty() {
+        Ok(PathBuf::from(music_dir))
+    } else {
+        Ok(Path::new(music_dir).join(rel_path))
+    }
+}
+
+// What an Index run changed, handed to post_index_hook as APOLLO_* environment variables
+#[derive(Default)]
+struct IndexSummary {
+    added: usize,
+    updated: usize,
+    removed: usize,
+    moved: usize,
+    failed: usize, // files that couldn't be saved to the database
+}
+
+// Index the whole music directory, or only `scope` (a file or subdirectory inside it)
+#[allow(clippy::too_many_arguments)]
+fn index_library(
+    settings: &Settings,
+    scope: Option<&Path>,
+    organize: bool,
+    dry_run: bool,
+    preview_tree: bool,
+    fail_fast: bool,
+    checksum: bool,
+    strict: bool,
+    on_conflict: OnConflict,
+) -> IndexSummary {
+    let music_dir = expand_tilde(&settings.files.music_directory);
+    let db_path = expand_tilde(&settings.files.database_name);
+    let walk_root = scope.unwrap_or(Path::new(&music_dir));
+
+    // file_pattern only enables organizing; files are moved when --organize is passed
+    let file_pattern = if organize {
+        if settings.files.file_pattern.is_none() {
+            eprintln!("{}", "Warning: --organize has no effect without a file_pattern in the config".yellow());
+        }
+        settings.files.file_pattern.as_deref()
+    } else {
+        None
+    };
+    let organize_mode = match OrganizeMode::from_config(settings.files.organize_mode.as_deref()) {
+        Ok(mode) => mode,
+        Err(e) => exit_with_error(EXIT_CONFIG, &e),
+    };
+    let required_tags = required_tags(settings, strict).unwrap_or_else(|e| exit_with_error(EXIT_CONFIG, &e));
+    let tag_preference: Vec<lofty::tag::TagType> = match settings
+        .tag_preference
+        .iter()
+        .flatten()
+        .map(|name| parse_tag_type(name))
+        .collect()
+    {
+        Ok(preference) => preference,
+        Err(e) => exit_with_error(EXIT_CONFIG, &e),
+    };
+
+    // Build ignore matcher
+    let mut glob_builder = GlobSetBuilder::new();
+    if let Some(ignore_patterns) = &settings.files.ignore {
+        for pattern in ignore_patterns {
+            if let Ok(glob) = Glob::new(pattern) {
+                glob_builder.add(glob);
+            }
+        }
+    }
+    let glob_set = glob_builder.build().unwrap();
+
+    // Finish an organize run that was interrupted before its moves reached the database
+    let journal_path = organize_journal_path(&db_path);
+    if journal_path.exists() {
+        recover_interrupted_organize(&open_db(&db_path), &journal_path);
+    }
+
+    let entries: Vec<_> = walkdir::WalkDir::new(walk_root)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|e| e.file_type().is_file())
+        .filter(|e| {
+            // Get the path relative to music_dir for matching
+            let rel_path = e.path().strip_prefix(&music_dir).unwrap_or(e.path());
+            !glob_set.is_match(rel_path)
+        })
+        .collect();
+
+    // create or open the database
+    let mut conn = open_db(&db_path);
+
+    let mut tx = conn.transaction().expect("Failed to start transaction");
+
+    println!("Indexing music files in: {}", walk_root.display());
+
+    // Collect all files first to know the total count
+    let pb = Arc::new(make_progress_bar(entries.len()));
+
+    // Start background ticker thread to keep progress bar updating smoothly
+    let ticker_running = Arc::new(AtomicBool::new(true));
+    let ticker_running_clone = Arc::clone(&ticker_running);
+    let pb_ticker = Arc::clone(&pb);
+
+    let ticker_handle = thread::spawn(move || {
+        while ticker_running_clone.load(Ordering::Relaxed) {
+            pb_ticker.tick();
+            thread::sleep(Duration::from_millis(100));
+        }
+    });
+
+    let artist_delimiters = settings.artist_delimiters.as_deref().unwrap_or_default();
+    let artist_exceptions = settings.artist_exceptions.as_deref().unwrap_or_default();
+
+    // Process files in parallel to read metadata
+    let pb_clone = Arc::clone(&pb);
+    let mut tracks: Vec<_> = entries.par_iter().filter_map(|entry| {
+        let path = entry.path();
+        // Probe the content rather than trusting the extension so mislabeled files still read
+        let probed = lofty::probe::Probe::open(path)
+            .and_then(|probe| Ok(probe.guess_file_type()?))
+            .and_then(|probe| probe.read());
+        let (artist, artists, album, albumartist, title, year, genre, real_format, replaygain_track_gain, numbers, rating, mbids) = match probed {
+            Ok(tagged_file) => {
+                let real_format = file_type_name(tagged_file.file_type());
+                let tag = preferred_tag(&tagged_file, &tag_preference);
+                let artist = tag.and_then(|t| t.get_string(&ItemKey::TrackArtist)).unwrap_or("").to_string();
+                // The first credited artist becomes the track artist; the full list is kept alongside
+                let credited = split_artists(&artist, artist_delimiters, artist_exceptions);
+                let (artist, artists) = match credited.as_slice() {
+                    [primary, _, ..] => (primary.clone(), serde_json::to_string(&credited).ok()),
+                    _ => (artist, None),
+                };
+                let albumartist = tag.and_then(|t| t.get_string(&ItemKey::AlbumArtist)).unwrap_or("");
+                // "VA", "V/A" and friends are stored as one label so compilations group together
+                let albumartist = if settings.is_various_artists(albumartist) {
+                    settings.various_artists_label().to_string()
+                } else {
+                    albumartist.to_string()
+                };
+                let album = tag.and_then(|t| t.get_string(&ItemKey::AlbumTitle)).unwrap_or("").to_string();
+                let title = tag.and_then(|t| t.get_string(&ItemKey::TrackTitle)).unwrap_or("").to_string();
+                let year = tag
+                    .and_then(|t| t.get_string(&ItemKey::Year))
+                    .and_then(|s| s.parse::<i32>().ok())
+                    .unwrap_or(0);
+                let genre = tag.and_then(|t| t.get_string(&ItemKey::Genre)).unwrap_or("").to_string();
+                let replaygain_track_gain = tag
+                    .and_then(|t| t.get_string(&ItemKey::ReplayGainTrackGain))
+                    .and_then(parse_replaygain);
+                let numbers = tag.map(TrackNumbers::from_tag).unwrap_or_default();
+                // Taggers often write ratings to a tag other than the preferred one
+                let rating = tag.and_then(tag_rating).or_else(|| tagged_file.tags().iter().find_map(tag_rating));
+                let mbids = MusicBrainzIds::from_tags(tag, tagged_file.tags());
+                (artist, artists, album, albumartist, title, year, genre, real_format, replaygain_track_gain, numbers, rating, mbids)
+            }
+            Err(_) => {
+                pb_clone.inc(1);
+                return None;
+            }
+        };
+
+        if let Some(ext) = path.extension().and_then(|e| e.to_str())
+            && (ext == "mp3" || ext == "flac" || ext == "wav" || ext == "m4a")
+        {
+            let metadata = entry.metadata().ok();
+            let size = metadata.as_ref().map(|m| m.len()).unwrap_or(0);
+            let mtime = metadata.as_ref().and_then(file_mtime);
+            let checksum = if checksum { file_checksum(path).ok() } else { None };
+            if let Some(real) = real_format
+                && !real.eq_ignore_ascii_case(ext)
+            {
+                pb_clone.suspend(|| eprintln!(
+                    "{}",
+                    format!("Warning: {} has a .{} extension but contains {}", path.display(), ext, real.to_uppercase()).yellow()
+                ));
+            }
+            pb_clone.inc(1);
+            return Some(IndexedTrack {
+                path: path.to_string_lossy().to_string(),
+                artist,
+                albumartist,
+                album,
+                title,
+                year,
+                genre,
+                ext: ext.to_lowercase(),
+                size,
+                real_format: real_format.map(str::to_string),
+                replaygain_track_gain,
+                numbers,
+                mtime,
+                checksum,
+                rating,
+                artists,
+                mbids,
+            });
+        }
+        pb_clone.inc(1);
+        None
+    }).collect();
+
+    // Stop the ticker thread
+    ticker_running.store(false, Ordering::Relaxed);
+    ticker_handle.join().ok();
+
+    pb.finish_with_message("Metadata reading complete");
+
+    // Files missing a required tag are listed next to the database; --strict keeps them out of it
+    let skipped_report = Path::new(&db_path).parent().unwrap_or(Path::new(".")).join("index_skipped.txt");
+    let lacking: Vec<(String, Vec<&str>)> = tracks
+        .iter()
+        .filter_map(|track| {
+            let missing: Vec<&str> = required_tags.iter().copied().filter(|tag| !track.has_tag(tag)).collect();
+            (!missing.is_empty()).then(|| (track.path.clone(), missing))
+        })
+        .collect();
+    if lacking.is_empty() {
+        fs::remove_file(&skipped_report).ok();
+    } else {
+        let report: String = lacking
+            .iter()
+            .map(|(path, missing)| format!("{}\tmissing {}\n", path, missing.join(", ")))
+            .collect();
+        if let Err(e) = fs::write(&skipped_report, report) {
+            eprintln!("{}", format!("Failed to write {}: {}", skipped_report.display(), e).red());
+        }
+        let action = if strict { "were not indexed" } else { "were indexed anyway (pass --strict to skip them)" };
+        eprintln!(
+            "{}",
+            format!(
+                "{} file(s) are missing required tags and {}; see {}",
+                lacking.len(), action, skipped_report.display()
+            ).yellow()
+        );
+        if strict {
+            tracks.retain(|track| required_tags.iter().all(|tag| track.has_tag(tag)));
+        }
+    }
+
+    // Move files if pattern is set. This runs after all metadata is read so each album's
+    // tracks can be placed together.
+    let mut moved: Vec<(String, String)> = Vec::new();
+    if let Some(pattern) = file_pattern {
+        let album_artists = compilation_album_artists(&tracks, settings);
+        let labels = &settings.unknown_label;
+        let mut organize_failures: Vec<(PathBuf, String)> = Vec::new();
+        let mut destinations: Vec<String> = Vec::new();
+        // Destinations replaced with --on-conflict overwrite and the index of the track moved there
+        let mut overwritten: Vec<(String, usize)> = Vec::new();
+        for (i, track) in tracks.iter_mut().enumerate() {
+            let path = PathBuf::from(&track.path);
+            let ext = path.extension().and_then(|e| e.to_str()).unwrap_or(&track.ext).to_string();
+            let (artist, album, title) = (
+                labels.artist_or(&track.artist),
+                labels.album_or(&track.album),
+                labels.title_or(&track.title),
+            );
+            let new_rel_path = match album_artists.get(&album_group_key(track)) {
+                // Compilations go under the album artist so their tracks stay in one folder
+                Some(album_artist) => generate_path_from_pattern(
+                    &compilation_pattern(pattern),
+                    artist,
+                    album_artist,
+                    album,
+                    title,
+                    &ext,
+                    &settings.replace,
+                ),
+                // "Various Artists" on a single-artist album falls back to the track artist
+                None => generate_path_from_pattern(
+                    pattern,
+                    artist,
+                    if settings.is_various_artists(&track.albumartist) { "" } else { &track.albumartist },
+                    album,
+                    title,
+                    &ext,
+                    &settings.replace,
+                ),
+            };
+            let new_abs_path = organize_root(&settings.files, &music_dir, &track.genre).join(&new_rel_path);
+            if new_abs_path == path && !preview_tree {
+                continue;
+            }
+            // Empty tags leave blank components, which would place the file outside the music directory
+            let incomplete = new_rel_path.split('/').any(|c| c.trim().is_empty())
+                || new_rel_path.rsplit('/').next() == Some(&format!(".{}", ext));
+            if incomplete {
+                let error = format!("file_pattern produced '{}'; the file is missing tags it needs", new_rel_path);
+                if fail_fast {
+                    drop(tx);
+                    exit_with_error(EXIT_ERROR, &format!("cannot organize {}: {} (stopping because of --fail-fast)", path.display(), error));
+                }
+                eprintln!("{}", format!("Warning: cannot organize {}: {}", path.display(), error).yellow());
+                organize_failures.push((path, error));
+                continue;
+            }
+            if preview_tree {
+                let shown = new_abs_path.strip_prefix(&music_dir).unwrap_or(&new_abs_path);
+                destinations.push(shown.to_string_lossy().to_string());
+            } else if dry_run {
+                println!(
+                    "[dry-run] Would {}:\n  from: {}\n  to:   {}",
+                    organize_mode.verb(),
+                    path.display(),
+                    new_abs_path.display()
+                );
+            } else if new_abs_path.exists() && organize_mode != OrganizeMode::Move && same_file_content(&path, &new_abs_path) {
+                // Copied or linked on an earlier run; the source is left in place
+                track.path = new_abs_path.to_string_lossy().to_string();
+            } else {
+                let conflict = new_abs_path.exists().then(|| resolve_organize_conflict(on_conflict, &path, &new_abs_path));
+                let destination = match conflict {
+                    Some(OnConflict::Suffix) => suffixed_path(&new_abs_path),
+                    _ => new_abs_path.clone(),
+                };
+                let overwrite = conflict == Some(OnConflict::Overwrite);
+                let error = if conflict == Some(OnConflict::Skip) {
+                    format!("{} already exists", new_abs_path.display())
+                } else if let Err(e) = journal_organize_step(&journal_path, organize_mode, overwrite, &path, &destination) {
+                    format!("cannot write {}: {}", journal_path.display(), e)
+                } else if overwrite
+                    && matches!(organize_mode, OrganizeMode::Hardlink | OrganizeMode::Symlink)
+                    && let Err(e) = fs::remove_file(&destination)
+                {
+                    // Moves and copies replace the destination themselves; links need it gone first
+                    format!("cannot replace {}: {}", destination.display(), e)
+                } else {
+                    match organize_file(&path, &destination, organize_mode) {
+                        Ok(()) => {
+                            // Without the marker, recovery falls back to inspecting the files
+                            journal_organize_done(&journal_path, &path, &destination).ok();
+                            let new_path = destination.to_string_lossy().to_string();
+                            if overwrite {
+                                tx.execute("DELETE FROM tracks WHERE path = ?1", [&new_path]).ok();
+                                overwritten.push((new_path.clone(), i));
+                            }
+                            moved.push((std::mem::replace(&mut track.path, new_path.clone()), new_path));
+                            continue;
+                        }
+                        Err(e) => e.to_string(),
+                    }
+                };
+                if fail_fast {
+                    // Nothing has been written to the database yet; dropping the transaction rolls it back
+                    drop(tx);
+                    exit_with_error(
+                        EXIT_ERROR,
+                        &format!("failed to {} {}: {} (stopping because of --fail-fast)", organize_mode.verb(), path.display(), error),
+                    );
+                }
+                eprintln!(
+                    "{}",
+                    format!("Warning: failed to {} {}: {}", organize_mode.verb(), path.display(), error).yellow()
+                );
+                organize_failures.push((path, error));
+            }
+        }
+        // The file that was overwritten is gone; only the track moved over it keeps that path
+        let mut i = 0;
+        tracks.retain(|track| {
+            let replaced = overwritten.iter().any(|(path, mover)| *path == track.path && *mover != i);
+            i += 1;
+            !replaced
+        });
+        if preview_tree {
+            println!("Resulting layout of {}:", music_dir);
+            print_path_tree(&destinations);
+        }
+        if !organize_failures.is_empty() {
+            println!("{}", format!("{} file(s) could not be organized:", organize_failures.len()).yellow());
+            for (path, error) in &organize_failures {
+                println!("  {}: {}", path.display(), error);
+            }
+        }
+    }
+
+    // Re-point the rows of organized files so their ids, notes and favorites follow them
+    for (old_path, new_path) in &moved {
+        tx.execute("UPDATE OR IGNORE tracks SET path = ?1 WHERE path = ?2", [new_path, old_path]).ok();
+    }
+
+    // Likewise for a file replaced by another format under the same name (song.mp3 -> song.flac):
+    // a missing row with the same folder and stem is taken over instead of pruned
+    let indexed_paths: std::collections::HashSet<String> = {
+        let mut stmt = tx.prepare("SELECT path FROM tracks").expect("Failed to prepare select statement");
+        stmt.query_map([], |row| row.get(0))
+            .expect("Failed to query tracks")
+            .filter_map(Result::ok)
+            .collect()
+    };
+    let mut replaced: HashMap<(PathBuf, std::ffi::OsString), &String> = indexed_paths
+        .iter()
+        .filter(|path| !Path::new(path).exists())
+        .filter_map(|path| {
+            let path_ref = Path::new(path);
+            Some(((path_ref.parent()?.to_path_buf(), path_ref.file_stem()?.to_os_string()), path))
+        })
+        .collect();
+    // Paths whose row came from a format change; they count as updated, not added
+    let mut taken_over: std::collections::HashSet<String> = std::collections::HashSet::new();
+    for track in tracks.iter().filter(|t| !indexed_paths.contains(&t.path)) {
+        let path = Path::new(&track.path);
+        let (Some(parent), Some(stem)) = (path.parent(), path.file_stem()) else {
+            continue;
+        };
+        if let Some(old_path) = replaced.remove(&(parent.to_path_buf(), stem.to_os_string())) {
+            println!("Format change: {} -> {}", old_path, track.path);
+            if let Ok(1) = tx.execute("UPDATE OR IGNORE tracks SET path = ?1 WHERE path = ?2", [&track.path, old_path]) {
+                taken_over.insert(track.path.clone());
+            }
+        }
+    }
+
+    // Save the tracks in batches, each in its own transaction, so a crash keeps what was
+    // already saved and the journal stays small. The first batch also carries the organize
+    // changes above, which is why nothing before this point commits.
+    let batch_size = settings.files.index_batch_size.unwrap_or(DEFAULT_INDEX_BATCH_SIZE).max(1);
+    println!("Inserting {} tracks into database...", tracks.len());
+    let insert_pb = make_progress_bar(tracks.len());
+    let mut summary = IndexSummary { moved: moved.len(), ..Default::default() };
+
+    for (i, track) in tracks.into_iter().enumerate() {
+        if i > 0 && i % batch_size == 0 {
+            tx.commit().expect("Failed to commit transaction");
+            tx = conn.transaction().expect("Failed to start transaction");
+        }
+        // A re-indexed path keeps its row (id, date_added, notes, duration) but takes the
+        // file's current tags and details; the checksum survives only while mtime is unchanged
+        let result = tx.execute(
+            "INSERT INTO tracks (path, artist, albumartist, album, title, duration, year, genre, ext, size, real_format, \
+             replaygain_track_gain, track_number, total_tracks, disc_number, total_discs, mtime, checksum, rating, artists, \
+             mb_track_id, mb_release_id, date_added) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, \
+             strftime('%s', 'now')) \
+             ON CONFLICT(path) DO UPDATE SET artist = excluded.artist, albumartist = excluded.albumartist, \
+             album = excluded.album, title = excluded.title, year = excluded.year, genre = excluded.genre, \
+             ext = excluded.ext, size = excluded.size, real_format = excluded.real_format, \
+             replaygain_track_gain = excluded.replaygain_track_gain, track_number = excluded.track_number, \
+             total_tracks = excluded.total_tracks, disc_number = excluded.disc_number, total_discs = excluded.total_discs, \
+             rating = excluded.rating, artists = excluded.artists, \
+             mb_track_id = excluded.mb_track_id, mb_release_id = excluded.mb_release_id, \
+             checksum = CASE WHEN excluded.checksum IS NOT NULL THEN excluded.checksum \
+             WHEN tracks.mtime IS excluded.mtime THEN tracks.checksum END, \
+             silence_secs = CASE WHEN tracks.mtime IS excluded.mtime THEN tracks.silence_secs END, \
+             mtime = excluded.mtime",
+            [
+                &track.path as &dyn rusqlite::ToSql,
+                &track.artist,
+                &track.albumartist,
+                &track.album,
+                &track.title,
+                &0.0 as &dyn rusqlite::ToSql,
+                &track.year,
+                &track.genre,
+                &track.ext,
+                &(track.size as i64),
+                &track.real_format,
+                &track.replaygain_track_gain,
+                &track.numbers.track_number,
+                &track.numbers.total_tracks,
+                &track.numbers.disc_number,
+                &track.numbers.total_discs,
+                &track.mtime,
+                &track.checksum,
+                &track.rating,
+                &track.artists,
+                &track.mbids.track,
+                &track.mbids.release,
+            ]
+        );
+        match result {
+            Err(e) => {
+                insert_pb.println(format!("Failed to save {}: {}", track.path, e).yellow());
+                summary.failed += 1;
+            }
+            Ok(_) if !indexed_paths.contains(&track.path) && !taken_over.contains(&track.path) => {
+                insert_pb.set_message(format!("Added: {}", track.path));
+                summary.added += 1;
+            }
+            Ok(_) => summary.updated += 1,
+        }
+        insert_pb.inc(1);
+    }
+    insert_pb.finish_with_message("Database insertion complete");
+
+    // Clean up missing files from database, only under the scope so a narrow index
+    // leaves the rest of the library alone
+    println!("Checking for missing files in database...");
+    let mut stmt = tx.prepare("SELECT path FROM tracks").expect("Failed to prepare select statement");
+    let mut rows = stmt.query([]).expect("Failed to query tracks");
+
+    let mut to_remove = Vec::new();
+    while let Some(row) = rows.next().expect("Failed to fetch row") {
+        let path: String = row.get(0).expect("Failed to get path");
+        let in_scope = scope.is_none_or(|scope| Path::new(&path).starts_with(scope));
+        if in_scope && !std::path::Path::new(&path).exists() {
+            to_remove.push(path);
+        }
+    }
+    drop(rows);
+    drop(stmt);
+
+    for path in &to_remove {
+        println!("Removing missing file from database: {}", path);
+        tx.execute("DELETE FROM tracks WHERE path = ?1", [path]).ok();
+    }
+    if !to_remove.is_empty() {
+        println!("Removed {} missing files from database", to_remove.len());
+    }
+    summary.removed = to_remove.len();
+
+    tx.commit().expect("Failed to commit transaction");
+    if journal_path.exists() {
+        fs::remove_file(&journal_path).ok();
+    }
+    if !moved.is_empty()
+        && let Err(e) = write_move_map(&move_map_path(&db_path), &moved)
+    {
+        eprintln!("{}", format!("Warning: cannot save the move map for RepairPlaylists --after-move: {}", e).yellow());
+    }
+    summary
+}
+
+// Run the configured post_index_hook through the shell. A failing hook is reported but
+// doesn't fail the index, which has already been saved.
+fn run_post_index_hook(hook: &str, db_path: &str, summary: &IndexSummary) {
+    #[cfg(unix)]
+    let mut command = std::process::Command::new("sh");
+    #[cfg(unix)]
+    command.arg("-c").arg(hook);
+    #[cfg(windows)]
+    let mut command = std::process::Command::new("cmd");
+    #[cfg(windows)]
+    command.arg("/C").arg(hook);
+
+    let status = command
+        .env("APOLLO_ADDED", summary.added.to_string())
+        .env("APOLLO_UPDATED", summary.updated.to_string())
+        .env("APOLLO_REMOVED", summary.removed.to_string())
+        .env("APOLLO_MOVED", summary.moved.to_string())
+        .env("APOLLO_FAILED", summary.failed.to_string())
+        .env("APOLLO_DATABASE", db_path)
+        .stdin(std::process::Stdio::null())
+        .status();
+    match status {
+        Ok(status) if status.success() => println!("post_index_hook finished"),
+        Ok(status) => eprintln!("{}", format!("Warning: post_index_hook failed ({})", status).yellow()),
+        Err(e) => eprintln!("{}", format!("Warning: cannot run post_index_hook: {}", e).yellow()),
+    }
+}
+
+// The moves of the last organize run that moved anything, "source<TAB>destination" per line,
+// for RepairPlaylists --after-move
+fn move_map_path(db_path: &str) -> PathBuf {
+    Path::new(db_path).with_extension("last-moves")
+}
+
+fn write_move_map(map_path: &Path, moved: &[(String, String)]) -> std::io::Result<()> {
+    let content: String = moved.iter().map(|(from, to)| format!("{}\t{}\n", from, to)).collect();
+    fs::write(map_path, content)
+}
+
+fn read_move_map(map_path: &Path) -> std::io::Result<HashMap<PathBuf, PathBuf>> {
+    Ok(fs::read_to_string(map_path)?
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(from, to)| (PathBuf::from(from), PathBuf::from(to)))
+        .collect())
+}
+
+// Organize writes each step here ("mode<TAB>new|replace<TAB>source<TAB>destination") before
+// touching the file, and "done<TAB>source<TAB>destination" once the file operation succeeded.
+// The journal is removed once the database reflects the moves, so a journal left behind
+// means the last run was interrupted.
+fn organize_journal_path(db_path: &str) -> PathBuf {
+    Path::new(db_path).with_extension("organize-journal")
+}
+
+fn append_organize_journal(journal_path: &Path, line: &str) -> std::io::Result<()> {
+    let mut journal = fs::OpenOptions::new().create(true).append(true).open(journal_path)?;
+    writeln!(journal, "{}", line)?;
+    journal.sync_data()
+}
+
+// `replace` records that the destination already held a file (--on-conflict overwrite)
+fn journal_organize_step(journal_path: &Path, mode: OrganizeMode, replace: bool, from: &Path, to: &Path) -> std::io::Result<()> {
+    let state = if replace { "replace" } else { "new" };
+    append_organize_journal(journal_path, &format!("{}\t{}\t{}\t{}", mode.verb(), state, from.display(), to.display()))
+}
+
+fn journal_organize_done(journal_path: &Path, from: &Path, to: &Path) -> std::io::Result<()> {
+    append_organize_journal(journal_path, &format!("done\t{}\t{}", from.display(), to.display()))
+}
+
+// Complete the journaled steps whose file operation finished, undo half-copied ones, and
+// point the database at wherever each file ended up. A destination that existed before its
+// step is never deleted, since it may still be the file the step was going to replace.
+fn recover_interrupted_organize(conn: &rusqlite::Connection, journal_path: &Path) {
+    let journal = match fs::read_to_string(journal_path) {
+        Ok(journal) => journal,
+        Err(e) => {
+            eprintln!("{}", format!("Warning: cannot read {}: {}", journal_path.display(), e).yellow());
+            return;
+        }
+    };
+    println!("{}", "Recovering an interrupted organize run...".yellow());
+
+    let finished_steps: std::collections::HashSet<(&str, &str)> = journal
+        .lines()
+        .filter_map(|line| line.strip_prefix("done\t")?.split_once('\t'))
+        .collect();
+
+    let (mut completed, mut rolled_back) = (0, 0);
+    for line in journal.lines() {
+        let mut fields = line.splitn(4, '\t');
+        let (Some(mode), Some(state), Some(from), Some(to)) = (fields.next(), fields.next(), fields.next(), fields.next()) else {
+            continue;
+        };
+        let Ok(mode) = OrganizeMode::from_config(Some(mode)) else {
+            continue;
+        };
+        let replace = match state {
+            "new" => false,
+            "replace" => true,
+            _ => continue,
+        };
+        let (from_path, to_path) = (Path::new(from), Path::new(to));
+        let done = if finished_steps.contains(&(from, to)) {
+            // A move that fell back to copying may not have removed its source yet
+            if mode == OrganizeMode::Move && from_path.exists() {
+                let result = fs::remove_file(from_path);
+                log_mutation("delete", &[from_path], &result);
+            }
+            true
+        } else {
+            match (from_path.exists(), to_path.symlink_metadata().is_ok()) {
+                // Never started; the next organize pass will redo it
+                (true, false) => false,
+                // Only a finished move removes its source
+                (false, true) => mode == OrganizeMode::Move,
+                // Links may have been made just before the crash, or the destination may still be
+                // the file being replaced; the next organize pass sorts out either case
+                (true, true) if matches!(mode, OrganizeMode::Hardlink | OrganizeMode::Symlink) => false,
+                (true, true) => {
+                    // A copy that matches its source finished; otherwise it was cut short or never began
+                    let finished = matches!(
+                        (file_checksum(from_path), file_checksum(to_path)),
+                        (Ok(a), Ok(b)) if a == b
+                    );
+                    if finished && mode == OrganizeMode::Move {
+                        let result = fs::remove_file(from_path);
+                        log_mutation("delete", &[from_path], &result);
+                    } else if !finished && replace {
+                        eprintln!(
+                            "{}",
+                            format!("  Warning: left {} in place; it may still be the file {} was replacing", to, from).yellow()
+                        );
+                    } else if !finished {
+                        let result = fs::remove_file(to_path);
+                        log_mutation("delete", &[to_path], &result);
+                        println!("  Removed incomplete copy {}", to);
+                        rolled_back += 1;
+                    }
+                    finished
+                }
+                (false, _) => {
+                    eprintln!("{}", format!("  Warning: neither {} nor {} exists", from, to).yellow());
+                    false
+                }
+            }
+        };
+        if done {
+            conn.execute("UPDATE OR IGNORE tracks SET path = ?1 WHERE path = ?2", [to, from])
+                .expect("Failed to update organized track");
+            if mode == OrganizeMode::Move {
+                conn.execute("DELETE FROM tracks WHERE path = ?1", [from]).expect("Failed to remove stale track");
+            }
+            println!("  Completed {} {} -> {}", mode.verb(), from, to);
+            completed += 1;
+        }
+    }
+
+    println!("Recovered {} completed and {} incomplete organize step(s).", completed, rolled_back);
+    fs::remove_file(journal_path).ok();
+}
+
+// Every column of the tracks table that user input may name. Anything interpolated into SQL
+// must come from here (via validate_column), never from the user's string itself.
+const TRACK_COLUMNS: &[&str] = &[
+    "path", "artist", "albumartist", "album", "title", "duration", "year", "genre", "ext", "size",
+    "date_added", "real_format", "replaygain_track_gain", "track_number", "total_tracks", "disc_number",
+    "total_discs", "mtime", "checksum", "rating", "artists", "silence_secs", "mb_track_id", "mb_release_id",
+];
+
+// Map a user-supplied column name (case-insensitive) to its identifier in TRACK_COLUMNS
+fn validate_column(name: &str) -> Result<&'static str, String> {
+    TRACK_COLUMNS
+        .iter()
+        .copied()
+        .find(|column| column.eq_ignore_ascii_case(name.trim()))
+        .ok_or_else(|| format!("unknown column '{}' (expected one of: {})", name.trim(), TRACK_COLUMNS.join(", ")))
+}
+
+// Columns that may be used to group duplicates via the `dupe_key` config option
+const DUPE_KEY_COLUMNS: &[&str] = &["artist", "albumartist", "album", "title", "genre"];
+
+// SQL fragments for grouping by a dupe key: (column list, non-empty filter, per-group match),
+// plus the validated column names in key order
+struct DupeKeySql {
+    names: Vec<&'static str>,
+    columns: String,
+    filter: String,
+    matcher: String,
+}
+
+// Tracks with a MusicBrainz recording id are grouped by it alone (same id, same recording):
+// the first key column carries the id with this prefix and the others are empty. Tracks
+// without one fall back to the dupe_key columns.
+const MBID_KEY_PREFIX: &str = "mbid:";
+const HAS_MBID: &str = "COALESCE(mb_track_id, '') != ''";
+
+fn dupe_key_sql(dupe_key: &[String]) -> Result<DupeKeySql, String> {
+    // The key is interpolated into the grouping queries, so only allow known columns
+    if dupe_key.is_empty() {
+        return Err("dupe_key must list at least one column".to_string());
+    }
+    let mut columns = Vec::with_capacity(dupe_key.len());
+    for column in dupe_key {
+        match validate_column(column) {
+            Ok(column) if DUPE_KEY_COLUMNS.contains(&column) => columns.push(column),
+            _ => {
+                return Err(format!(
+                    "unknown dupe_key column '{}' (expected one of: {})",
+                    column,
+                    DUPE_KEY_COLUMNS.join(", ")
+                ));
+            }
+        }
+    }
+    let expressions: Vec<String> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, c)| match i {
+            0 => format!("CASE WHEN {} THEN '{}' || mb_track_id ELSE {} END", HAS_MBID, MBID_KEY_PREFIX, c),
+            _ => format!("CASE WHEN {} THEN '' ELSE {} END", HAS_MBID, c),
+        })
+        .collect();
+    Ok(DupeKeySql {
+        columns: expressions.join(", "),
+        filter: format!(
+            "({} OR ({}))",
+            HAS_MBID,
+            columns.iter().map(|c| format!("{} != ''", c)).collect::<Vec<_>>().join(" AND ")
+        ),
+        matcher: expressions.iter().enumerate().map(|(i, e)| format!("{} = ?{}", e, i + 1)).collect::<Vec<_>>().join(" AND "),
+        names: columns,
+    })
+}
+
+// Groups marked "keep both" in Dupes --fix, keyed on the dupe key columns and the group's
+// values for them, so a kept group never hides another group sharing some of its values
+fn create_kept_duplicates_table(conn: &rusqlite::Connection) {
+    create_table(conn,
+        "CREATE TABLE IF NOT EXISTS kept_duplicate_groups (
+            id INTEGER PRIMARY KEY,
+            dupe_key TEXT NOT NULL,
+            key_values TEXT NOT NULL,
+            UNIQUE(dupe_key, key_values)
+        )",
+    ).expect("Failed to create kept_duplicate_groups table");
+
+    // Older databases kept groups by artist/title alone, which is the default dupe key
+    let legacy = conn.query_row("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'kept_duplicates'", [], |_| Ok(()))
+        .is_ok();
+    if legacy && !read_only() {
+        conn.execute_batch(
+            "INSERT OR IGNORE INTO kept_duplicate_groups (dupe_key, key_values)
+                SELECT 'artist,title', json_array(artist, title) FROM kept_duplicates;
+             DROP TABLE kept_duplicates;",
+        ).expect("Failed to migrate kept_duplicates");
+    }
+}
+
+// How a duplicate group is stored in kept_duplicate_groups: (key definition, serialized values)
+fn kept_group_key(names: &[&str], values: &[String]) -> (String, String) {
+    (names.join(","), serde_json::to_string(values).expect("Failed to serialize dupe key"))
+}
+
+fn is_kept_duplicate(conn: &rusqlite::Connection, names: &[&str], values: &[String]) -> bool {
+    let (dupe_key, key_values) = kept_group_key(names, values);
+    conn.query_row(
+        "SELECT 1 FROM kept_duplicate_groups WHERE dupe_key = ?1 AND key_values = ?2",
+        [&dupe_key, &key_values],
+        |_| Ok(true),
+    ).unwrap_or(false)
+}
+
+// How a duplicate group is named in reports; MBID groups have empty trailing key values
+fn dupe_label(values: &[String]) -> String {
+    values.iter().filter(|v| !v.is_empty()).map(String::as_str).collect::<Vec<_>>().join(" - ")
+}
+
+// Which duplicate groups a report shows and in what order (Dupes --min-count, --sort-by-count)
+#[derive(Clone, Copy)]
+struct DupeGroups {
+    min_count: usize,
+    sort_by_count: bool,
+}
+
+impl DupeGroups {
+    // Copies a group needs to be reported, not counting ignored ones
+    fn min_copies(self) -> usize {
+        self.min_count.max(2)
+    }
+}
+
+// Tracks that count toward a duplicate group: everything not whitelisted with Dupes --ignore
+const NOT_IGNORED: &str = "id NOT IN (SELECT track_id FROM dupe_ignores)";
+
+// GROUP BY clause for the key `columns`, keeping groups of at least min_count tracks. The
+// query's WHERE should exclude NOT_IGNORED tracks so counts and order match what is shown.
+fn dupe_grouping(columns: &str, groups: DupeGroups) -> String {
+    let order = if groups.sort_by_count {
+        format!(" ORDER BY COUNT(*) DESC, {}", columns)
+    } else {
+        String::new()
+    };
+    format!("GROUP BY {} HAVING COUNT(*) >= {}{}", columns, groups.min_copies(), order)
+}
+
+// Map extensions to quality rank (lower is better)
+fn quality_rank(ext: &str) -> u8 {
+    match ext.to_lowercase().as_str() {
+        "flac" => 1,
+        "m4a" => 2,
+        "mp3" => 3,
+        _ => 100,
+    }
+}
+
+fn find_duplicates(db_path: &str, fix: bool, dupe_key: &[String], groups: DupeGroups) -> bool {
+    let DupeKeySql { names: key_names, columns: key_columns, filter: key_filter, matcher: key_match } = match dupe_key_sql(dupe_key) {
+        Ok(sql) => sql,
+        Err(e) => exit_with_error(EXIT_CONFIG, &e),
+    };
+
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+
+    create_kept_duplicates_table(&conn);
+    create_dupe_ignores_table(&conn);
+
+    // Paths of tracks the user has whitelisted as known false positives
+    let ignored: std::collections::HashSet<String> = {
+        let mut stmt = conn.prepare(
+            "SELECT t.path FROM dupe_ignores d JOIN tracks t ON t.id = d.track_id"
+        ).expect("Failed to prepare ignore statement");
+        stmt.query_map([], |row| row.get(0))
+            .expect("Failed to query ignored tracks")
+            .filter_map(Result::ok)
+            .collect()
+    };
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {}, COUNT(*) as count FROM tracks \
+         WHERE {} AND {} \
+         {}",
+        key_columns, key_filter, NOT_IGNORED, dupe_grouping(&key_columns, groups)
+    )).expect("Failed to prepare statement");
+
+    let mut rows = stmt.query([]).expect("Failed to execute query");
+
+    let mut found_duplicates = false;
+    while let Some(row) = rows.next().expect("Failed to fetch row") {
+        let values: Vec<String> = (0..dupe_key.len())
+            .map(|i| row.get(i).expect("Failed to get key column"))
+            .collect();
+        let count: i32 = row.get(dupe_key.len()).expect("Failed to get count");
+        let label = dupe_label(&values);
+
+        // Check if this duplicate is marked as "keep both"
+        let is_kept = is_kept_duplicate(&conn, &key_names, &values);
+
+        // Query for file paths of this duplicate track
+        let mut path_stmt = conn.prepare(
+            &format!("SELECT id, path, album, COALESCE(real_format, ext, '') FROM tracks WHERE {}", key_match)
+        ).expect("Failed to prepare path statement");
+
+        let mut path_rows = path_stmt.query(rusqlite::params_from_iter(&values)).expect("Failed to execute path query");
+        let mut paths = Vec::new();
+        let mut details = Vec::new();
+        while let Some(path_row) = path_rows.next().expect("Failed to fetch path row") {
+            let id: i64 = path_row.get(0).expect("Failed to get id");
+            let path: String = path_row.get(1).expect("Failed to get path");
+            let album: String = path_row.get(2).expect("Failed to get album");
+            let format: String = path_row.get(3).expect("Failed to get format");
+            details.push((format, album));
+            paths.push((id, path));
+        }
+
+        if paths.iter().filter(|(_, p)| !ignored.contains(p)).count() < groups.min_copies() {
+            continue;
+        }
+        found_duplicates = true;
+
+        let keep_tag = if is_kept { "[Keep All] ".green() } else { "".green() };
+        println!("{}{} {}", keep_tag, label.cyan(), format!("(x{})", count).yellow());
+        // Format, bitrate and album tell editions apart without opening the files
+        for ((id, path), (format, album)) in paths.iter().zip(&details) {
+            let bitrate = get_bitrate_with_lofty(Path::new(path))
+                .map(|kbps| format!(" {}k", kbps))
+                .unwrap_or_default();
+            let album = if album.is_empty() { String::new() } else { format!(" \u{2014} album: {}", album) };
+            // Ignored copies are listed for context but left out of the (xN) count
+            let ignored_tag = if ignored.contains(path) { " (ignored)" } else { "" };
+            println!(
+                "  {} [{}{}] {}{}{}",
+                format!("#{:<5}", id).yellow(),
+                format.to_uppercase(),
+                bitrate,
+                path,
+                album,
+                ignored_tag
+            );
+        }
+
+        if fix && paths.len() > 1 && !is_kept {
+            // Make "Skip" and "Keep both" the first options
+            let mut options: Vec<String> = vec!["Skip".to_string(), "Keep both".to_string()];
+            options.extend(paths.iter().map(|(_, p)| p.clone()));
+            match inquire::Select::new(
+                &format!("Which file do you want to keep for '{}'?", label),
+                options.clone(),
+            ).prompt() {
+                Ok(selected) if selected != "Skip" && selected != "Keep both" => {
+                    // Remove all except the selected one
+                    for (id, path) in &paths {
+                        if path != &selected {
+                            // Delete from database
+                            conn.execute("DELETE FROM tracks WHERE id = ?1", [id]).expect("Failed to delete duplicate");
+                            println!("  Removed duplicate from database: {}", path);
+                            // Delete from filesystem
+                            let result = std::fs::remove_file(path);
+                            log_mutation("delete", &[Path::new(path)], &result);
+                            match result {
+                                Ok(_) => println!("  Deleted file from filesystem: {}", path),
+                                Err(e) => eprintln!("  Failed to delete file '{}': {}", path, e),
+                            }
+                        }
+                    }
+                }
+                Ok(selected) if selected == "Keep both" => {
+                    let (kept_key, kept_values) = kept_group_key(&key_names, &values);
+                    conn.execute(
+                        "INSERT OR IGNORE INTO kept_duplicate_groups (dupe_key, key_values) VALUES (?1, ?2)",
+                        [&kept_key, &kept_values],
+                    ).expect("Failed to save kept duplicate");
+                    println!("  Keeping all copies of '{}' (won't show again)", label);
+                }
+                Ok(_) | Err(_) => {
+                    println!("  Skipped fixing '{}'", label);
+                }
+            }
+        }
+    }
+
+    if !found_duplicates {
+        println!("{}", "No duplicate tracks found.".green());
+    }
+
+    // Identify tracks where a lower quality version exists (FLAC > M4A > MP3)
+    println!("\nTracks with lower quality duplicates (FLAC > M4A > MP3):");
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM tracks \
+         WHERE {} AND {} \
+         {}",
+        key_columns, key_filter, NOT_IGNORED, dupe_grouping(&key_columns, groups)
+    )).expect("Failed to prepare statement for quality check");
+
+    let mut rows = stmt.query([]).expect("Failed to execute quality check query");
+
+    let real_formats: HashMap<String, String> = {
+        let mut stmt = conn.prepare(
+            "SELECT path, real_format FROM tracks WHERE real_format IS NOT NULL"
+        ).expect("Failed to prepare format statement");
+        stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .expect("Failed to query formats")
+            .filter_map(Result::ok)
+            .collect()
+    };
+
+    let mut found_quality_dupes = false;
+    while let Some(row) = rows.next().expect("Failed to fetch row") {
+        let values: Vec<String> = (0..dupe_key.len())
+            .map(|i| row.get(i).expect("Failed to get key column"))
+            .collect();
+        let paths = dupe_group_paths(&conn, &key_match, &values);
+        let files: Vec<&str> = paths.iter().map(String::as_str).collect();
+        if files.iter().filter(|p| !ignored.contains(**p)).count() < groups.min_copies() {
+            continue;
+        }
+
+        // Prefer the detected container over the extension, which may be wrong
+        let mut qualities: Vec<(u8, &str)> = files.iter()
+            .filter_map(|p| {
+                real_formats.get(*p)
+                    .map(|f| f.as_str())
+                    .or_else(|| std::path::Path::new(p).extension().and_then(|e| e.to_str()))
+                    .map(|ext| (quality_rank(ext), *p))
+            })
+            .collect();
+
+        qualities.sort_by_key(|q| q.0);
+
+        // If there are at least two files and the best quality is not the only one
+        if qualities.len() > 1 && qualities[0].0 < qualities[1].0 {
+            found_quality_dupes = true;
+            println!("{}", dupe_label(&values).cyan());
+            for (rank, path) in &qualities {
+                let label = match rank {
+                    1 => "FLAC",
+                    2 => "M4A",
+                    3 => "MP3",
+                    _ => "OTHER",
+                };
+                println!("  [{}] {}", label, path);
+            }
+        }
+    }
+
+    if !found_quality_dupes {
+        println!("{}", "No lower quality duplicates found.".green());
+    }
+    found_duplicates || found_quality_dupes
+}
+
+// Paths of one duplicate group, by path. They are fetched per group because a joined
+// list can't be split safely when paths contain commas.
+fn dupe_group_paths(conn: &rusqlite::Connection, matcher: &str, values: &[String]) -> Vec<String> {
+    let mut stmt = conn
+        .prepare_cached(&format!("SELECT path FROM tracks WHERE {} ORDER BY path", matcher))
+        .expect("Failed to prepare group path statement");
+    stmt.query_map(rusqlite::params_from_iter(values), |row| row.get(0))
+        .expect("Failed to execute group path query")
+        .filter_map(Result::ok)
+        .collect()
+}
+
+fn print_duplicates_by_folder(db_path: &str, dupe_key: &[String], groups: DupeGroups) -> bool {
+    let key = match dupe_key_sql(dupe_key) {
+        Ok(sql) => sql,
+        Err(e) => exit_with_error(EXIT_CONFIG, &e),
+    };
+
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+    create_dupe_ignores_table(&conn);
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM tracks WHERE {} AND {} {}",
+        key.columns, key.filter, NOT_IGNORED, dupe_grouping(&key.columns, groups)
+    )).expect("Failed to prepare statement");
+    let group_keys: Vec<Vec<String>> = stmt
+        .query_map([], |row| (0..dupe_key.len()).map(|i| row.get(i)).collect())
+        .expect("Failed to execute query")
+        .filter_map(Result::ok)
+        .collect();
+
+    let mut path_stmt = conn.prepare(&format!(
+        "SELECT path FROM tracks WHERE {} AND {}",
+        key.matcher, NOT_IGNORED
+    )).expect("Failed to prepare path statement");
+
+    let mut found = false;
+    for values in group_keys {
+        let paths: Vec<String> = path_stmt
+            .query_map(rusqlite::params_from_iter(&values), |row| row.get(0))
+            .expect("Failed to execute path query")
+            .filter_map(Result::ok)
+            .collect();
+        if paths.len() < groups.min_copies() {
+            continue;
+        }
+        found = true;
+
+        // Count copies per parent directory, keeping first-seen order
+        let mut folders: Vec<(String, usize)> = Vec::new();
+        for path in &paths {
+            let folder = Path::new(path).parent().map(|p| p.display().to_string()).unwrap_or_default();
+            match folders.iter_mut().find(|(f, _)| *f == folder) {
+                Some((_, count)) => *count += 1,
+                None => folders.push((folder, 1)),
+            }
+        }
+
+        println!("{} {}", dupe_label(&values).cyan(), format!("(x{})", paths.len()).yellow());
+        for (folder, count) in &folders {
+            if *count > 1 {
+                println!("  {} {}", folder, format!("[{} copies in the same folder]", count).red());
+            } else {
+                println!("  {}", folder);
+            }
+        }
+    }
+
+    if !found {
+        println!("{}", "No duplicate tracks found.".green());
+    }
+    found
+}
+
+// Filename stem used to match untagged copies: lowercased, whitespace collapsed and copy
+// markers such as "(1)", "[2]" or " - Copy" removed, so "Song (1).mp3" matches "song.mp3"
+fn normalized_file_stem(path: &Path) -> String {
+    static COPY_MARKER: OnceLock<regex::Regex> = OnceLock::new();
+    let copy_marker = COPY_MARKER.get_or_init(|| {
+        regex::Regex::new(r"(?:\s*(?:\(\d+\)|\[\d+\])|\s+(?:-\s*)?copy(?:\s+\d+)?)$").expect("valid copy marker regex")
+    });
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_lowercase()).unwrap_or_default();
+    let mut stem = stem.split_whitespace().collect::<Vec<_>>().join(" ");
+    while let Some(marker) = copy_marker.find(&stem) {
+        if marker.start() == 0 {
+            break;
+        }
+        stem.truncate(marker.start());
+    }
+    stem
+}
+
+// Duplicates among tracks the tag-based scan skips because their artist or title is empty
+fn print_duplicates_by_filename(db_path: &str, groups: DupeGroups) -> bool {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+    create_dupe_ignores_table(&conn);
+
+    let mut stmt = conn.prepare(
+        "SELECT id, path, COALESCE(real_format, ext, '') FROM tracks \
+         WHERE (COALESCE(artist, '') = '' OR COALESCE(title, '') = '') \
+         AND id NOT IN (SELECT track_id FROM dupe_ignores) ORDER BY path"
+    ).expect("Failed to prepare statement");
+    let mut by_stem: HashMap<String, Vec<(i64, String, String)>> = HashMap::new();
+    for (id, path, format) in stmt
+        .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?)))
+        .expect("Failed to execute query")
+        .filter_map(Result::ok)
+    {
+        by_stem.entry(normalized_file_stem(Path::new(&path))).or_default().push((id, path, format));
+    }
+
+    let mut matches: Vec<_> = by_stem
+        .into_iter()
+        .filter(|(stem, files)| !stem.is_empty() && files.len() >= groups.min_copies())
+        .collect();
+    if groups.sort_by_count {
+        matches.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then_with(|| a.0.cmp(&b.0)));
+    } else {
+        matches.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+
+    for (stem, files) in &matches {
+        println!("{} {}", stem.cyan(), format!("(x{})", files.len()).yellow());
+        for (id, path, format) in files {
+            println!("  {} [{}] {}", format!("#{:<5}", id).yellow(), format.to_uppercase(), path);
+        }
+    }
+
+    if matches.is_empty() {
+        println!("{}", "No untagged duplicates found.".green());
+    }
+    !matches.is_empty()
+}
+
+#[derive(Clone, Serialize)]
+struct DuplicateFile {
+    id: i64,
+    path: String,
+    format: String,
+    bitrate: Option<u32>, // kbps
+    size: i64,
+    ignored: bool,
+    mb_track_id: Option<String>,
+    mb_release_id: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+struct DuplicateGroup {
+    artist: String,
+    title: String,
+    mb_track_id: Option<String>, // set when the files were grouped by MusicBrainz recording id
+    key: std::collections::BTreeMap<String, String>, // every dupe_key column, for keys beyond artist/title
+    kept: bool,                   // marked "Keep both" during --fix
+    files: Vec<DuplicateFile>,
+}
+
+#[derive(Serialize)]
+struct DuplicateReport {
+    exact: Vec<DuplicateGroup>,
+    quality: Vec<DuplicateGroup>, // groups where a better format makes the other copies redundant
+}
+
+// One file of an exact duplicate group, flattened for table and CSV output
+#[derive(Serialize)]
+struct DuplicateRow {
+    group: usize,
+    artist: String,
+    title: String,
+    id: i64,
+    format: String,
+    bitrate: Option<u32>,
+    size: i64,
+    path: String,
+}
+
+impl Renderable for DuplicateRow {
+    fn headers() -> &'static [&'static str] {
+        &["Group", "Artist", "Title", "Id", "Format", "Bitrate", "Size", "Path"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.group.to_string(),
+            self.artist.clone(),
+            self.title.clone(),
+            self.id.to_string(),
+            self.format.clone(),
+            self.bitrate.map(|kbps| kbps.to_string()).unwrap_or_default(),
+            self.size.to_string(),
+            self.path.clone(),
+        ]
+    }
+}
+
+// Structured counterpart of find_duplicates' report, for scripts; never prompts. JSON keeps
+// the grouped report, table and CSV list one row per non-ignored file of each exact group.
+fn print_duplicates_structured(db_path: &str, dupe_key: &[String], format: OutputFormat, groups: DupeGroups) -> bool {
+    let key = match dupe_key_sql(dupe_key) {
+        Ok(sql) => sql,
+        Err(e) => exit_with_error(EXIT_CONFIG, &e),
+    };
+
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+    create_kept_duplicates_table(&conn);
+    create_dupe_ignores_table(&conn);
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM tracks WHERE {} AND {} {}",
+        key.columns, key.filter, NOT_IGNORED, dupe_grouping(&key.columns, groups)
+    )).expect("Failed to prepare statement");
+    let group_keys: Vec<Vec<String>> = stmt
+        .query_map([], |row| (0..dupe_key.len()).map(|i| row.get(i)).collect())
+        .expect("Failed to execute query")
+        .filter_map(Result::ok)
+        .collect();
+
+    let mut file_stmt = conn.prepare(&format!(
+        "SELECT id, path, COALESCE(real_format, ext, ''), COALESCE(size, 0), \
+         id IN (SELECT track_id FROM dupe_ignores), mb_track_id, mb_release_id FROM tracks WHERE {} ORDER BY path",
+        key.matcher
+    )).expect("Failed to prepare file statement");
+
+    let mut report = DuplicateReport { exact: Vec::new(), quality: Vec::new() };
+    for values in group_keys {
+        let files: Vec<DuplicateFile> = file_stmt
+            .query_map(rusqlite::params_from_iter(&values), |row| {
+                let path: String = row.get(1)?;
+                Ok(DuplicateFile {
+                    id: row.get(0)?,
+                    bitrate: get_bitrate_with_lofty(Path::new(&path)),
+                    path,
+                    format: row.get::<_, String>(2)?.to_lowercase(),
+                    size: row.get(3)?,
+                    ignored: row.get(4)?,
+                    mb_track_id: row.get(5)?,
+                    mb_release_id: row.get(6)?,
+                })
+            })
+            .expect("Failed to execute file query")
+            .filter_map(Result::ok)
+            .collect();
+        if files.iter().filter(|f| !f.ignored).count() < groups.min_copies() {
+            continue;
+        }
+
+        let key_values: std::collections::BTreeMap<String, String> =
+            key.names.iter().map(|name| name.to_string()).zip(values.iter().cloned()).collect();
+        let mb_track_id = values[0].strip_prefix(MBID_KEY_PREFIX).map(str::to_string);
+        // An MBID group is named after its first file, since artist and title aren't part of its key
+        let (artist, title) = match mb_track_id {
+            Some(_) => conn.query_row(
+                "SELECT COALESCE(artist, ''), COALESCE(title, '') FROM tracks WHERE id = ?1",
+                [files[0].id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            ).unwrap_or_default(),
+            None => (
+                key_values.get("artist").cloned().unwrap_or_default(),
+                key_values.get("title").cloned().unwrap_or_default(),
+            ),
+        };
+        let kept = is_kept_duplicate(&conn, &key.names, &values);
+
+        // Same rule as the text report: the best format must beat the next best
+        let mut ranks: Vec<u8> = files.iter().map(|f| quality_rank(&f.format)).collect();
+        ranks.sort();
+        let group = DuplicateGroup { artist, title, mb_track_id, key: key_values, kept, files };
+        if ranks[0] < ranks[1] {
+            report.quality.push(group.clone());
+        }
+        report.exact.push(group);
+    }
+
+    if format == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&report).expect("Failed to serialize duplicates"));
+    } else {
+        let rows: Vec<DuplicateRow> = report.exact.iter().enumerate()
+            .flat_map(|(i, group)| group.files.iter().filter(|f| !f.ignored).map(move |f| DuplicateRow {
+                group: i + 1,
+                artist: group.artist.clone(),
+                title: group.title.clone(),
+                id: f.id,
+                format: f.format.clone(),
+                bitrate: f.bitrate,
+                size: f.size,
+                path: f.path.clone(),
+            }))
+            .collect();
+        render(&rows, format);
+    }
+    !report.exact.is_empty()
+}
+
+// For each quality duplicate group, move the best copy to its file_pattern path and the rest
+// under archive_directory (keeping their layout). Only organize_mode "move" is allowed: a copy
+// or link would leave the duplicates in the library for the next Index to pick up again.
+fn promote_quality_dupes(settings: &Settings, dupe_key: &[String], dry_run: bool) {
+    let key = match dupe_key_sql(dupe_key) {
+        Ok(sql) => sql,
+        Err(e) => exit_with_error(EXIT_CONFIG, &e),
+    };
+    let Some(archive_dir) = settings.files.archive_directory.as_deref().map(expand_tilde) else {
+        exit_with_error(EXIT_CONFIG, "--promote needs an archive_directory in the config");
+    };
+    match OrganizeMode::from_config(settings.files.organize_mode.as_deref()) {
+        Ok(OrganizeMode::Move) => {}
+        Ok(mode) => exit_with_error(
+            EXIT_CONFIG,
+            &format!("--promote moves files, but organize_mode is '{}'; set it to 'move'", mode.verb()),
+        ),
+        Err(e) => exit_with_error(EXIT_CONFIG, &e),
+    }
+    let music_dir = expand_tilde(&settings.files.music_directory);
+    let labels = &settings.unknown_label;
+
+    let db_path = expand_tilde(&settings.files.database_name);
+    let conn = open_db(&db_path);
+    create_dupe_ignores_table(&conn);
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM tracks WHERE {} AND {} GROUP BY {} HAVING COUNT(*) > 1",
+        key.columns, key.filter, NOT_IGNORED, key.columns
+    )).expect("Failed to prepare statement");
+    let groups: Vec<Vec<String>> = stmt
+        .query_map([], |row| (0..dupe_key.len()).map(|i| row.get(i)).collect())
+        .expect("Failed to execute query")
+        .filter_map(Result::ok)
+        .collect();
+
+    let mut file_stmt = conn.prepare(&format!(
+        "SELECT id, path, COALESCE(real_format, ext, ''), artist, albumartist, album, title, COALESCE(genre, '') FROM tracks \
+         WHERE {} AND id NOT IN (SELECT track_id FROM dupe_ignores)",
+        key.matcher
+    )).expect("Failed to prepare file statement");
+
+    let (mut promoted, mut archived) = (0, 0);
+    let mut moved: Vec<(String, String)> = Vec::new();
+    for values in groups {
+        let mut files: Vec<(u8, i64, String, [String; 5])> = file_stmt
+            .query_map(rusqlite::params_from_iter(&values), |row| {
+                let format: String = row.get(2)?;
+                Ok((quality_rank(&format), row.get(0)?, row.get(1)?, [row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?, row.get(7)?]))
+            })
+            .expect("Failed to execute file query")
+            .filter_map(Result::ok)
+            .collect();
+        files.sort_by_key(|f| f.0);
+        if files.len() < 2 || files[0].0 == files[1].0 {
+            continue;
+        }
+        println!("{}", dupe_label(&values).cyan());
+
+        let (_, winner_id, winner_path, [artist, albumartist, album, title, genre]) = &files[0];
+        let winner_path = Path::new(winner_path);
+        if let Some(pattern) = settings.files.file_pattern.as_deref() {
+            let ext = winner_path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+            let primary = organize_root(&settings.files, &music_dir, genre).join(generate_path_from_pattern(
+                pattern,
+                labels.artist_or(artist),
+                if settings.is_various_artists(albumartist) { "" } else { albumartist },
+                labels.album_or(album),
+                labels.title_or(title),
+                ext,
+                &settings.replace,
+            ));
+            if primary != winner_path && dry_run {
+                println!("  [dry-run] Would promote {} -> {}", winner_path.display(), primary.display());
+                promoted += 1;
+            } else if primary != winner_path {
+                match relocate(winner_path, &primary, OrganizeMode::Move) {
+                    Ok(()) => {
+                        let primary = primary.to_string_lossy().to_string();
+                        conn.execute("UPDATE tracks SET path = ?1 WHERE id = ?2", rusqlite::params![primary, winner_id])
+                            .expect("Failed to update promoted track");
+                        println!("  Promoted {} -> {}", winner_path.display(), primary);
+                        moved.push((winner_path.to_string_lossy().to_string(), primary));
+                        promoted += 1;
+                    }
+                    Err(e) => eprintln!("{}", format!("  Failed to promote {}: {}", winner_path.display(), e).yellow()),
+                }
+            }
+        }
+
+        for (_, id, path, _) in &files[1..] {
+            let path = Path::new(path);
+            let rel_path = path.strip_prefix(&music_dir).unwrap_or(path.file_name().map_or(path, Path::new));
+            let archived_path = Path::new(&archive_dir).join(rel_path);
+            if dry_run {
+                println!("  [dry-run] Would archive {} -> {}", path.display(), archived_path.display());
+                archived += 1;
+                continue;
+            }
+            match relocate(path, &archived_path, OrganizeMode::Move) {
+                Ok(()) => {
+                    // The archived copy has left the library
+                    conn.execute("DELETE FROM tracks WHERE id = ?1", [id]).expect("Failed to remove archived track");
+                    println!("  Archived {} -> {}", path.display(), archived_path.display());
+                    moved.push((path.to_string_lossy().to_string(), archived_path.to_string_lossy().to_string()));
+                    archived += 1;
+                }
+                Err(e) => eprintln!("{}", format!("  Failed to archive {}: {}", path.display(), e).yellow()),
+            }
+        }
+    }
+
+    if dry_run {
+        println!("{}", format!("Would promote {} file(s) and archive {} lower-quality copies.", promoted, archived).green());
+        return;
+    }
+    if !moved.is_empty()
+        && let Err(e) = write_move_map(&move_map_path(&db_path), &moved)
+    {
+        eprintln!("{}", format!("Warning: cannot save the move map for RepairPlaylists --after-move: {}", e).yellow());
+    }
+    println!("{}", format!("Promoted {} file(s) and archived {} lower-quality copies.", promoted, archived).green());
+}
+
+// organize_file, refusing to overwrite an existing destination
+fn relocate(from: &Path, to: &Path, mode: OrganizeMode) -> std::io::Result<()> {
+    if to.exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} already exists", to.display()),
+        ));
+    }
+    organize_file(from, to, mode)
+}
+
+fn create_dupe_ignores_table(conn: &rusqlite::Connection) {
+    create_table(conn,
+        "CREATE TABLE IF NOT EXISTS dupe_ignores (
+            track_id INTEGER PRIMARY KEY
+        )",
+    ).expect("Failed to create dupe_ignores table");
+}
+
+fn update_dupe_ignores(db_path: &str, ignore: &[i64], clear: bool) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+    create_dupe_ignores_table(&conn);
+
+    if clear {
+        let removed = conn.execute("DELETE FROM dupe_ignores", []).expect("Failed to clear dupe ignores");
+        println!("Cleared {} ignored tracks", removed);
+    }
+
+    for id in ignore {
+        let path: Option<String> = conn.query_row(
+            "SELECT path FROM tracks WHERE id = ?1",
+            [id],
+            |row| row.get(0),
+        ).ok();
+        match path {
+            Some(path) => {
+                conn.execute("INSERT OR IGNORE INTO dupe_ignores (track_id) VALUES (?1)", [id])
+                    .expect("Failed to save dupe ignore");
+                println!("Ignoring #{} in duplicate scans: {}", id, path);
+            }
+            None => eprintln!("{}", format!("No track with id {}", id).red()),
+        }
+    }
+}
+
+// How missing playlist entries are resolved
+#[derive(Clone, Copy, Default)]
+struct PlaylistRepair<'a> {
+    confirm_auto: bool,           // prompt even when a suggestion is above the auto-replace threshold
+    auto_only: bool,              // apply auto-replacements but never prompt
+    dry_run: bool,                // report what would change without writing playlists
+    artist_sep: Option<&'a str>,  // separator for parsing missing entries' filenames
+    metric: SimilarityMetric,     // how suggestions are scored against the entry's title
+    after_move: bool,             // re-point entries from the last organize's move map before suggesting
+}
+
+// Title and path of every indexed track, used to suggest replacements for missing entries
+fn load_track_titles(db_path: &str) -> Vec<(String, String)> {
+    let conn = open_db(db_path);
+    let mut stmt = conn.prepare("SELECT title, path FROM tracks").expect("Failed to prepare statement");
+    let mut rows = stmt.query([]).expect("Failed to execute query");
+    let mut tracks = Vec::new();
+    while let Some(row) = rows.next().expect("Failed to fetch row") {
+        let title: String = row.get(0).expect("Failed to get title");
+        let path: String = row.get(1).expect("Failed to get path");
+        tracks.push((title, path));
+    }
+    tracks
+}
+
+fn repair_playlist(
+    conn: &rusqlite::Connection,
+    path: &Path,
+    name: &str,
+    all_tracks: &[(String, String)],
+    repair: PlaylistRepair<'_>,
+) {
+    let playlist_path = &*path.to_string_lossy();
+    // Check for missing files in the playlist
+    for song_path in parse_playlist(path) {
+        if !song_path.exists() {
+            println!(
+                "Missing file in playlist '{}': {}",
+                name,
+                song_path.display()
+            );
+
+            // Suggest similar files in the music directory
+            let song_file_name = song_path.file_name().and_then(|f| f.to_str()).unwrap_or("");
+            let song_name = extract_song_name_from_filename(song_file_name, repair.artist_sep)
+                .map(|(_, title)| title)
+                .unwrap_or_else(|| song_file_name.to_string());
+            println!("  Suggested song name: {}", song_name);
+            if !song_file_name.is_empty() {
+                // Use cached tracks instead of opening a new connection
+                let mut suggestions = Vec::new();
+                for (candidate_title, candidate_path) in all_tracks {
+                    let score = repair.metric.score(candidate_title, &song_name);
+                    suggestions.push((score, candidate_path.clone()));
+                }
+                // Sort by descending similarity score and take top 5
+                suggestions.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+                let top_suggestions: Vec<_> = suggestions.into_iter().take(5).collect();
+                if !top_suggestions.is_empty() {
+                    let mut options: Vec<String> = top_suggestions
+                        .iter()
+                        .map(|(score, suggestion)| format!("({:.3}) {} ", score, suggestion))
+                        .collect();
+                    options.push("Remove".to_string());
+                    options.push("Skip".to_string());
+
+                    // Auto-replace if top suggestion is very similar
+                    let (top_score, top_path) = &top_suggestions[0];
+                    if *top_score >= 0.9 && !repair.confirm_auto {
+                        if repair.dry_run {
+                            println!("  [dry-run] Would replace '{}' with '{}' (similarity {:.3})", song_path.display(), top_path, top_score);
+                            continue;
+                        }
+                        println!("  Auto-replacing '{}' with '{}' (similarity {:.3})", song_path.display(), top_path, top_score);
+                        update_playlist_line(playlist_path, &song_path.display().to_string(), top_path).expect("Failed to update playlist");
+                        // Logged so a false positive can be reverted with `apollo review-autofixes`
+                        conn.execute(
+                            "INSERT INTO playlist_autofix_log (playlist, old_path, new_path, score, fixed_at) \
+                             VALUES (?1, ?2, ?3, ?4, strftime('%s', 'now'))",
+                            rusqlite::params![playlist_path, song_path.display().to_string(), top_path, top_score],
+                        ).expect("Failed to log playlist autofix");
+                    } else if repair.dry_run || repair.auto_only {
+                        println!(
+                            "  Skipped '{}': best suggestion '{}' is below the auto-replace threshold ({:.3})",
+                            song_path.display(), top_path, top_score
+                        );
+                    } else {
+                        // Use inquire to let user select a replacement or skip
+                        match inquire::Select::new(
+                            &format!("Select a replacement for '{}':", song_file_name),
+                            options.clone(),
+                        ).prompt() {
+                            Ok(selected) if selected != "Skip" && selected != "Remove" => {
+                                // Extract the path from the selected option (before the space)
+                                // Extract the path from the selected option: format is "(score) path"
+                                let selected_path = selected
+                                    .split_once(')')
+                                    .map(|(_, s)| s.trim())
+                                    .unwrap_or(&selected);
+                                println!("  Replacing '{}' with '{}'", song_path.display(), selected_path);
+                                update_playlist_line(playlist_path, &song_path.display().to_string(), selected_path).expect("Failed to update playlist");
+                            }
+                            Ok(selected) if selected == "Remove" => {
+                                // Remove the missing song from the playlist file
+                                println!("  Removing '{}' from playlist", song_path.display());
+                                // Use update_playlist_line with new_line as empty string to indicate removal
+                                if let Err(e) = update_playlist_line(playlist_path, &song_path.display().to_string(), "") {
+                                    eprintln!("Failed to update playlist file: {}", e);
+                                }
+                            }
+                            Ok(_) | Err(_) => {
+                                println!("  Skipped replacement for '{}'", song_path.display());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Repair playlists already in the database without re-indexing the library
+fn repair_playlists(db_path: &str, repair: PlaylistRepair<'_>) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+    create_autofix_log_table(&conn);
+
+    let playlists: Vec<(String, String)> = match conn.prepare("SELECT name, path FROM playlists ORDER BY name") {
+        Ok(mut stmt) => stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .expect("Failed to query playlists")
+            .filter_map(Result::ok)
+            .collect(),
+        // The playlists table is created by the first Index
+        Err(_) => Vec::new(),
+    };
+    if playlists.is_empty() {
+        println!("{}", "No playlists indexed. Run 'apollo index' first.".yellow());
+        return;
+    }
+
+    let moves = if repair.after_move {
+        let map_path = move_map_path(&db_path);
+        read_move_map(&map_path).unwrap_or_else(|e| {
+            exit_with_error(EXIT_ERROR, &format!("no move map at {} ({}); run 'apollo index --organize' first", map_path.display(), e))
+        })
+    } else {
+        HashMap::new()
+    };
+
+    let all_tracks = load_track_titles(&db_path);
+    for (name, path) in playlists {
+        let path = Path::new(&path);
+        if !path.exists() {
+            println!("{}", format!("Skipping '{}': {} no longer exists", name, path.display()).yellow());
+            continue;
+        }
+        if !moves.is_empty() {
+            match rewrite_moved_entries(path, &moves, repair.dry_run) {
+                Ok(0) => {}
+                Ok(n) if repair.dry_run => println!("Would re-point {} moved track(s) in '{}'", n, name),
+                Ok(n) => println!("Re-pointed {} moved track(s) in '{}'", n, name),
+                Err(e) => eprintln!("{}", format!("Failed to update playlist '{}': {}", name, e).red()),
+            }
+        }
+        // Whatever the move map didn't cover falls back to similarity suggestions
+        repair_playlist(&conn, path, &name, &all_tracks, repair);
+    }
+}
+
+// Replace every missing entry that the move map has a destination for, writing the playlist
+// once. Returns how many entries were (or, with dry_run, would be) replaced.
+fn rewrite_moved_entries(path: &Path, moves: &HashMap<PathBuf, PathBuf>, dry_run: bool) -> std::io::Result<usize> {
+    let Some(format) = PlaylistFormat::from_path(path) else {
+        return Ok(0);
+    };
+    let content = fs::read_to_string(path)?;
+    let playlist_dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut new_content = String::with_capacity(content.len());
+    let mut copied_up_to = 0;
+    let mut replaced = 0;
+    for (span, entry) in playlist_entry_spans(format, &content) {
+        let old_path = resolve_playlist_entry(playlist_dir, &entry);
+        if old_path.exists() {
+            continue;
+        }
+        let Some(new_path) = moves.get(&old_path) else {
+            continue;
+        };
+        // Relative entries stay relative, like update_playlist_line
+        let replacement = if format == PlaylistFormat::Xspf {
+            encode_file_uri(new_path)
+        } else if Path::new(&entry).is_absolute() {
+            new_path.to_string_lossy().to_string()
+        } else {
+            new_path.strip_prefix(playlist_dir).unwrap_or(new_path).to_string_lossy().to_string()
+        };
+        new_content.push_str(&content[copied_up_to..span.start]);
+        new_content.push_str(&replacement);
+        copied_up_to = span.end;
+        replaced += 1;
+    }
+    if replaced == 0 || dry_run {
+        return Ok(replaced);
+    }
+    new_content.push_str(&content[copied_up_to..]);
+    let result = fs::write(path, new_content);
+    log_mutation("write-playlist", &[path], &result);
+    result.map(|()| replaced)
+}
+
+// Playlists: near-identical playlists that could be merged, and the tracks included
+// most often, which make good candidates for a "frequently included" list
+fn print_playlist_overlap(db_path: &str, top: usize) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+    create_playlists_table(&conn);
+
+    let playlists: Vec<(String, std::collections::HashSet<PathBuf>)> = {
+        let mut stmt = conn.prepare("SELECT name, path FROM playlists ORDER BY name").expect("Failed to prepare statement");
+        stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+            .expect("Failed to query playlists")
+            .filter_map(Result::ok)
+            .filter(|(_, path)| Path::new(path).exists())
+            .map(|(name, path)| (name, parse_playlist(Path::new(&path)).into_iter().collect()))
+            .collect()
+    };
+    if playlists.len() < 2 {
+        println!("{}", "Fewer than two playlists indexed. Run 'apollo index' first.".yellow());
+        return;
+    }
+
+    let mut pairs: Vec<(f64, usize, &str, &str)> = Vec::new();
+    for (i, (name_a, entries_a)) in playlists.iter().enumerate() {
+        for (name_b, entries_b) in &playlists[i + 1..] {
+            let shared = entries_a.intersection(entries_b).count();
+            if shared > 0 {
+                let union = entries_a.len() + entries_b.len() - shared;
+                pairs.push((shared as f64 / union as f64, shared, name_a, name_b));
+            }
+        }
+    }
+    pairs.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal).then(b.1.cmp(&a.1)));
+
+    println!("{}", "Most similar playlists:".bold());
+    if pairs.is_empty() {
+        println!("{}", "No two playlists share a track.".green());
+    } else {
+        let rows: Vec<Vec<String>> = pairs
+            .iter()
+            .take(top)
+            .map(|(similarity, shared, a, b)| {
+                vec![format!("{:.0}%", similarity * 100.0), shared.to_string(), a.to_string(), b.to_string()]
+            })
+            .collect();
+        print_table(&["Similarity", "Shared", "Playlist", "Playlist"], &rows);
+    }
+
+    let mut inclusions: HashMap<&PathBuf, usize> = HashMap::new();
+    for (_, entries) in &playlists {
+        for entry in entries {
+            *inclusions.entry(entry).or_default() += 1;
+        }
+    }
+    let mut included: Vec<(&PathBuf, usize)> = inclusions.into_iter().filter(|(_, count)| *count > 1).collect();
+    included.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+    println!("\n{}", "Tracks in the most playlists:".bold());
+    if included.is_empty() {
+        println!("{}", "No track is in more than one playlist.".green());
+        return;
+    }
+    let rows: Vec<Vec<String>> = included
+        .iter()
+        .take(top)
+        .map(|(path, count)| {
+            let path = path.to_string_lossy();
+            // Entries that aren't indexed (or no longer exist) are shown by path
+            let track = conn.query_row(
+                "SELECT artist, title FROM tracks WHERE path = ?1",
+                [&*path],
+                |row| Ok(format!("{} - {}", row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            ).unwrap_or_else(|_| path.to_string());
+            vec![count.to_string(), track]
+        })
+        .collect();
+    print_table(&["Playlists", "Track"], &rows);
+}
+
+fn create_autofix_log_table(conn: &rusqlite::Connection) {
+    create_table(conn,
+        "CREATE TABLE IF NOT EXISTS playlist_autofix_log (
+            id INTEGER PRIMARY KEY,
+            playlist TEXT NOT NULL,
+            old_path TEXT NOT NULL,
+            new_path TEXT NOT NULL,
+            score REAL NOT NULL,
+            fixed_at INTEGER NOT NULL
+        )",
+    ).expect("Failed to create playlist_autofix_log table");
+}
+
+fn review_autofixes(db_path: &str, revert: &[i64]) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+    create_autofix_log_table(&conn);
+
+    for id in revert {
+        let entry: Option<(String, String, String)> = conn.query_row(
+            "SELECT playlist, old_path, new_path FROM playlist_autofix_log WHERE id = ?1",
+            [id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        ).ok();
+        let Some((playlist, old_path, new_path)) = entry else {
+            eprintln!("{}", format!("No logged autofix with id {}", id).red());
+            continue;
+        };
+        match update_playlist_line(&playlist, &new_path, &old_path) {
+            Ok(()) => {
+                conn.execute("DELETE FROM playlist_autofix_log WHERE id = ?1", [id])
+                    .expect("Failed to delete autofix log entry");
+                println!("Reverted #{} in {}", id, playlist);
+            }
+            Err(e) => eprintln!("{}", format!("Failed to revert #{}: {}", id, e).red()),
+        }
+    }
+    if !revert.is_empty() {
+        return;
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT id, datetime(fixed_at, 'unixepoch', 'localtime'), playlist, old_path, new_path, score \
+         FROM playlist_autofix_log ORDER BY fixed_at DESC, id DESC"
+    ).expect("Failed to prepare autofix log statement");
+    let rows: Vec<Vec<String>> = stmt
+        .query_map([], |row| {
+            Ok(vec![
+                format!("#{}", row.get::<_, i64>(0)?),
+                row.get::<_, String>(1)?,
+                Path::new(&row.get::<_, String>(2)?).file_name().unwrap_or_default().to_string_lossy().to_string(),
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+                format!("{:.3}", row.get::<_, f64>(5)?),
+            ])
+        })
+        .expect("Failed to execute autofix log query")
+        .filter_map(Result::ok)
+        .collect();
+
+    if rows.is_empty() {
+        println!("{}", "No auto-replaced playlist entries logged.".green());
+        return;
+    }
+    print_table(&["Id", "When", "Playlist", "Original", "Replacement", "Score"], &rows);
+}
+
+fn config_dir() -> PathBuf {
+    PathBuf::from(expand_tilde("~/.config/apollo-music"))
+}
+
+// Audit trail of every change made to library files, one JSON object per line; the log is
+// rotated to mutations.jsonl.1 once it outgrows MUTATION_LOG_MAX_BYTES
+const MUTATION_LOG_MAX_BYTES: u64 = 5 * 1024 * 1024;
+
+#[derive(Serialize)]
+struct MutationRecord<'a> {
+    timestamp: u64, // unix seconds
+    action: &'a str,
+    paths: Vec<String>,
+    result: String, // "ok" or the error
+}
+
+// Best effort: a failure to log never stops the operation being logged
+fn log_mutation<T, E: std::fmt::Display>(action: &str, paths: &[&Path], result: &Result<T, E>) {
+    let log_path = config_dir().join("mutations.jsonl");
+    if fs::metadata(&log_path).is_ok_and(|m| m.len() > MUTATION_LOG_MAX_BYTES) {
+        fs::rename(&log_path, config_dir().join("mutations.jsonl.1")).ok();
+    }
+    let record = MutationRecord {
+        timestamp: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        action,
+        paths: paths.iter().map(|p| p.to_string_lossy().to_string()).collect(),
+        result: match result {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        },
+    };
+    let Ok(mut line) = serde_json::to_string(&record) else {
+        return;
+    };
+    line.push('\n');
+    fs::create_dir_all(config_dir()).ok();
+    if let Ok(mut file) = fs::OpenOptions::new().create(true).append(true).open(&log_path) {
+        file.write_all(line.as_bytes()).ok();
+    }
+}
+
+// Advisory lock held while a mutating command runs. The OS releases it when the file is
+// closed, so it is freed on normal exit, panic and abort alike.
+fn acquire_instance_lock() -> fs::File {
+    let lock_path = config_dir().join("apollo.lock");
+    fs::create_dir_all(config_dir()).ok();
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .expect("Failed to open lock file");
+    match file.try_lock() {
+        Ok(()) => file,
+        Err(fs::TryLockError::WouldBlock) => {
+            exit_with_error(EXIT_ERROR, "another apollo instance is running");
+        }
+        Err(fs::TryLockError::Error(e)) => panic!("Failed to lock {}: {}", lock_path.display(), e),
+    }
+}
+
+// Copy the database next to itself as <db>.bak-<unix time> before a destructive run
+fn backup_database(db_path: &str) {
+    if !Path::new(db_path).exists() {
+        return;
+    }
+    let stamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let backup_path = format!("{}.bak-{}", db_path, stamp);
+    match fs::copy(db_path, &backup_path) {
+        Ok(_) => println!("Backed up database to {}", backup_path),
+        Err(e) => exit_with_error(EXIT_DATABASE, &format!("failed to back up {} to {}: {}", db_path, backup_path, e)),
+    }
+}
+
+fn config_file() -> PathBuf {
+    config_dir().join("config.toml")
+}
+
+fn load_settings() -> Settings {
+    let config_path = config_file().to_string_lossy().to_string();
+    app_config::Config::builder()
+        .add_source(app_config::File::with_name(&config_path))
+        .add_source(app_config::Environment::with_prefix("APP"))
+        .build()
+        .and_then(|config| config.try_deserialize())
+        .unwrap_or_else(|e| exit_with_error(EXIT_CONFIG, &format!("failed to load {}: {}", config_path, e)))
+}
+
+// Quote a value as a TOML basic string
+fn toml_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+const FILE_PATTERN_PRESETS: &[&str] = &[
+    "{albumartist}/{album}/{title}.{ext}",
+    "{artist}/{album}/{title}.{ext}",
+    "{albumartist}/{title}.{ext}",
+];
+
+// Prompt for the basic settings and write config.toml. Returns whether to run the first
+// index now, or None if the wizard was cancelled.
+fn run_setup() -> Option<bool> {
+    use inquire::validator::Validation;
+
+    let config_path = config_file();
+    println!("{}", "Apollo setup".bold());
+    if config_path.exists()
+        && !inquire::Confirm::new(&format!("{} already exists. Overwrite it?", config_path.display()))
+            .with_default(false)
+            .prompt()
+            .ok()?
+    {
+        return None;
+    }
+
+    let music_directory = inquire::Text::new("Music directory:")
+        .with_default("~/Music")
+        .with_validator(|input: &str| {
+            Ok(if Path::new(&expand_tilde(input.trim())).is_dir() {
+                Validation::Valid
+            } else {
+                Validation::Invalid("Directory does not exist".into())
+            })
+        })
+        .prompt()
+        .ok()?;
+
+    let default_db = config_dir().join("apollo.db").to_string_lossy().to_string();
+    let database_name = inquire::Text::new("Database location:")
+        .with_default(&default_db)
+        .with_validator(|input: &str| {
+            Ok(match Path::new(&expand_tilde(input.trim())).file_name() {
+                Some(_) => Validation::Valid,
+                None => Validation::Invalid("Enter a path to a database file".into()),
+            })
+        })
+        .prompt()
+        .ok()?;
+
+    const NO_PATTERN: &str = "Don't organize files";
+    const CUSTOM_PATTERN: &str = "Custom...";
+    let mut options = vec![NO_PATTERN];
+    options.extend(FILE_PATTERN_PRESETS);
+    options.push(CUSTOM_PATTERN);
+    let file_pattern = match inquire::Select::new("File pattern for Index --organize:", options).prompt().ok()? {
+        NO_PATTERN => None,
+        CUSTOM_PATTERN => Some(
+            inquire::Text::new("File pattern:")
+                .with_help_message("Placeholders: {artist} {albumartist} {album} {title} {ext}")
+                .with_validator(|input: &str| {
+                    Ok(if input.contains("{title}") && input.contains("{ext}") {
+                        Validation::Valid
+                    } else {
+                        Validation::Invalid("The pattern must contain {title} and {ext}".into())
+                    })
+                })
+                .prompt()
+                .ok()?,
+        ),
+        preset => Some(preset.to_string()),
+    };
+
+    let mut config = String::from("[files]\n");
+    config.push_str(&format!("music_directory = {}\n", toml_string(music_directory.trim())));
+    config.push_str(&format!("database_name = {}\n", toml_string(database_name.trim())));
+    if let Some(pattern) = file_pattern {
+        config.push_str(&format!("file_pattern = {}\n", toml_string(&pattern)));
+    }
+    fs::create_dir_all(config_dir()).expect("Failed to create config directory");
+    fs::write(&config_path, config).expect("Failed to write config file");
+    println!("{}", format!("Wrote {}", config_path.display()).green());
+
+    inquire::Confirm::new("Index your library now?")
+        .with_default(true)
+        .prompt()
+        .ok()
+}
+
+fn create_playlists_table(conn: &rusqlite::Connection) {
+    create_table(conn,
+        "CREATE TABLE IF NOT EXISTS playlists (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            path TEXT NOT NULL UNIQUE
+        )",
+    ).expect("Failed to create playlists table");
+}
+
+fn index_playlists(music_dir: &str, db_path: &str, repair: PlaylistRepair<'_>) {
+    // loads and indexes .m3u or .m3u8 playlists in the given directory and stores them in a database
+    // create or open the database
+    let db_path = expand_tilde(db_path);
+    let mut conn = open_db(&db_path);
+    create_playlists_table(&conn);
+    create_autofix_log_table(&conn);
+
+    let tx = conn.transaction().expect("Failed to start transaction");
+
+    // Remove playlists from the database that no longer exist on the filesystem
+    let mut stmt = tx.prepare("SELECT path FROM playlists").expect("Failed to prepare select statement");
+    let mut rows = stmt.query([]).expect("Failed to query playlists");
+
+    let mut to_remove = Vec::new();
+    while let Some(row) = rows.next().expect("Failed to fetch row") {
+        let path: String = row.get(0).expect("Failed to get path");
+        if !std::path::Path::new(&path).exists() {
+            to_remove.push(path);
+        }
+    }
+    drop(rows);
+    drop(stmt);
+
+    for path in to_remove {
+        println!("Removing missing playlist from database: {}", path);
+        tx.execute("DELETE FROM playlists WHERE path = ?1", [&path]).ok();
+    }
+
+    println!("Indexing playlists in directory: {}", music_dir);
+
+    // Load all tracks once to avoid repeated database queries for missing file suggestions
+    // This significantly improves performance when dealing with playlists that have missing files
+    let all_tracks = load_track_titles(&db_path);
+
+    for entry in walkdir::WalkDir::new(music_dir)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|e| e.file_type().is_file())
+    {
+        let path = entry.path();
+        if PlaylistFormat::from_path(path).is_some() {
+            let path_str = path.to_string_lossy();
+            let name = path.file_stem().unwrap_or_default().to_string_lossy();
+            tx.execute(
+                "INSERT OR IGNORE INTO playlists (name, path) VALUES (?1, ?2)",
+                [&name as &dyn rusqlite::ToSql, &path_str]
+            ).ok();
+
+            repair_playlist(&tx, path, &name, &all_tracks, repair);
+        }
+    }
+    tx.commit().expect("Failed to commit transaction");
+}
+
+// How a search query is matched against a column. A plain query without wildcards also
+// carries an FTS5 expression that matches its words as prefixes.
+enum SearchPattern {
+    Like { pattern: String, fts: Option<String> },
+    Regex(regex::Regex),
+}
+
+impl SearchPattern {
+    fn new(query: &str, regex: bool) -> Result<Self, regex::Error> {
+        if regex {
+            return Ok(SearchPattern::Regex(regex::Regex::new(query)?));
+        }
+        let fts = (!query.contains('*') && !query.contains('?')).then(|| fts_query(query)).flatten();
+        Ok(SearchPattern::Like { pattern: like_pattern(query), fts })
+    }
+}
+
+// Each word of `query` as a quoted prefix term, so "beat sun" finds "The Beatles - Here Comes the Sun"
+fn fts_query(query: &str) -> Option<String> {
+    let terms: Vec<String> = query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{}\"*", word))
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+// Translate `*`/`?` wildcards into a LIKE pattern; without wildcards the query matches as a
+// substring. Literal `%`, `_` and `\` are escaped so they only ever match themselves.
+fn like_pattern(query: &str) -> String {
+    let has_wildcards = query.contains('*') || query.contains('?');
+    let mut pattern = String::with_capacity(query.len() + 2);
+    for c in query.chars() {
+        match c {
+            '%' | '_' | '\\' => {
+                pattern.push('\\');
+                pattern.push(c);
+            }
+            '*' => pattern.push('%'),
+            '?' => pattern.push('_'),
+            _ => pattern.push(c),
+        }
+    }
+    if has_wildcards { pattern } else { format!("%{}%", pattern) }
+}
+
+// Select `columns` ordered by them, keeping rows whose `columns[field]` matches the pattern.
+// Plain queries match as a LIKE substring, plus, when the full-text index exists, every row
+// whose words start with the query's words, so "beat sun" still finds "Beatles ... Sun".
+// Regex mode scans every row and filters in Rust, since SQLite has no built-in REGEXP.
+fn search_db(db_path: &str, columns: [&str; 3], field: usize, pattern: &SearchPattern) -> Vec<(String, String, String)> {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+
+    let column_list = columns.join(", ");
+    let fts = match pattern {
+        SearchPattern::Like { fts: Some(fts), .. } if has_tracks_fts(&conn) => Some(format!("{} : ({})", columns[field], fts)),
+        _ => None,
+    };
+    let statement = match (pattern, &fts) {
+        (SearchPattern::Like { .. }, Some(_)) => format!(
+            "SELECT {0} FROM tracks WHERE {1} LIKE ?1 ESCAPE '\\' \
+             OR id IN (SELECT rowid FROM tracks_fts WHERE tracks_fts MATCH ?2) ORDER BY {0}",
+            column_list, columns[field]
+        ),
+        (SearchPattern::Like { .. }, None) => format!(
+            "SELECT {0} FROM tracks WHERE {1} LIKE ?1 ESCAPE '\\' ORDER BY {0}",
+            column_list, columns[field]
+        ),
+        (SearchPattern::Regex(_), _) => format!("SELECT {0} FROM tracks ORDER BY {0}", column_list),
+    };
+    let mut stmt = conn.prepare(&statement).expect("Failed to prepare statement");
+
+    let mut rows = match (pattern, fts) {
+        (SearchPattern::Like { pattern, .. }, Some(fts)) => stmt.query([pattern, &fts]),
+        (SearchPattern::Like { pattern, .. }, None) => stmt.query([pattern]),
+        (SearchPattern::Regex(_), _) => stmt.query([]),
+    }.expect("Failed to execute query");
+
+    let mut results = Vec::new();
+    while let Some(row) = rows.next().expect("Failed to fetch row") {
+        let values: [String; 3] = [
+            row.get(0).unwrap_or_default(),
+            row.get(1).unwrap_or_default(),
+            row.get(2).unwrap_or_default(),
+        ];
+        if let SearchPattern::Regex(re) = pattern
+            && !re.is_match(&values[field])
+        {
+            continue;
+        }
+        let [first, second, third] = values;
+        results.push((first, second, third));
+    }
+    results
+}
+
+// A matching track for --output-format; albums and artists are left to the text layout
+#[derive(Serialize)]
+struct SearchHit {
+    title: String,
+    album: String,
+    artist: String,
+}
+
+impl Renderable for SearchHit {
+    fn headers() -> &'static [&'static str] {
+        &["Title", "Album", "Artist"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![self.title.clone(), self.album.clone(), self.artist.clone()]
+    }
+}
+
+fn search_tracks(db_path: &str, query: &str, regex: bool) {
+    let db_path = expand_tilde(db_path);
+    let pattern = match SearchPattern::new(query, regex) {
+        Ok(pattern) => pattern,
+        Err(e) => exit_with_error(EXIT_ERROR, &format!("invalid regex: {}", e)),
+    };
+
+    let format = output_format();
+    if format != OutputFormat::Text {
+        let hits: Vec<SearchHit> = search_db(&db_path, ["artist", "album", "title"], 2, &pattern)
+            .into_iter()
+            .map(|(artist, album, title)| SearchHit { title, album, artist })
+            .collect();
+        render(&hits, format);
+        return;
+    }
+
+    // Display Tracks (flat list for search)
+    println!("{} (Track - Album - Artist)", "Tracks".bold().underline());
+    let results = search_db(&db_path, ["artist", "album", "title"], 2, &pattern);
+    if results.is_empty() {
+        println!("{}", "No tracks found.".yellow());
+    } else {
+        for (artist, album, title) in results {
+            println!("{} - {} - {}", title, album, artist);
+        }
+    }
+    println!();
+
+    println!("{}", "Albums".bold().underline());
+    let results = search_db(&db_path, ["album", "artist", "title"], 0, &pattern);
+    if results.is_empty() {
+        println!("{}", "No albums found.".yellow());
+    } else {
+        let unique_albums = results.iter().map(|(album, _, _)| album).collect::<std::collections::HashSet<_>>();
+        for album in unique_albums {
+            println!("{}", album);
+        }
+    }
+    println!();
+
+    println!("{}", "Artists".bold().underline());
+    let results = search_db(&db_path, ["album", "artist", "title"], 1, &pattern);
+    if results.is_empty() {
+        println!("{}", "No artists found.".yellow());
+    } else {
+        let unique_artists = results.iter().map(|(_, artist, _)| artist).collect::<std::collections::HashSet<_>>();
+        for artist in unique_artists {
+            println!("{}", artist);
+        }
+    }
+
+}
+
+// Track gain beyond this many dB either way is flagged as abnormally loud or quiet
+const GAIN_OUTLIER_DB: f64 = 12.0;
+
+fn format_gain(gain: Option<f64>) -> String {
+    match gain {
+        None => "unanalyzed".to_string(),
+        Some(db) if db.abs() > GAIN_OUTLIER_DB => {
+            let label = if db < 0.0 { "loud" } else { "quiet" };
+            format!("{:+.2} dB ({})", db, label)
+        }
+        Some(db) => format!("{:+.2} dB", db),
+    }
+}
+
+// Artist, album, title, ReplayGain and note of a listed track
+type TrackRow = (String, String, String, Option<f64>, Option<String>);
+
+// A listed track for --output-format; gain and notes are always included
+#[derive(Serialize)]
+struct ListedTrack {
+    artist: String,
+    album: String,
+    title: String,
+    gain: Option<f64>,
+    notes: Option<String>,
+}
+
+impl Renderable for ListedTrack {
+    fn headers() -> &'static [&'static str] {
+        &["Artist", "Album", "Title", "Gain", "Notes"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.artist.clone(),
+            self.album.clone(),
+            self.title.clone(),
+            self.gain.map(|db| format!("{:+.2}", db)).unwrap_or_default(),
+            self.notes.clone().unwrap_or_default(),
+        ]
+    }
+}
+
+fn print_track_table(results: Vec<TrackRow>, show_gain: bool, show_notes: bool, labels: &UnknownLabels) {
+    let format = output_format();
+    if format != OutputFormat::Text {
+        let tracks: Vec<ListedTrack> = results
+            .into_iter()
+            .map(|(artist, album, title, gain, notes)| ListedTrack {
+                artist: labels.artist_or(&artist).to_string(),
+                album: labels.album_or(&album).to_string(),
+                title: labels.title_or(&title).to_string(),
+                gain,
+                notes,
+            })
+            .collect();
+        render(&tracks, format);
+        return;
+    }
+    if results.is_empty() {
+        println!("{}", "No tracks found.".yellow());
+        return;
+    }
+    let mut headers = vec!["Artist", "Album", "Title"];
+    if show_gain {
+        headers.push("Gain");
+    }
+    if show_notes {
+        headers.push("Notes");
+    }
+    let rows: Vec<Vec<String>> = results
+        .into_iter()
+        .map(|(artist, album, title, gain, notes)| {
+            let mut row = vec![
+                labels.artist_or(&artist).to_string(),
+                labels.album_or(&album).to_string(),
+                labels.title_or(&title).to_string(),
+            ];
+            if show_gain {
+                row.push(format_gain(gain));
+            }
+            if show_notes {
+                row.push(notes.unwrap_or_default());
+            }
+            row
+        })
+        .collect();
+    print_table(&headers, &rows);
+}
+
+// Tracks matching an optional album/artist/title substring and genre, at or above min_rating
+fn query_tracks(conn: &rusqlite::Connection, query: Option<&str>, genre: Option<&str>, min_rating: u8) -> Vec<TrackRow> {
+    match (query, genre) {
+        // No filters — list everything
+        (None, None) => {
+            let mut stmt = conn.prepare(
+                "SELECT artist, album, title, replaygain_track_gain, notes FROM tracks \
+                 WHERE COALESCE(rating, 0) >= ?1 \
+                 ORDER BY artist, album, title"
+            ).expect("Failed to prepare statement");
+            let mut rows = stmt.query([min_rating]).expect("Failed to execute query");
+            let mut out = Vec::new();
+            while let Some(row) = rows.next().expect("Failed to fetch row") {
+                out.push((
+                    row.get(0).unwrap_or_default(),
+                    row.get(1).unwrap_or_default(),
+                    row.get(2).unwrap_or_default(),
+                    row.get(3).unwrap_or_default(),
+                    row.get(4).unwrap_or_default(),
+                ));
+            }
+            out
+        }
+
+        // Genre only
+        (None, Some(g)) => {
+            let pattern = format!("%{}%", g);
+            let mut stmt = conn.prepare(
+                "SELECT artist, album, title, replaygain_track_gain, notes FROM tracks \
+                 WHERE genre LIKE ?1 AND COALESCE(rating, 0) >= ?2 \
+                 ORDER BY artist, album, title"
+            ).expect("Failed to prepare statement");
+            let mut rows = stmt.query(rusqlite::params![pattern, min_rating]).expect("Failed to execute query");
+            let mut out = Vec::new();
+            while let Some(row) = rows.next().expect("Failed to fetch row") {
+                out.push((
+                    row.get(0).unwrap_or_default(),
+                    row.get(1).unwrap_or_default(),
+                    row.get(2).unwrap_or_default(),
+                    row.get(3).unwrap_or_default(),
+                    row.get(4).unwrap_or_default(),
+                ));
+            }
+            out
+        }
+
+        // Query only
+        (Some(q), None) => {
+            let pattern = format!("%{}%", q);
+            let mut stmt = conn.prepare(
+                "SELECT artist, album, title, replaygain_track_gain, notes FROM tracks \
+                 WHERE (album LIKE ?1 OR artist LIKE ?1 OR title LIKE ?1) AND COALESCE(rating, 0) >= ?2 \
+                 ORDER BY artist, album, title"
+            ).expect("Failed to prepare statement");
+            let mut rows = stmt.query(rusqlite::params![pattern, min_rating]).expect("Failed to execute query");
+            let mut out = Vec::new();
+            while let Some(row) = rows.next().expect("Failed to fetch row") {
+                out.push((
+                    row.get(0).unwrap_or_default(),
+                    row.get(1).unwrap_or_default(),
+                    row.get(2).unwrap_or_default(),
+                    row.get(3).unwrap_or_default(),
+                    row.get(4).unwrap_or_default(),
+                ));
+            }
+            out
+        }
+
+        // Both query and genre
+        (Some(q), Some(g)) => {
+            let q_pattern = format!("%{}%", q);
+            let g_pattern = format!("%{}%", g);
+            let mut stmt = conn.prepare(
+                "SELECT artist, album, title, replaygain_track_gain, notes FROM tracks \
+                 WHERE genre LIKE ?1 \
+                 AND (album LIKE ?2 OR artist LIKE ?2 OR title LIKE ?2) AND COALESCE(rating, 0) >= ?3 \
+                 ORDER BY artist, album, title"
+            ).expect("Failed to prepare statement");
+            let mut rows = stmt.query(rusqlite::params![g_pattern, q_pattern, min_rating]).expect("Failed to execute query");
+            let mut out = Vec::new();
+            while let Some(row) = rows.next().expect("Failed to fetch row") {
+                out.push((
+                    row.get(0).unwrap_or_default(),
+                    row.get(1).unwrap_or_default(),
+                    row.get(2).unwrap_or_default(),
+                    row.get(3).unwrap_or_default(),
+                    row.get(4).unwrap_or_default(),
+                ));
+            }
+            out
+        }
+    }
+}
+
+fn list_tracks(
+    db_path: &str,
+    query: Option<String>,
+    genre: Option<String>,
+    show_gain: bool,
+    show_notes: bool,
+    min_rating: u8,
+    labels: &UnknownLabels,
+) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+
+    // Print genre header if filtering
+    if let Some(ref g) = genre {
+        println!("{} {}", "Genre:".bold(), g.cyan());
+    }
+
+    let results = query_tracks(&conn, query.as_deref(), genre.as_deref(), min_rating);
+    print_track_table(results, show_gain, show_notes, labels);
+}
+
+// Artist, album artist, album, title, year and track number of an Ls --group-albums row
+type AlbumTrackRow = (String, String, String, String, i32, Option<u32>);
+
+// Ls --group-albums: the same filters as list_tracks, one block per album. Tracks with a blank
+// album are collected under a single "Singles / Unknown" block at the end.
+fn list_albums(db_path: &str, query: Option<&str>, genre: Option<&str>, min_rating: u8, labels: &UnknownLabels) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+
+    let mut stmt = conn.prepare(
+        "SELECT COALESCE(artist, ''), COALESCE(albumartist, ''), COALESCE(album, ''), COALESCE(title, ''), \
+         COALESCE(year, 0), track_number FROM tracks \
+         WHERE (?1 IS NULL OR genre LIKE ?1) \
+         AND (?2 IS NULL OR album LIKE ?2 OR artist LIKE ?2 OR title LIKE ?2) AND COALESCE(rating, 0) >= ?3 \
+         ORDER BY TRIM(COALESCE(album, '')) = '', COALESCE(NULLIF(albumartist, ''), artist), album, year, \
+         COALESCE(disc_number, 1), COALESCE(track_number, 0), title"
+    ).expect("Failed to prepare statement");
+    let like = |value: Option<&str>| value.map(|v| format!("%{}%", v));
+    let tracks: Vec<AlbumTrackRow> = stmt
+        .query_map(rusqlite::params![like(genre), like(query), min_rating], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?))
+        })
+        .expect("Failed to execute query")
+        .filter_map(Result::ok)
+        .collect();
+    if tracks.is_empty() {
+        println!("{}", "No tracks found.".yellow());
+        return;
+    }
+
+    // Consecutive rows with the same album artist, album and year form one album
+    let mut albums: Vec<(String, Vec<&AlbumTrackRow>)> = Vec::new();
+    let mut current_key = None;
+    for track in &tracks {
+        let (artist, albumartist, album, _, year, _) = track;
+        let owner = if albumartist.trim().is_empty() { artist } else { albumartist };
+        let key = (!album.trim().is_empty()).then(|| (owner.to_lowercase(), album.to_lowercase(), *year));
+        if albums.is_empty() || key != current_key {
+            let header = match &key {
+                None => "Singles / Unknown".to_string(),
+                Some(_) if *year > 0 => format!("{} — {} ({})", labels.artist_or(owner), album, year),
+                Some(_) => format!("{} — {}", labels.artist_or(owner), album),
+            };
+            albums.push((header, Vec::new()));
+            current_key = key;
+        }
+        if let Some((_, album_tracks)) = albums.last_mut() {
+            album_tracks.push(track);
+        }
+    }
+
+    for (i, (header, album_tracks)) in albums.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        println!("{} {}", header.bold(), format!("[{} track(s)]", album_tracks.len()).cyan());
+        for (artist, albumartist, album, title, _, track_number) in album_tracks {
+            let number = track_number.map(|n| format!("{:>2}.", n)).unwrap_or_else(|| " -.".to_string());
+            // Compilations and singles name each track's own artist
+            if album.trim().is_empty() || (!albumartist.trim().is_empty() && !artist.eq_ignore_ascii_case(albumartist)) {
+                println!("  {} {} — {}", number, labels.title_or(title), labels.artist_or(artist));
+            } else {
+                println!("  {} {}", number, labels.title_or(title));
+            }
+        }
+    }
+}
+
+// Parse "header=field,field,..." into (header, field) pairs
+fn parse_export_columns(spec: &str) -> Result<Vec<(String, &'static str)>, String> {
+    let columns = spec
+        .split(',')
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .map(|column| match column.split_once('=') {
+            Some((header, field)) => Ok((header.trim().to_string(), validate_column(field)?)),
+            None => Ok((column.to_string(), validate_column(column)?)),
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    if columns.is_empty() {
+        return Err("--columns must list at least one field".to_string());
+    }
+    Ok(columns)
+}
+
+fn export_tracks(db_path: &str, bom: bool, delimiter: char, columns: Option<&str>) {
+    if delimiter == '"' || delimiter == '\n' || delimiter == '\r' {
+        exit_with_error(EXIT_ERROR, "the delimiter cannot be a quote or newline");
+    }
+    let columns = match columns {
+        Some(spec) => parse_export_columns(spec).unwrap_or_else(|e| exit_with_error(EXIT_ERROR, &e)),
+        None => vec![
+            ("Artist".to_string(), "artist"),
+            ("Album".to_string(), "album"),
+            ("Title".to_string(), "title"),
+        ],
+    };
+
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+
+    let fields: Vec<&str> = columns.iter().map(|(_, field)| *field).collect();
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM tracks", fields.join(", ")))
+        .expect("Failed to prepare statement");
+    let mut rows = stmt.query([]).expect("Failed to execute query");
+
+    // Write CSV to a file in the same directory as the database, named "tracks_export.csv"
+    let db_folder = std::path::Path::new(&db_path).parent().unwrap_or_else(|| std::path::Path::new("."));
+    let csv_path = db_folder.join("tracks_export.csv");
+    let mut file = std::fs::File::create(&csv_path).expect("Failed to create CSV file");
+
+    if bom {
+        file.write_all("\u{feff}".as_bytes()).expect("Failed to write CSV byte order mark");
+    }
+
+    // Write CSV header
+    let headers: Vec<&str> = columns.iter().map(|(header, _)| header.as_str()).collect();
+    write_csv_row(&mut file, &headers, delimiter).expect("Failed to write CSV header");
+
+    while let Some(row) = rows.next().expect("Failed to fetch row") {
+        let values: Vec<String> = (0..columns.len())
+            .map(|i| match row.get_ref(i) {
+                Ok(rusqlite::types::ValueRef::Integer(n)) => n.to_string(),
+                Ok(rusqlite::types::ValueRef::Real(x)) => x.to_string(),
+                Ok(rusqlite::types::ValueRef::Text(t)) => String::from_utf8_lossy(t).to_string(),
+                _ => String::new(),
+            })
+            .collect();
+        let values: Vec<&str> = values.iter().map(String::as_str).collect();
+        write_csv_row(&mut file, &values, delimiter).expect("Failed to write CSV record");
+    }
+
+    println!("Exported tracks to {}", csv_path.display());
+}
+
+// Bumped whenever the archive layout changes; Import refuses archives newer than it knows
+const ARCHIVE_SCHEMA_VERSION: u32 = 1;
+const ARCHIVE_FORMAT: &str = "apollo-archive";
+
+#[derive(Serialize, Deserialize)]
+struct ArchiveManifest {
+    format: String,
+    schema_version: u32,
+    created_at: u64,
+    apollo_version: String,
+}
+
+// Favorites are stored by path since track ids differ between databases
+#[derive(Serialize, Deserialize)]
+struct ArchivedFavorite {
+    path: String,
+    added_at: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ArchivedPlaylist {
+    name: String,
+    path: String,
+    content: String,
+}
+
+// Everything Export --archive writes: the catalog without the audio. A single JSON document
+// is portable enough and needs nothing beyond serde_json to read back.
+#[derive(Serialize, Deserialize)]
+struct LibraryArchive {
+    manifest: ArchiveManifest,
+    tracks: Vec<serde_json::Map<String, serde_json::Value>>,
+    favorites: Vec<ArchivedFavorite>,
+    playlists: Vec<ArchivedPlaylist>,
+}
+
+fn sql_to_json(value: rusqlite::types::ValueRef<'_>) -> serde_json::Value {
+    use rusqlite::types::ValueRef;
+    match value {
+        ValueRef::Integer(n) => n.into(),
+        ValueRef::Real(x) => serde_json::Number::from_f64(x).map_or(serde_json::Value::Null, Into::into),
+        ValueRef::Text(t) => String::from_utf8_lossy(t).into(),
+        ValueRef::Null | ValueRef::Blob(_) => serde_json::Value::Null,
+    }
+}
+
+fn json_to_sql(value: &serde_json::Value) -> rusqlite::types::Value {
+    use rusqlite::types::Value;
+    match value {
+        serde_json::Value::Bool(b) => Value::Integer(*b as i64),
+        serde_json::Value::Number(n) => n.as_i64().map(Value::Integer).or_else(|| n.as_f64().map(Value::Real)).unwrap_or(Value::Null),
+        serde_json::Value::String(s) => Value::Text(s.clone()),
+        _ => Value::Null,
+    }
+}
+
+fn export_archive(db_path: &str, archive_path: &Path) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+    create_favorites_table(&conn);
+    create_playlists_table(&conn);
+
+    let mut stmt = conn.prepare("SELECT * FROM tracks ORDER BY path").expect("Failed to prepare statement");
+    let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+    let tracks: Vec<serde_json::Map<String, serde_json::Value>> = stmt
+        .query_map([], |row| {
+            columns
+                .iter()
+                .enumerate()
+                .filter(|(_, column)| *column != "id")
+                .map(|(i, column)| Ok((column.clone(), sql_to_json(row.get_ref(i)?))))
+                .collect()
+        })
+        .expect("Failed to execute query")
+        .filter_map(Result::ok)
+        .collect();
+
+    let mut stmt = conn
+        .prepare("SELECT t.path, f.added_at FROM favorites f JOIN tracks t ON t.id = f.track_id ORDER BY f.added_at")
+        .expect("Failed to prepare statement");
+    let favorites: Vec<ArchivedFavorite> = stmt
+        .query_map([], |row| Ok(ArchivedFavorite { path: row.get(0)?, added_at: row.get(1)? }))
+        .expect("Failed to execute query")
+        .filter_map(Result::ok)
+        .collect();
+
+    let mut stmt = conn.prepare("SELECT name, path FROM playlists ORDER BY path").expect("Failed to prepare statement");
+    let indexed: Vec<(String, String)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .expect("Failed to execute query")
+        .filter_map(Result::ok)
+        .collect();
+    let mut playlists = Vec::new();
+    for (name, path) in indexed {
+        match fs::read(&path) {
+            Ok(bytes) => playlists.push(ArchivedPlaylist { name, path, content: String::from_utf8_lossy(&bytes).to_string() }),
+            Err(e) => eprintln!("{}", format!("Skipping playlist {}: {}", path, e).yellow()),
+        }
+    }
+
+    let archive = LibraryArchive {
+        manifest: ArchiveManifest {
+            format: ARCHIVE_FORMAT.to_string(),
+            schema_version: ARCHIVE_SCHEMA_VERSION,
+            created_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            apollo_version: env!("CARGO_PKG_VERSION").to_string(),
+        },
+        tracks,
+        favorites,
+        playlists,
+    };
+    let json = serde_json::to_vec(&archive).expect("Failed to serialize archive");
+    fs::write(archive_path, json).unwrap_or_else(|e| {
+        exit_with_error(EXIT_ERROR, &format!("failed to write {}: {}", archive_path.display(), e))
+    });
+    println!(
+        "Archived {} track(s), {} favorite(s) and {} playlist(s) to {}",
+        archive.tracks.len(),
+        archive.favorites.len(),
+        archive.playlists.len(),
+        archive_path.display()
+    );
+}
+
+// Import --relocate OLD=NEW
+fn parse_relocation(spec: &str) -> Result<(String, String), String> {
+    match spec.split_once('=') {
+        Some((old, new)) if !old.is_empty() && !new.is_empty() => Ok((old.to_string(), new.to_string())),
+        _ => Err(format!("expected OLD=NEW, got '{}'", spec)),
+    }
+}
+
+// `path` moved under the first --relocate prefix it starts with, or None when none matches.
+// Prefixes match whole path components, so /music doesn't match /music2.
+fn relocate_path(path: &str, relocations: &[(String, String)]) -> Option<String> {
+    relocations.iter().find_map(|(old, new)| {
+        let rest = Path::new(path).strip_prefix(old).ok()?;
+        Some(Path::new(new).join(rest).to_string_lossy().to_string())
+    })
+}
+
+// Import --on-duplicate: how an archived track that duplicates a library track is handled
+#[derive(Clone, Copy, PartialEq)]
+enum OnDuplicate {
+    Skip,
+    Add,     // insert it next to the library track
+    Replace, // overwrite the library track's row, keeping its id, favorites and notes
+}
+
+impl OnDuplicate {
+    fn from_arg(value: &str) -> Self {
+        match value {
+            "add" => OnDuplicate::Add,
+            "replace" => OnDuplicate::Replace,
+            _ => OnDuplicate::Skip,
+        }
+    }
+}
+
+// Artist or title as compared by Import --on-duplicate: lowercased with whitespace collapsed
+fn normalized_tag(value: &str) -> String {
+    value.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+fn import_archive(db_path: &str, archive_path: &Path, force: bool, relocations: &[(String, String)], on_duplicate: OnDuplicate) {
+    let data = fs::read(archive_path).unwrap_or_else(|e| {
+        exit_with_error(EXIT_ERROR, &format!("failed to read {}: {}", archive_path.display(), e))
+    });
+    let archive: LibraryArchive = serde_json::from_slice(&data).unwrap_or_else(|e| {
+        exit_with_error(EXIT_ERROR, &format!("{} is not an apollo archive: {}", archive_path.display(), e))
+    });
+    if archive.manifest.format != ARCHIVE_FORMAT {
+        exit_with_error(EXIT_ERROR, &format!("{} is not an apollo archive", archive_path.display()));
+    }
+    if archive.manifest.schema_version > ARCHIVE_SCHEMA_VERSION {
+        exit_with_error(EXIT_ERROR, &format!(
+            "{} uses archive schema {}, but this version of apollo only reads up to {}",
+            archive_path.display(), archive.manifest.schema_version, ARCHIVE_SCHEMA_VERSION
+        ));
+    }
+
+    let db_path = expand_tilde(db_path);
+    let mut conn = open_db(&db_path);
+    create_favorites_table(&conn);
+    create_playlists_table(&conn);
+    let known_columns: Vec<String> = {
+        let mut stmt = conn.prepare("PRAGMA table_info(tracks)").expect("Failed to read tracks schema");
+        stmt.query_map([], |row| row.get(1))
+            .expect("Failed to read tracks schema")
+            .filter_map(Result::ok)
+            .collect()
+    };
+
+    // Library tracks by path and by normalized artist and title, to tell re-imported tracks
+    // from duplicates at another path. Only tracks indexed before the import count as
+    // duplicates, so copies the archive itself keeps are all restored.
+    let mut indexed_paths: std::collections::HashSet<String> = {
+        let mut stmt = conn.prepare("SELECT path FROM tracks").expect("Failed to prepare statement");
+        stmt.query_map([], |row| row.get(0))
+            .expect("Failed to execute query")
+            .filter_map(Result::ok)
+            .collect()
+    };
+    let by_tags: HashMap<(String, String), i64> = {
+        let mut stmt = conn
+            .prepare("SELECT id, artist, title FROM tracks WHERE COALESCE(artist, '') != '' AND COALESCE(title, '') != '' ORDER BY id")
+            .expect("Failed to prepare statement");
+        let rows: Vec<(i64, String, String)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .expect("Failed to execute query")
+            .filter_map(Result::ok)
+            .collect();
+        let mut by_tags = HashMap::new();
+        for (id, artist, title) in rows {
+            by_tags.entry((normalized_tag(&artist), normalized_tag(&title))).or_insert(id);
+        }
+        by_tags
+    };
+
+    let tx = conn.transaction().expect("Failed to start transaction");
+    let (mut restored, mut relocated, mut missing) = (0, 0, 0);
+    let (mut added, mut updated, mut skipped, mut added_duplicates, mut replaced) = (0, 0, 0, 0, 0);
+    // A library track is replaced once; further archived copies of it are added next to it
+    let mut replaced_ids = std::collections::HashSet::new();
+    for track in &archive.tracks {
+        // Columns this database doesn't have, e.g. from a newer apollo, are dropped
+        let (columns, mut values): (Vec<&str>, Vec<rusqlite::types::Value>) = track
+            .iter()
+            .filter(|(column, _)| *column != "id" && known_columns.contains(column))
+            .map(|(column, value)| (column.as_str(), json_to_sql(value)))
+            .unzip();
+        let Some(path_index) = columns.iter().position(|c| *c == "path") else {
+            continue;
+        };
+        if let rusqlite::types::Value::Text(path) = &values[path_index]
+            && let Some(new_path) = relocate_path(path, relocations)
+        {
+            if !Path::new(&new_path).exists() {
+                eprintln!("{}", format!("Skipping {}: relocated file {} doesn't exist", path, new_path).yellow());
+                missing += 1;
+                continue;
+            }
+            values[path_index] = rusqlite::types::Value::Text(new_path);
+            relocated += 1;
+        }
+        let rusqlite::types::Value::Text(path) = values[path_index].clone() else {
+            continue;
+        };
+
+        let tag = |column: &str| track.get(column).and_then(serde_json::Value::as_str).map(normalized_tag).unwrap_or_default();
+        let tags = (tag("artist"), tag("title"));
+        let duplicate_of = if tags.0.is_empty() || tags.1.is_empty() || indexed_paths.contains(&path) {
+            None
+        } else {
+            by_tags.get(&tags).copied()
+        };
+        let replace = duplicate_of.filter(|id| on_duplicate == OnDuplicate::Replace && !replaced_ids.contains(id));
+
+        let placeholders: Vec<String> = (1..=columns.len()).map(|i| format!("?{}", i)).collect();
+        let result = match (duplicate_of, replace) {
+            (Some(id), _) if on_duplicate == OnDuplicate::Skip => {
+                eprintln!("{}", format!("Skipping {}: duplicate of library track {}", path, id).yellow());
+                skipped += 1;
+                continue;
+            }
+            (_, Some(id)) => {
+                let assignments: Vec<String> = columns.iter().zip(&placeholders).map(|(c, p)| format!("{} = {}", c, p)).collect();
+                values.push(rusqlite::types::Value::Integer(id));
+                tx.execute(
+                    &format!("UPDATE tracks SET {} WHERE id = ?{}", assignments.join(", "), values.len()),
+                    rusqlite::params_from_iter(values),
+                )
+            }
+            _ => {
+                let updates: Vec<String> = columns.iter().map(|c| format!("{} = excluded.{}", c, c)).collect();
+                tx.execute(
+                    &format!(
+                        "INSERT INTO tracks ({}) VALUES ({}) ON CONFLICT(path) DO UPDATE SET {}",
+                        columns.join(", "), placeholders.join(", "), updates.join(", ")
+                    ),
+                    rusqlite::params_from_iter(values),
+                )
+            }
+        };
+        match result {
+            Err(e) => eprintln!("{}", format!("Failed to restore track: {}", e).red()),
+            Ok(_) => {
+                restored += 1;
+                match (duplicate_of, replace) {
+                    (_, Some(id)) => {
+                        replaced_ids.insert(id);
+                        replaced += 1;
+                    }
+                    (Some(_), None) => added_duplicates += 1,
+                    (None, _) if indexed_paths.contains(&path) => updated += 1,
+                    (None, _) => added += 1,
+                }
+                indexed_paths.insert(path);
+            }
+        }
+    }
+
+    let mut favorites = 0;
+    for favorite in &archive.favorites {
+        let path = relocate_path(&favorite.path, relocations).unwrap_or_else(|| favorite.path.clone());
+        favorites += tx.execute(
+            "INSERT OR IGNORE INTO favorites (track_id, added_at) SELECT id, ?2 FROM tracks WHERE path = ?1",
+            rusqlite::params![path, favorite.added_at],
+        ).expect("Failed to restore favorite");
+    }
+
+    let mut playlists = 0;
+    for playlist in &archive.playlists {
+        let playlist_path = relocate_path(&playlist.path, relocations).unwrap_or_else(|| playlist.path.clone());
+        // Absolute entries move with the tracks; relative ones already follow the playlist
+        let content: String = playlist
+            .content
+            .split_inclusive('\n')
+            .map(|line| {
+                let entry = line.trim_end_matches(['\r', '\n']);
+                match relocate_path(entry, relocations) {
+                    Some(relocated) => format!("{}{}", relocated, &line[entry.len()..]),
+                    None => line.to_string(),
+                }
+            })
+            .collect();
+        let path = Path::new(&playlist_path);
+        if path.exists() && !force {
+            eprintln!("{}", format!("Keeping existing playlist {} (use --force to overwrite)", playlist_path).yellow());
+        } else {
+            let result = path.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| fs::write(path, &content));
+            log_mutation("write-playlist", &[path], &result);
+            if let Err(e) = result {
+                eprintln!("{}", format!("Failed to write playlist {}: {}", playlist_path, e).red());
+                continue;
+            }
+            playlists += 1;
+        }
+        tx.execute(
+            "INSERT OR IGNORE INTO playlists (name, path) VALUES (?1, ?2)",
+            [&playlist.name, &playlist_path],
+        ).expect("Failed to restore playlist");
+    }
+    tx.commit().expect("Failed to commit transaction");
+
+    println!(
+        "{}",
+        format!(
+            "Restored {} track(s), {} favorite(s) and {} playlist file(s) from {}",
+            restored, favorites, playlists, archive_path.display()
+        ).green()
+    );
+    println!(
+        "Tracks: {} new, {} already indexed, {} duplicate(s) skipped, {} added, {} replaced",
+        added, updated, skipped, added_duplicates, replaced
+    );
+    if !relocations.is_empty() {
+        println!("Relocated {} track path(s); skipped {} whose relocated file doesn't exist", relocated, missing);
+    }
+}
+
+// Headline numbers shown by Stats. Computing them walks the whole music directory and
+// backfills missing durations, so they are cached in `stats_cache` between runs.
+#[derive(Serialize)]
+struct LibraryTotals {
+    tracks: i64,
+    artists: i64,
+    albums: i64,
+    folder_size: u64,
+    duration: f64,
+}
+
+// Stats with --output-format prints the totals as a single row
+impl Renderable for LibraryTotals {
+    fn headers() -> &'static [&'static str] {
+        &["Tracks", "Artists", "Albums", "Size", "Duration"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.tracks.to_string(),
+            self.artists.to_string(),
+            self.albums.to_string(),
+            self.folder_size.to_string(),
+            format!("{:.0}", self.duration),
+        ]
+    }
+}
+
+fn create_stats_cache_table(conn: &rusqlite::Connection) {
+    create_table(conn,
+        "CREATE TABLE IF NOT EXISTS stats_cache (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            total_tracks INTEGER NOT NULL,
+            total_artists INTEGER NOT NULL,
+            total_albums INTEGER NOT NULL,
+            folder_size INTEGER NOT NULL,
+            total_duration REAL NOT NULL,
+            computed_at INTEGER NOT NULL
+        )",
+    ).expect("Failed to create stats cache table");
+}
+
+// Drop the cached totals; called before any command that changes the library
+fn invalidate_stats_cache(db_path: &str) {
+    let conn = open_db(db_path);
+    create_stats_cache_table(&conn);
+    conn.execute("DELETE FROM stats_cache", []).expect("Failed to clear stats cache");
+}
+
+// Cached totals and their age in seconds, if they are younger than `ttl_secs`
+fn load_stats_cache(conn: &rusqlite::Connection, ttl_secs: i64) -> Option<(LibraryTotals, i64)> {
+    conn.query_row(
+        "SELECT total_tracks, total_artists, total_albums, folder_size, total_duration, \
+         CAST(strftime('%s', 'now') AS INTEGER) - computed_at FROM stats_cache \
+         WHERE id = 1 AND CAST(strftime('%s', 'now') AS INTEGER) - computed_at <= ?1",
+        [ttl_secs],
+        |row| Ok((
+            LibraryTotals {
+                tracks: row.get(0)?,
+                artists: row.get(1)?,
+                albums: row.get(2)?,
+                folder_size: row.get::<_, i64>(3)? as u64,
+                duration: row.get(4)?,
+            },
+            row.get(5)?,
+        )),
+    ).ok()
+}
+
+// Probe the duration of tracks that don't have one yet, optionally only those added in
+// [since, before). Returns how many durations were filled in and their sum in seconds;
+// under --read-only they are probed but not saved.
+fn backfill_durations(conn: &rusqlite::Connection, since: Option<&str>, before: Option<&str>) -> (usize, f64) {
+    // Collect all rows first to know the total count for the progress bar; the statement is
+    // dropped before anything is written
+    let rows_vec: Vec<(i64, String)> = {
+        let mut stmt = conn.prepare(
+            "SELECT id, path FROM tracks WHERE duration = 0 \
+             AND (?1 IS NULL OR date_added >= CAST(strftime('%s', ?1) AS INTEGER)) \
+             AND (?2 IS NULL OR date_added < CAST(strftime('%s', ?2) AS INTEGER))"
+        ).expect("Failed to prepare statement");
+        stmt.query_map([since, before], |row| Ok((row.get(0)?, row.get(1)?)))
+            .expect("Failed to execute query")
+            .filter_map(Result::ok)
+            .collect()
+    };
+
+    let pb = make_progress_bar(rows_vec.len());
+
+    let mut probed: Vec<(i64, f64)> = Vec::new();
+    for (id, path) in rows_vec {
+        let duration: f64 = get_duration_with_lofty(std::path::Path::new(&path)) as f64;
+        if duration > 0.0 {
+            probed.push((id, duration));
+        }
+        pb.inc(1);
+        pb.set_message(path.to_string());
+    }
+
+    // All updates go in one short transaction rather than one implicit one per track
+    if !read_only() && !probed.is_empty() {
+        retry_when_busy(|| {
+            let tx = conn.unchecked_transaction()?;
+            for (id, duration) in &probed {
+                tx.execute("UPDATE tracks SET duration = ?1 WHERE id = ?2", rusqlite::params![duration, id])?;
+            }
+            tx.commit()
+        }).unwrap_or_else(|e| exit_with_error(EXIT_DATABASE, &format!("failed to save durations: {}", e)));
+    }
+    pb.finish_with_message("Duration update complete");
+    (probed.len(), probed.iter().map(|(_, duration)| duration).sum())
+}
+
+fn backfill(db_path: &str, since: Option<&str>, before: Option<&str>) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+
+    for date in [since, before].into_iter().flatten() {
+        let valid: bool = conn
+            .query_row("SELECT strftime('%s', ?1) IS NOT NULL", [date], |row| row.get(0))
+            .unwrap_or(false);
+        if !valid {
+            exit_with_error(EXIT_ERROR, &format!("invalid date '{}' (expected YYYY-MM-DD)", date));
+        }
+    }
+
+    let (filled, _) = backfill_durations(&conn, since, before);
+    println!("{}", format!("Filled in {} duration(s).", filled).green());
+}
+
+fn compute_library_totals(conn: &rusqlite::Connection, music_dir: &str) -> LibraryTotals {
+    let total_tracks: i64 = conn.query_row("SELECT COUNT(*) FROM tracks", [], |row| row.get(0)).unwrap_or(0);
+    let total_artists: i64 = conn.query_row(
+        &format!("SELECT COUNT(DISTINCT value) FROM {}", CREDITED_ARTISTS),
+        [],
+        |row| row.get(0),
+    ).unwrap_or(0);
+    let total_albums: i64 = conn.query_row("SELECT COUNT(DISTINCT album) FROM tracks", [], |row| row.get(0)).unwrap_or(0);
+    
+    // Durations probed under --read-only aren't in the table, so they are added on top
+    let (_, probed_secs) = backfill_durations(conn, None, None);
+    let unsaved_secs = if read_only() { probed_secs } else { 0.0 };
+
+    let total_duration: f64 = conn.query_row(
+        "SELECT SUM(duration) FROM tracks",
+        [],
+        |row| row.get(0)
+    ).unwrap_or(0.0) + unsaved_secs;
+
+    LibraryTotals {
+        tracks: total_tracks,
+        artists: total_artists,
+        albums: total_albums,
+        folder_size: get_dir_size(music_dir).unwrap(),
+        duration: total_duration,
+    }
+}
+
+fn stats_cache_ttl_secs(settings: &Settings) -> i64 {
+    settings.stats.as_ref().and_then(|s| s.cache_ttl_secs).unwrap_or(24 * 60 * 60)
+}
+
+// The cached totals and their age in seconds, or freshly computed ones that are then cached
+fn library_totals(conn: &rusqlite::Connection, music_dir: &str, refresh: bool, cache_ttl_secs: i64) -> (LibraryTotals, Option<i64>) {
+    create_stats_cache_table(conn);
+    let cached = if refresh { None } else { load_stats_cache(conn, cache_ttl_secs) };
+    match cached {
+        Some((totals, age)) => (totals, Some(age)),
+        None => {
+            let totals = compute_library_totals(conn, music_dir);
+            if !read_only() {
+                conn.execute(
+                    "INSERT OR REPLACE INTO stats_cache \
+                     (id, total_tracks, total_artists, total_albums, folder_size, total_duration, computed_at) \
+                     VALUES (1, ?1, ?2, ?3, ?4, ?5, strftime('%s', 'now'))",
+                    rusqlite::params![totals.tracks, totals.artists, totals.albums, totals.folder_size as i64, totals.duration],
+                ).expect("Failed to update stats cache");
+            }
+            (totals, None)
+        }
+    }
+}
+
+fn get_stats(music_dir: &str, db_path: &str, refresh: bool, cache_ttl_secs: i64) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+
+    let (totals, cache_age) = library_totals(&conn, music_dir, refresh, cache_ttl_secs);
+    if let Some(age) = cache_age
+        && output_format() == OutputFormat::Text
+    {
+        println!("Using statistics cached {} ago (pass --refresh to recompute).", format_duration(age as f64));
+    }
+    let format = output_format();
+    if format != OutputFormat::Text {
+        render(&[totals], format);
+        return;
+    }
+    let total_tracks = totals.tracks;
+    let folder_size: String = format_bytes(totals.folder_size as f64);
+
+    print_table(&["Statistic", "Value"], &[
+        vec!["Total tracks".to_string(), total_tracks.to_string()],
+        vec!["Total artists".to_string(), totals.artists.to_string()],
+        vec!["Total albums".to_string(), totals.albums.to_string()],
+        vec!["Total size".to_string(), folder_size],
+        vec!["Total time".to_string(), format_duration(totals.duration)],
+    ]);
+
+    // Headline tag health: tracks missing any of the core tags
+    let missing_core: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM tracks WHERE COALESCE(artist, '') = '' OR COALESCE(album, '') = '' OR COALESCE(title, '') = ''",
+        [],
+        |row| row.get(0),
+    ).unwrap_or(0);
+    if total_tracks > 0 {
+        let share = missing_core as f64 * 100.0 / total_tracks as f64;
+        let summary = format!(
+            "{} of {} tracks are missing at least one core tag (artist, album, title): {:.1}%",
+            missing_core, total_tracks, share
+        );
+        let summary = if share < 5.0 {
+            summary.green()
+        } else if share < 20.0 {
+            summary.yellow()
+        } else {
+            summary.red()
+        };
+        println!("\nTag health: {}", summary);
+    }
+
+    // --- Date Histogram ---
+    println!("\nTracks by Year:");
+    let mut stmt = conn.prepare(
+        "SELECT year, COUNT(*) FROM tracks WHERE year IS NOT NULL AND year > 0 GROUP BY year ORDER BY year"
+    ).expect("Failed to prepare year histogram statement");
+    let mut rows = stmt.query([]).expect("Failed to execute year histogram query");
+
+    // Collect year counts
+    let mut year_counts = Vec::new();
+    let mut max_count = 0;
+    while let Some(row) = rows.next().expect("Failed to fetch year row") {
+        let year: i64 = row.get(0).unwrap_or(0);
+        let count: i64 = row.get(1).unwrap_or(0);
+        if count > max_count {
+            max_count = count;
+        }
+        year_counts.push((year, count));
+    }
+
+    // Print histogram
+    for (year, count) in year_counts {
+        let bar_len = if max_count > 0 { (count * 40 / max_count) as usize } else { 0 };
+        let bar = "█".repeat(bar_len);
+        println!("{:4}: {:4} {}", year, count, bar);
+    }
+}
+
+// One Stats --histogram bar for --output-format
+#[derive(Serialize)]
+struct HistogramBucket {
+    bucket: String,
+    tracks: i64,
+}
+
+impl Renderable for HistogramBucket {
+    fn headers() -> &'static [&'static str] {
+        &["Bucket", "Tracks"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![self.bucket.clone(), self.tracks.to_string()]
+    }
+}
+
+// Tracks per bucket of `dimension` ("duration", "bitrate" or "year") as a horizontal bar chart
+fn print_histogram(db_path: &str, dimension: &str) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+
+    // Bitrate isn't stored, so it is estimated from the file size and duration
+    let (title, bucket_sql, fixed_labels): (&str, &str, &[&str]) = match dimension {
+        "duration" => (
+            "Tracks by duration",
+            "SELECT CASE WHEN duration < 120 THEN 0 WHEN duration < 240 THEN 1 WHEN duration < 360 THEN 2 \
+             WHEN duration < 600 THEN 3 ELSE 4 END AS bucket, COUNT(*) FROM tracks \
+             WHERE duration > 0 GROUP BY bucket ORDER BY bucket",
+            &["0-2 min", "2-4 min", "4-6 min", "6-10 min", "10+ min"],
+        ),
+        "bitrate" => (
+            "Tracks by average bitrate",
+            "SELECT CASE WHEN kbps < 128 THEN 0 WHEN kbps < 192 THEN 1 WHEN kbps < 256 THEN 2 \
+             WHEN kbps < 321 THEN 3 WHEN kbps < 1000 THEN 4 ELSE 5 END AS bucket, COUNT(*) FROM \
+             (SELECT size * 8.0 / duration / 1000 AS kbps FROM tracks WHERE duration > 0 AND size > 0) \
+             GROUP BY bucket ORDER BY bucket",
+            &["<128k", "128-191k", "192-255k", "256-320k", "321-999k", "1000k+"],
+        ),
+        _ => (
+            "Tracks by decade",
+            "SELECT (year / 10) * 10 AS bucket, COUNT(*) FROM tracks WHERE year > 0 GROUP BY bucket ORDER BY bucket",
+            &[],
+        ),
+    };
+
+    let mut stmt = conn.prepare(bucket_sql).expect("Failed to prepare histogram statement");
+    let counts: Vec<(i64, i64)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .expect("Failed to execute histogram query")
+        .filter_map(Result::ok)
+        .collect();
+    let format = output_format();
+    if format == OutputFormat::Text {
+        println!("\n{}:", title);
+    }
+    if counts.is_empty() && format == OutputFormat::Text {
+        println!("{}", "No tracks found.".yellow());
+        return;
+    }
+
+    // Fixed buckets are all shown, even when empty, so the shape of the distribution is visible
+    let bars: Vec<(String, i64)> = if fixed_labels.is_empty() {
+        counts.into_iter().map(|(decade, count)| (format!("{}s", decade), count)).collect()
+    } else {
+        fixed_labels
+            .iter()
+            .enumerate()
+            .map(|(i, label)| {
+                let count = counts.iter().find(|(bucket, _)| *bucket == i as i64).map_or(0, |(_, count)| *count);
+                (label.to_string(), count)
+            })
+            .collect()
+    };
+    if format != OutputFormat::Text {
+        let buckets: Vec<HistogramBucket> = bars.into_iter().map(|(bucket, tracks)| HistogramBucket { bucket, tracks }).collect();
+        render(&buckets, format);
+        return;
+    }
+    let max_count = bars.iter().map(|(_, count)| *count).max().unwrap_or(0);
+    let label_width = bars.iter().map(|(label, _)| label.len()).max().unwrap_or(0);
+    for (label, count) in bars {
+        let bar_len = if max_count > 0 { (count * 40 / max_count) as usize } else { 0 };
+        let line = format!("{:>width$}: {:4} {}", label, count, "█".repeat(bar_len), width = label_width);
+        println!("{}", line.trim_end());
+    }
+}
+
+// One Stats --formats row; share is of the library's total size
+#[derive(Serialize)]
+struct FormatShare {
+    format: String,
+    tracks: i64,
+    size: i64,
+    share: f64,
+}
+
+impl Renderable for FormatShare {
+    fn headers() -> &'static [&'static str] {
+        &["Format", "Tracks", "Size", "Share"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![self.format.clone(), self.tracks.to_string(), self.size.to_string(), format!("{:.1}", self.share)]
+    }
+}
+
+fn print_format_breakdown(db_path: &str) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+
+    let mut stmt = conn.prepare(
+        "SELECT COALESCE(ext, ''), COUNT(*), COALESCE(SUM(size), 0) FROM tracks \
+         GROUP BY ext ORDER BY SUM(size) DESC"
+    ).expect("Failed to prepare format statement");
+    let formats: Vec<(String, i64, i64)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .expect("Failed to execute format query")
+        .filter_map(Result::ok)
+        .collect();
+
+    let total_size: i64 = formats.iter().map(|(_, _, size)| size).sum();
+    let shares: Vec<FormatShare> = formats
+        .into_iter()
+        .map(|(ext, tracks, size)| FormatShare {
+            // Rows indexed before the ext column existed are filled in by the next Index
+            format: if ext.is_empty() { "unknown".to_string() } else { ext.to_uppercase() },
+            tracks,
+            size,
+            share: if total_size > 0 { size as f64 * 100.0 / total_size as f64 } else { 0.0 },
+        })
+        .collect();
+    let format = output_format();
+    if format != OutputFormat::Text {
+        render(&shares, format);
+        return;
+    }
+
+    println!("\nFormats:");
+    if shares.is_empty() {
+        println!("{}", "No tracks found.".yellow());
+        return;
+    }
+    let rows: Vec<Vec<String>> = shares
+        .iter()
+        .map(|f| vec![f.format.clone(), f.tracks.to_string(), format_bytes(f.size as f64), format!("{:.1}%", f.share)])
+        .collect();
+    print_table(&["Format", "Tracks", "Size", "Share"], &rows);
+}
+
+// A track count per artist, for Stats --artists-without-album and --by-artist
+#[derive(Serialize)]
+struct ArtistTracks {
+    artist: String,
+    tracks: i64,
+}
+
+impl Renderable for ArtistTracks {
+    fn headers() -> &'static [&'static str] {
+        &["Artist", "Tracks"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![self.artist.clone(), self.tracks.to_string()]
+    }
+}
+
+fn print_artists_without_album(db_path: &str) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+
+    let mut stmt = conn.prepare(
+        "SELECT artist, COUNT(*) FROM tracks WHERE album = '' AND artist != '' \
+         GROUP BY artist ORDER BY COUNT(*) DESC, artist COLLATE NOCASE"
+    ).expect("Failed to prepare album statement");
+    let counts: Vec<ArtistTracks> = stmt
+        .query_map([], |row| Ok(ArtistTracks { artist: row.get(0)?, tracks: row.get(1)? }))
+        .expect("Failed to execute album query")
+        .filter_map(Result::ok)
+        .collect();
+    let format = output_format();
+    if format != OutputFormat::Text {
+        render(&counts, format);
+        return;
+    }
+
+    println!("\nArtists with tracks missing an album:");
+    let rows: Vec<Vec<String>> = counts.iter().map(ArtistTracks::cells).collect();
+    if rows.is_empty() {
+        println!("{}", "Every artist's tracks have an album.".green());
+        return;
+    }
+    print_table(&["Artist", "Tracks"], &rows);
+}
+
+// One disc of an album with fewer tracks indexed than its total_tracks tag says
+#[derive(Serialize)]
+struct IncompleteAlbum {
+    artist: String,
+    album: String,
+    disc: i64,
+    total_discs: Option<i64>,
+    tracks: i64,
+    total_tracks: i64,
+}
+
+impl Renderable for IncompleteAlbum {
+    fn headers() -> &'static [&'static str] {
+        &["Artist", "Album", "Disc", "Total discs", "Tracks", "Total tracks"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.artist.clone(),
+            self.album.clone(),
+            self.disc.to_string(),
+            self.total_discs.map(|n| n.to_string()).unwrap_or_default(),
+            self.tracks.to_string(),
+            self.total_tracks.to_string(),
+        ]
+    }
+}
+
+fn print_incomplete_albums(db_path: &str) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+
+    // Track totals are per disc, so each disc is checked on its own
+    let mut stmt = conn.prepare(
+        "SELECT COALESCE(NULLIF(albumartist, ''), artist) AS album_artist, album, COALESCE(disc_number, 1) AS disc, \
+         COUNT(*), MAX(total_tracks), MAX(total_discs) FROM tracks \
+         WHERE album != '' AND total_tracks IS NOT NULL \
+         GROUP BY album_artist, album, disc HAVING COUNT(*) < MAX(total_tracks) \
+         ORDER BY album_artist COLLATE NOCASE, album COLLATE NOCASE, disc"
+    ).expect("Failed to prepare incomplete albums statement");
+    let albums: Vec<IncompleteAlbum> = stmt
+        .query_map([], |row| {
+            Ok(IncompleteAlbum {
+                artist: row.get(0)?,
+                album: row.get(1)?,
+                disc: row.get(2)?,
+                tracks: row.get(3)?,
+                total_tracks: row.get(4)?,
+                total_discs: row.get(5)?,
+            })
+        })
+        .expect("Failed to execute incomplete albums query")
+        .filter_map(Result::ok)
+        .collect();
+    let format = output_format();
+    if format != OutputFormat::Text {
+        render(&albums, format);
+        return;
+    }
+
+    println!("\nIncomplete albums:");
+    let rows: Vec<Vec<String>> = albums
+        .into_iter()
+        .map(|a| {
+            let disc = match a.total_discs {
+                Some(discs) => format!("{}/{}", a.disc, discs),
+                None => a.disc.to_string(),
+            };
+            vec![a.artist, a.album, disc, format!("{}/{}", a.tracks, a.total_tracks)]
+        })
+        .collect();
+    if rows.is_empty() {
+        println!("{}", "No incomplete albums found.".green());
+        return;
+    }
+    print_table(&["Artist", "Album", "Disc", "Tracks"], &rows);
+}
+
+// Stats --duplicates-summary as a single row; reclaimable is in bytes
+#[derive(Serialize)]
+struct DuplicatesSummary {
+    groups: usize,
+    surplus_files: usize,
+    reclaimable: i64,
+}
+
+impl Renderable for DuplicatesSummary {
+    fn headers() -> &'static [&'static str] {
+        &["Groups", "Surplus files", "Reclaimable"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![self.groups.to_string(), self.surplus_files.to_string(), self.reclaimable.to_string()]
+    }
+}
+
+fn print_duplicates_summary(db_path: &str, dupe_key: &[String]) {
+    let key = match dupe_key_sql(dupe_key) {
+        Ok(sql) => sql,
+        Err(e) => exit_with_error(EXIT_CONFIG, &e),
+    };
+
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+    create_dupe_ignores_table(&conn);
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {}, path, COALESCE(real_format, ext, ''), COALESCE(size, 0), \
+         id IN (SELECT track_id FROM dupe_ignores) FROM tracks WHERE {}",
+        key.columns, key.filter
+    )).expect("Failed to prepare duplicate summary statement");
+    let mut rows = stmt.query([]).expect("Failed to execute duplicate summary query");
+
+    // (quality rank, size, ignored) for every copy, keyed by the dupe key values
+    let mut groups: HashMap<Vec<String>, Vec<(u8, i64, bool)>> = HashMap::new();
+    while let Some(row) = rows.next().expect("Failed to fetch row") {
+        let values: Vec<String> = (0..dupe_key.len())
+            .map(|i| row.get(i).expect("Failed to get key column"))
+            .collect();
+        let path: String = row.get(dupe_key.len()).expect("Failed to get path");
+        let format: String = row.get(dupe_key.len() + 1).unwrap_or_default();
+        let format = if format.is_empty() {
+            Path::new(&path).extension().and_then(|e| e.to_str()).unwrap_or("").to_string()
+        } else {
+            format
+        };
+        let size: i64 = row.get(dupe_key.len() + 2).unwrap_or(0);
+        let ignored: bool = row.get(dupe_key.len() + 3).unwrap_or(false);
+        groups.entry(values).or_default().push((quality_rank(&format), size, ignored));
+    }
+
+    let mut group_count = 0;
+    let mut surplus_files = 0;
+    let mut reclaimable: i64 = 0;
+    for mut copies in groups.into_values() {
+        if copies.iter().filter(|(_, _, ignored)| !ignored).count() < 2 {
+            continue;
+        }
+        // Keep the best quality copy, preferring the larger file on ties
+        copies.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+        group_count += 1;
+        surplus_files += copies.len() - 1;
+        reclaimable += copies[1..].iter().map(|(_, size, _)| size).sum::<i64>();
+    }
+    let format = output_format();
+    if format != OutputFormat::Text {
+        render(&[DuplicatesSummary { groups: group_count, surplus_files, reclaimable }], format);
+        return;
+    }
+
+    println!("\nDuplicates:");
+    print_table(&["Statistic", "Value"], &[
+        vec!["Duplicate groups".to_string(), group_count.to_string()],
+        vec!["Surplus files".to_string(), surplus_files.to_string()],
+        vec!["Reclaimable space".to_string(), format_bytes(reclaimable as f64)],
+    ]);
+}
+
+// Tracks and listening time (in seconds) of one period, for Stats --per-year-added and
+// --by-decade; the period is a year added or a release decade ("1990s", "Unknown")
+#[derive(Serialize)]
+struct ListeningTime {
+    period: String,
+    tracks: i64,
+    duration: f64,
+}
+
+impl Renderable for ListeningTime {
+    fn headers() -> &'static [&'static str] {
+        &["Period", "Tracks", "Duration"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![self.period.clone(), self.tracks.to_string(), format!("{:.0}", self.duration)]
+    }
+}
+
+fn print_time_added_per_year(db_path: &str) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+
+    let mut stmt = conn.prepare(
+        "SELECT strftime('%Y', date_added, 'unixepoch') AS year_added, COUNT(*), COALESCE(SUM(duration), 0) \
+         FROM tracks WHERE date_added IS NOT NULL GROUP BY year_added ORDER BY year_added"
+    ).expect("Failed to prepare year added statement");
+    let years: Vec<ListeningTime> = stmt
+        .query_map([], |row| Ok(ListeningTime { period: row.get(0)?, tracks: row.get(1)?, duration: row.get(2)? }))
+        .expect("Failed to execute year added query")
+        .filter_map(Result::ok)
+        .collect();
+    let format = output_format();
+    if format != OutputFormat::Text {
+        render(&years, format);
+        return;
+    }
+
+    println!("\nListening time added per year:");
+    let rows: Vec<Vec<String>> = years
+        .into_iter()
+        .map(|year| vec![year.period, year.tracks.to_string(), format_duration(year.duration)])
+        .collect();
+    if rows.is_empty() {
+        println!("{}", "No tracks with a recorded date added.".yellow());
+        return;
+    }
+    print_table(&["Year", "Tracks", "Listening time"], &rows);
+}
+
+// Release decade from the year tag, as opposed to print_time_added_per_year's date_added
+fn print_time_by_decade(db_path: &str) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+
+    let mut stmt = conn.prepare(
+        "SELECT CASE WHEN year > 0 THEN year / 10 * 10 END AS decade, COUNT(*), COALESCE(SUM(duration), 0) \
+         FROM tracks GROUP BY decade ORDER BY decade IS NULL, decade"
+    ).expect("Failed to prepare decade statement");
+    let decades: Vec<ListeningTime> = stmt
+        .query_map([], |row| {
+            Ok(ListeningTime {
+                period: row.get::<_, Option<i64>>(0)?.map(|d| format!("{}s", d)).unwrap_or_else(|| "Unknown".to_string()),
+                tracks: row.get(1)?,
+                duration: row.get(2)?,
+            })
+        })
+        .expect("Failed to execute decade query")
+        .filter_map(Result::ok)
+        .collect();
+    let format = output_format();
+    if format != OutputFormat::Text {
+        render(&decades, format);
+        return;
+    }
+
+    println!("\nListening time by decade:");
+    let rows: Vec<Vec<String>> = decades
+        .into_iter()
+        .map(|decade| vec![decade.period, decade.tracks.to_string(), format_duration(decade.duration)])
+        .collect();
+    if rows.is_empty() {
+        println!("{}", "No tracks indexed.".yellow());
+        return;
+    }
+    print_table(&["Decade", "Tracks", "Listening time"], &rows);
+}
+
+// One row per credited artist of each track: the split-off list when there is one, else the artist
+const CREDITED_ARTISTS: &str = "tracks, json_each(COALESCE(tracks.artists, json_array(tracks.artist)))";
+
+// With primary_artist, guest credits are rolled up under the main artist for display only;
+// Normalize --canonicalize-feat rewrites the tags the same way
+fn print_tracks_by_artist(db_path: &str, primary_artist: bool, exceptions: &[String]) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT value, COUNT(*) FROM {} WHERE value IS NOT NULL AND value != '' \
+         GROUP BY value ORDER BY COUNT(*) DESC, value",
+        CREDITED_ARTISTS
+    )).expect("Failed to prepare artist statement");
+    let mut counts: Vec<(String, i64)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .expect("Failed to execute artist query")
+        .filter_map(Result::ok)
+        .collect();
+    if primary_artist {
+        let mut rolled_up: HashMap<String, i64> = HashMap::new();
+        for (artist, count) in counts {
+            let primary = split_featured_artist(&artist, exceptions).map_or(artist.as_str(), |(primary, _)| primary);
+            *rolled_up.entry(primary.to_string()).or_default() += count;
+        }
+        counts = rolled_up.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    }
+    let counts: Vec<ArtistTracks> = counts.into_iter().map(|(artist, tracks)| ArtistTracks { artist, tracks }).collect();
+    let format = output_format();
+    if format != OutputFormat::Text {
+        render(&counts, format);
+        return;
+    }
+
+    println!("\nTracks by artist:");
+    let rows: Vec<Vec<String>> = counts.iter().map(ArtistTracks::cells).collect();
+    if rows.is_empty() {
+        println!("{}", "No tracks indexed.".yellow());
+        return;
+    }
+    print_table(&["Artist", "Tracks"], &rows);
+}
+
+// What silencedetect counts as silence: below -50 dB for at least half a second
+const SILENCE_FILTER: &str = "silencedetect=noise=-50dB:d=0.5";
+
+// Leading plus trailing silence in seconds, from the log of an ffmpeg silencedetect run.
+// Quiet passages in the middle of a track aren't counted.
+fn parse_silence(log: &str) -> Option<f64> {
+    static DURATION: OnceLock<regex::Regex> = OnceLock::new();
+    let duration = DURATION.get_or_init(|| {
+        regex::Regex::new(r"Duration: (\d+):(\d+):(\d+(?:\.\d+)?)").expect("valid duration regex")
+    });
+    let caps = duration.captures(log)?;
+    let duration = caps[1].parse::<f64>().ok()? * 3600.0 + caps[2].parse::<f64>().ok()? * 60.0 + caps[3].parse::<f64>().ok()?;
+
+    let value_after = |line: &str, key: &str| -> Option<f64> {
+        line.split(key).nth(1)?.split(|c: char| c.is_whitespace() || c == '|').next()?.parse().ok()
+    };
+    // Silence still running at the end of the file may have no silence_end line
+    let mut intervals: Vec<(f64, Option<f64>)> = Vec::new();
+    for line in log.lines() {
+        if let Some(start) = value_after(line, "silence_start: ") {
+            intervals.push((start.max(0.0), None));
+        } else if let Some(end) = value_after(line, "silence_end: ")
+            && let Some(last) = intervals.last_mut()
+        {
+            last.1 = Some(end);
+        }
+    }
+
+    const EDGE_SECS: f64 = 0.05;
+    let length = |(start, end): (f64, Option<f64>)| (end.unwrap_or(duration).min(duration) - start).max(0.0);
+    let leading = intervals.first().copied().filter(|(start, _)| *start <= EDGE_SECS);
+    let trailing = intervals.last().copied().filter(|(_, end)| end.is_none_or(|end| end >= duration - EDGE_SECS));
+    Some(match (leading, trailing) {
+        // A silent file is one interval that is both
+        (Some(_), Some(_)) if intervals.len() == 1 => length(intervals[0]),
+        (leading, trailing) => leading.map_or(0.0, length) + trailing.map_or(0.0, length),
+    })
+}
+
+fn detect_silence(path: &Path) -> Option<f64> {
+    let output = std::process::Command::new("ffmpeg")
+        .args(["-hide_banner", "-nostats", "-i"])
+        .arg(path)
+        .args(["-af", SILENCE_FILTER, "-f", "null", "-"])
+        .stdin(std::process::Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_silence(&String::from_utf8_lossy(&output.stderr))
+}
+
+// Stats --silence as a single row: total leading and trailing silence in seconds
+#[derive(Serialize)]
+struct SilenceTotal {
+    silence: f64,
+    analyzed: i64,
+    tracks: i64,
+}
+
+impl Renderable for SilenceTotal {
+    fn headers() -> &'static [&'static str] {
+        &["Silence", "Analyzed", "Tracks"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![format!("{:.1}", self.silence), self.analyzed.to_string(), self.tracks.to_string()]
+    }
+}
+
+// Decoding every file is slow, so each track is measured once and the result kept in
+// silence_secs; `limit` caps how many new tracks one run measures
+fn print_silence_total(db_path: &str, limit: Option<usize>) {
+    let db_path = expand_tilde(db_path);
+    let mut conn = open_db(&db_path);
+
+    let has_ffmpeg = std::process::Command::new("ffmpeg")
+        .arg("-version")
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .is_ok();
+    let pending: Vec<(i64, String)> = {
+        let mut stmt = conn.prepare("SELECT id, path FROM tracks WHERE silence_secs IS NULL ORDER BY id LIMIT ?1")
+            .expect("Failed to prepare statement");
+        stmt.query_map([limit.map_or(-1, |n| n as i64)], |row| Ok((row.get(0)?, row.get(1)?)))
+            .expect("Failed to execute query")
+            .filter_map(Result::ok)
+            .collect()
+    };
+
+    if !pending.is_empty() && read_only() {
+        eprintln!("{}", format!("Warning: --read-only, so {} track(s) not measured yet are left out", pending.len()).yellow());
+    } else if !pending.is_empty() && !has_ffmpeg {
+        eprintln!("{}", "Warning: ffmpeg is not installed or not in PATH, so only tracks measured earlier are counted".yellow());
+    } else if !pending.is_empty() {
+        eprintln!("Measuring silence in {} track(s)...", pending.len());
+        let pb = make_progress_bar(pending.len());
+        let measured: Vec<(i64, Option<f64>)> = pending
+            .par_iter()
+            .map(|(id, path)| {
+                let silence = detect_silence(Path::new(path));
+                pb.inc(1);
+                (*id, silence)
+            })
+            .collect();
+        pb.finish_and_clear();
+
+        let tx = conn.transaction().expect("Failed to start transaction");
+        for (id, silence) in &measured {
+            if let Some(secs) = silence {
+                tx.execute("UPDATE tracks SET silence_secs = ?1 WHERE id = ?2", rusqlite::params![secs, id])
+                    .expect("Failed to save silence");
+            }
+        }
+        tx.commit().expect("Failed to commit transaction");
+        let failed = measured.iter().filter(|(_, silence)| silence.is_none()).count();
+        if failed > 0 {
+            eprintln!("{}", format!("Warning: ffmpeg could not analyze {} track(s)", failed).yellow());
+        }
+    }
+
+    let (analyzed, total_silence, total_tracks): (i64, f64, i64) = conn.query_row(
+        "SELECT COUNT(silence_secs), COALESCE(SUM(silence_secs), 0), COUNT(*) FROM tracks",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    ).unwrap_or((0, 0.0, 0));
+    let format = output_format();
+    if format != OutputFormat::Text {
+        render(&[SilenceTotal { silence: total_silence, analyzed, tracks: total_tracks }], format);
+        return;
+    }
+    println!("\nSilence:");
+    println!("Leading and trailing silence: {} across {} of {} track(s)", format_duration(total_silence), analyzed, total_tracks);
+
+    // Tracks that are mostly silence are often hidden tracks or mis-tagged megamixes
+    let mut stmt = conn.prepare(
+        "SELECT path, silence_secs, duration FROM tracks WHERE silence_secs >= 1 ORDER BY silence_secs DESC LIMIT 10"
+    ).expect("Failed to prepare statement");
+    let rows: Vec<Vec<String>> = stmt
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, f64>(1)?, row.get::<_, Option<f64>>(2)?)))
+        .expect("Failed to execute query")
+        .filter_map(Result::ok)
+        .map(|(path, silence, duration)| {
+            let share = duration
+                .filter(|d| *d > 0.0)
+                .map(|d| format!("{:.0}%", silence / d * 100.0))
+                .unwrap_or_else(|| "?".to_string());
+            vec![format!("{:.1}s", silence), share, path]
+        })
+        .collect();
+    if !rows.is_empty() {
+        print_table(&["Silence", "Of track", "Path"], &rows);
+    }
+}
+
+// Waveform peaks for external players, one row per track. A row is reused while the file's
+// mtime and the requested resolution are unchanged.
+fn create_peaks_table(conn: &rusqlite::Connection) {
+    create_table(conn,
+        "CREATE TABLE IF NOT EXISTS track_peaks (
+            track_id INTEGER PRIMARY KEY,
+            resolution INTEGER NOT NULL,
+            mtime INTEGER,
+            peaks TEXT NOT NULL
+        )",
+    ).expect("Failed to create track_peaks table");
+}
+
+// Rate ffmpeg downmixes and resamples to; plenty for peaks and keeps the decoded PCM small
+const PEAKS_SAMPLE_RATE: &str = "8000";
+
+// The loudest sample in each of `resolution` equal slices of the track, from 0.0 to 1.0
+fn decode_peaks(path: &Path, resolution: usize) -> Option<Vec<f32>> {
+    let output = std::process::Command::new("ffmpeg")
+        .args(["-hide_banner", "-nostats", "-loglevel", "error", "-i"])
+        .arg(path)
+        .args(["-ac", "1", "-ar", PEAKS_SAMPLE_RATE, "-f", "s16le", "-"])
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let samples: Vec<i16> = output.stdout.chunks_exact(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])).collect();
+    if samples.is_empty() {
+        return None;
+    }
+    let slice_len = samples.len().div_ceil(resolution.max(1));
+    Some(
+        samples
+            .chunks(slice_len)
+            .map(|slice| {
+                let peak = slice.iter().map(|s| s.unsigned_abs()).max().unwrap_or(0) as f32 / 32768.0;
+                (peak * 1000.0).round() / 1000.0
+            })
+            .collect(),
+    )
+}
+
+// Analyze: decode every track without current peaks in parallel and store them.
+// Already computed tracks are skipped, so an interrupted run picks up where it stopped.
+fn analyze_peaks(db_path: &str, resolution: usize, force: bool) {
+    let db_path = expand_tilde(db_path);
+    let mut conn = open_db(&db_path);
+    create_peaks_table(&conn);
+    conn.execute("DELETE FROM track_peaks WHERE track_id NOT IN (SELECT id FROM tracks)", [])
+        .expect("Failed to prune peaks");
+
+    let has_ffmpeg = std::process::Command::new("ffmpeg")
+        .arg("-version")
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .is_ok();
+    if !has_ffmpeg {
+        exit_with_error(EXIT_ERROR, "ffmpeg is not installed or not in PATH. Please install ffmpeg to compute peaks");
+    }
+
+    let pending: Vec<(i64, String, Option<i64>)> = {
+        let mut stmt = conn.prepare(
+            "SELECT t.id, t.path, t.mtime FROM tracks t LEFT JOIN track_peaks p ON p.track_id = t.id \
+             WHERE ?1 OR p.track_id IS NULL OR p.resolution != ?2 OR p.mtime IS NOT t.mtime ORDER BY t.path"
+        ).expect("Failed to prepare statement");
+        stmt.query_map(rusqlite::params![force, resolution as i64], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .expect("Failed to execute query")
+            .filter_map(Result::ok)
+            .collect()
+    };
+    if pending.is_empty() {
+        println!("{}", "Peaks are up to date for every track.".green());
+        return;
+    }
+
+    println!("Computing peaks for {} track(s)...", pending.len());
+    let pb = make_progress_bar(pending.len());
+    let computed: Vec<(i64, Option<i64>, Option<Vec<f32>>)> = pending
+        .par_iter()
+        .map(|(id, path, mtime)| {
+            let peaks = decode_peaks(Path::new(path), resolution);
+            pb.inc(1);
+            pb.set_message(path.to_string());
+            (*id, *mtime, peaks)
+        })
+        .collect();
+    pb.finish_and_clear();
+
+    let tx = conn.transaction().expect("Failed to start transaction");
+    let mut stored = 0;
+    for (id, mtime, peaks) in &computed {
+        let Some(peaks) = peaks else {
+            continue;
+        };
+        tx.execute(
+            "INSERT OR REPLACE INTO track_peaks (track_id, resolution, mtime, peaks) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![id, resolution as i64, mtime, serde_json::to_string(peaks).expect("Failed to serialize peaks")],
+        ).expect("Failed to save peaks");
+        stored += 1;
+    }
+    tx.commit().expect("Failed to commit transaction");
+
+    let failed = computed.len() - stored;
+    if failed > 0 {
+        eprintln!("{}", format!("Warning: ffmpeg could not decode {} track(s)", failed).yellow());
+    }
+    println!("{}", format!("Stored peaks for {} track(s).", stored).green());
+}
+
+// Tracks added in one rolling week of Stats --activity; weeks_ago is null for tracks
+// without a recorded date added
+#[derive(Serialize)]
+struct ActivityWeek {
+    weeks_ago: Option<i64>,
+    tracks: i64,
+}
+
+impl Renderable for ActivityWeek {
+    fn headers() -> &'static [&'static str] {
+        &["Weeks ago", "Tracks"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![self.weeks_ago.map_or_else(|| "unknown".to_string(), |n| n.to_string()), self.tracks.to_string()]
+    }
+}
+
+fn print_activity(db_path: &str, weeks: i64) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+
+    let count = |sql: &str| -> i64 { conn.query_row(sql, [], |row| row.get(0)).unwrap_or(0) };
+    let this_week = count("SELECT COUNT(*) FROM tracks WHERE date_added >= strftime('%s', 'now') - 604800");
+    let this_month = count("SELECT COUNT(*) FROM tracks WHERE date_added >= strftime('%s', 'now', 'start of month')");
+    let unknown = count("SELECT COUNT(*) FROM tracks WHERE date_added IS NULL");
+
+    // Bucket into rolling 7-day windows counting back from now
+    let mut stmt = conn.prepare(
+        "SELECT (strftime('%s', 'now') - date_added) / 604800 AS weeks_ago, COUNT(*) FROM tracks \
+         WHERE date_added IS NOT NULL GROUP BY weeks_ago HAVING weeks_ago < ?1"
+    ).expect("Failed to prepare activity statement");
+    let buckets: HashMap<i64, i64> = stmt
+        .query_map([weeks], |row| Ok((row.get(0)?, row.get(1)?)))
+        .expect("Failed to execute activity query")
+        .filter_map(Result::ok)
+        .collect();
+    let format = output_format();
+    if format != OutputFormat::Text {
+        let mut rows: Vec<ActivityWeek> = (0..weeks)
+            .rev()
+            .map(|weeks_ago| ActivityWeek { weeks_ago: Some(weeks_ago), tracks: buckets.get(&weeks_ago).copied().unwrap_or(0) })
+            .collect();
+        if unknown > 0 {
+            rows.push(ActivityWeek { weeks_ago: None, tracks: unknown });
+        }
+        render(&rows, format);
+        return;
+    }
+
+    println!("\nActivity:");
+    println!("Added this week: {}", this_week);
+    println!("Added this month: {}", this_month);
+
+    let max_count = buckets.values().copied().max().unwrap_or(0);
+    println!("\nTracks added per week:");
+    for weeks_ago in (0..weeks).rev() {
+        let count = buckets.get(&weeks_ago).copied().unwrap_or(0);
+        let label = match weeks_ago {
+            0 => "this week".to_string(),
+            1 => "1 week ago".to_string(),
+            n => format!("{} weeks ago", n),
+        };
+        let bar_len = if max_count > 0 { (count * 40 / max_count) as usize } else { 0 };
+        println!("{:>12}: {:4} {}", label, count, "█".repeat(bar_len));
+    }
+    if unknown > 0 {
+        println!("{:>12}: {:4}", "unknown", unknown);
+    }
+}
+
+fn format_duration(secs: f64) -> String {
+    let months: f64 = secs / 2592000.0;
+    let weeks: f64 = secs / 604800.0;
+    let days: f64 = secs / 86400.0;
+    let hours: f64 = secs / 3600.0;
+    let minutes: f64 = secs / 60.0;
+    if months > 1.0 {
+        format!("{:.2} months", months)
+    } else if weeks > 1.0 {
+        format!("{:.2} weeks", weeks)
+    } else if days > 1.0 {
+        format!("{:.2} days", days)
+    } else if hours > 1.0 {
+        format!("{:.2} hours", hours)
+    } else if minutes > 1.0 {
+        format!("{:.2} minutes", minutes)
+    } else {
+        format!("{:.2} seconds", secs)
+    }
+}
+
+fn format_track_length(secs: i64) -> String {
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+// A track of Stats --longest/--shortest; duration is in seconds
+#[derive(Serialize)]
+struct TrackLength {
+    duration: i64,
+    artist: String,
+    title: String,
+}
+
+impl Renderable for TrackLength {
+    fn headers() -> &'static [&'static str] {
+        &["Duration", "Artist", "Title"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![self.duration.to_string(), self.artist.clone(), self.title.clone()]
+    }
+}
+
+fn print_tracks_by_length(db_path: &str, limit: usize, longest: bool) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+
+    // Zero durations haven't been probed yet, so they would crowd out the shortest list
+    let mut stmt = conn.prepare(&format!(
+        "SELECT duration, artist, title FROM tracks WHERE duration > 0 ORDER BY duration {} LIMIT ?1",
+        if longest { "DESC" } else { "ASC" }
+    )).expect("Failed to prepare length statement");
+    let tracks: Vec<TrackLength> = stmt
+        .query_map([limit as i64], |row| Ok(TrackLength { duration: row.get(0)?, artist: row.get(1)?, title: row.get(2)? }))
+        .expect("Failed to execute length query")
+        .filter_map(Result::ok)
+        .collect();
+    let format = output_format();
+    if format != OutputFormat::Text {
+        render(&tracks, format);
+        return;
+    }
+
+    println!("\n{} tracks:", if longest { "Longest" } else { "Shortest" });
+    let rows: Vec<Vec<String>> = tracks
+        .into_iter()
+        .map(|track| vec![format_track_length(track.duration), track.artist, track.title])
+        .collect();
+    if rows.is_empty() {
+        println!("{}", "No tracks found.".yellow());
+        return;
+    }
+    print_table(&["Length", "Artist", "Title"], &rows);
+}
+
+// A track of Stats --outliers; duration is in seconds
+#[derive(Serialize)]
+struct DurationOutlier {
+    duration: i64,
+    path: String,
+}
+
+impl Renderable for DurationOutlier {
+    fn headers() -> &'static [&'static str] {
+        &["Duration", "Path"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![self.duration.to_string(), self.path.clone()]
+    }
+}
+
+fn print_duration_outliers(db_path: &str, min_secs: i64, max_secs: i64) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+
+    // Zero durations haven't been probed yet, so they aren't reported as fragments
+    let mut stmt = conn.prepare(
+        "SELECT path, duration FROM tracks \
+         WHERE duration > 0 AND (duration < ?1 OR duration > ?2) \
+         ORDER BY duration"
+    ).expect("Failed to prepare outlier statement");
+    let outliers: Vec<DurationOutlier> = stmt
+        .query_map([min_secs, max_secs], |row| Ok(DurationOutlier { path: row.get(0)?, duration: row.get(1)? }))
+        .expect("Failed to execute outlier query")
+        .filter_map(Result::ok)
+        .collect();
+    let format = output_format();
+    if format != OutputFormat::Text {
+        render(&outliers, format);
+        return;
+    }
+
+    println!(
+        "\nDuration outliers (under {} or over {}):",
+        format_track_length(min_secs),
+        format_track_length(max_secs)
+    );
+    for outlier in &outliers {
+        let length = format!("{:>8}", format_track_length(outlier.duration));
+        let length = if outlier.duration < min_secs { length.yellow() } else { length.red() };
+        println!("  {} {}", length, outlier.path);
+    }
 
-        if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-            if ext == "mp3" || ext == "flac" || ext == "wav" || ext == "m4a" {
-                let mut path_str = path.to_string_lossy().to_string();
-
-                // Move file if pattern is set
-                if let Some(pattern) = file_pattern {
-                    let new_rel_path = generate_path_from_pattern(
-                        pattern,
-                        &artist,
-                        &albumartist,
-                        &album,
-                        &title,
-                        ext,
-                        &settings.replace,
-                    );
-                    let new_abs_path = std::path::Path::new(&music_dir).join(&new_rel_path);
-                    if new_abs_path != path {
-                        if dry_run {
-                            println!(
-                                "[dry-run] Would move:\n  from: {}\n  to:   {}",
-                                path.display(),
-                                new_abs_path.display()
-                            );
-                        } else {
-                            if let Some(parent) = new_abs_path.parent() {
-                                std::fs::create_dir_all(parent).ok();
-                            }
-                            std::fs::rename(path, &new_abs_path).ok();
-                        }
-                        path_str = new_abs_path.to_string_lossy().to_string();
-                    }
-                }
+    if outliers.is_empty() {
+        println!("{}", "No duration outliers found.".green());
+    }
+}
 
-                pb_clone.inc(1);
-                return Some((path_str, artist, albumartist, album, title, year, genre));
-            }
+fn get_duration_with_lofty(path: &std::path::Path) -> i64 {
+    match lofty::read_from_path(path) {
+        Ok(tagged_file) => {
+            tagged_file.properties().duration().as_secs() as i64
         }
-        pb_clone.inc(1);
-        None
-    }).collect();
-
-    // Stop the ticker thread
-    ticker_running.store(false, Ordering::Relaxed);
-    ticker_handle.join().ok();
+        Err(_) => 0,
+    }
+}
 
-    pb.finish_with_message("Metadata reading complete");
+// Audio bitrate in kbps, if the file can be read and reports one
+fn get_bitrate_with_lofty(path: &std::path::Path) -> Option<u32> {
+    lofty::read_from_path(path).ok()?.properties().audio_bitrate().filter(|&kbps| kbps > 0)
+}
 
-    // Batch insert all tracks into database
-    println!("Inserting {} tracks into database...", tracks.len());
-    let insert_pb = ProgressBar::new(tracks.len() as u64);
-    insert_pb.set_style(ProgressStyle::with_template("[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}")
-        .unwrap()
-        .progress_chars("##-"));
+// Separators tried in order when no explicit one is given
+const ARTIST_TITLE_SEPARATORS: &[&str] = &[" - ", " － ", " – ", " — ", ": "];
 
-    for (path_str, artist, albumartist, album, title, year, genre) in tracks {
-        let result = tx.execute(
-            "INSERT OR IGNORE INTO tracks (path, artist, albumartist, album, title, duration, year, genre) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
-            [
-                &path_str as &dyn rusqlite::ToSql,
-                &artist,
-                &albumartist,
-                &album,
-                &title,
-                &0.0 as &dyn rusqlite::ToSql,
-                &year,
-                &genre,
-            ]
-        );
-        if let Ok(1) = result {
-            insert_pb.set_message(format!("Added: {}", path_str));
-        }
-        insert_pb.inc(1);
+// Split an "Artist - Title.ext" filename into (artist, title). An explicit `separator` replaces
+// the detected ones so a batch with a known convention parses deterministically.
+fn extract_song_name_from_filename(filename: &str, separator: Option<&str>) -> Option<(String, String)> {
+    // Remove extension
+    let file_stem = std::path::Path::new(filename)
+        .file_stem()
+        .and_then(|s| s.to_str())?;
+    // Split on the first separator; everything after it is the title
+    let (artist, title) = match separator {
+        Some(separator) => file_stem.split_once(separator)?,
+        None => ARTIST_TITLE_SEPARATORS.iter().find_map(|sep| file_stem.split_once(sep))?,
+    };
+    let (artist, title) = (artist.trim(), title.trim());
+    if artist.is_empty() || title.is_empty() {
+        return None;
     }
-    insert_pb.finish_with_message("Database insertion complete");
+    Some((artist.to_string(), title.to_string()))
+}
 
-    // Clean up missing files from database
-    println!("Checking for missing files in database...");
-    let mut stmt = tx.prepare("SELECT path FROM tracks").expect("Failed to prepare select statement");
-    let mut rows = stmt.query([]).expect("Failed to query tracks");
+// Fill in empty artist/title tags from "Artist - Title" filenames
+fn tag_from_filename(db_path: &str, dry_run: bool, separator: Option<&str>) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
 
-    let mut to_remove = Vec::new();
-    while let Some(row) = rows.next().expect("Failed to fetch row") {
-        let path: String = row.get(0).expect("Failed to get path");
-        if !std::path::Path::new(&path).exists() {
-            to_remove.push(path);
+    let mut stmt = conn.prepare(
+        "SELECT path, artist, title FROM tracks WHERE artist = '' OR title = '' ORDER BY path"
+    ).expect("Failed to prepare statement");
+    let candidates: Vec<(String, String, String)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .expect("Failed to execute query")
+        .filter_map(Result::ok)
+        .collect();
+
+    let mut tagged = 0;
+    for (path, artist, title) in candidates {
+        let file_name = Path::new(&path).file_name().and_then(|f| f.to_str()).unwrap_or("");
+        let Some((parsed_artist, parsed_title)) = extract_song_name_from_filename(file_name, separator) else {
+            println!("{}", format!("Skipping {}: filename is not 'Artist - Title'", path).yellow());
+            continue;
+        };
+        // Only fill fields that are currently empty so existing tags are never overwritten
+        let new_artist = if artist.is_empty() { parsed_artist } else { artist };
+        let new_title = if title.is_empty() { parsed_title } else { title };
+
+        if dry_run {
+            println!("[dry-run] {}\n  artist: {}\n  title:  {}", path, new_artist, new_title);
+            continue;
         }
-    }
-    drop(rows);
-    drop(stmt);
 
-    for path in &to_remove {
-        println!("Removing missing file from database: {}", path);
-        tx.execute("DELETE FROM tracks WHERE path = ?1", [path]).ok();
+        let result = write_artist_title_tags(Path::new(&path), &new_artist, &new_title);
+        log_mutation("tag", &[Path::new(&path)], &result);
+        if let Err(e) = result {
+            eprintln!("{}", format!("Failed to tag {}: {}", path, e).red());
+            continue;
+        }
+        conn.execute(
+            "UPDATE tracks SET artist = ?1, title = ?2 WHERE path = ?3",
+            [&new_artist, &new_title, &path],
+        ).expect("Failed to update track");
+        println!("Tagged {}: {} - {}", path, new_artist, new_title);
+        tagged += 1;
     }
-    if !to_remove.is_empty() {
-        println!("Removed {} missing files from database", to_remove.len());
+
+    if !dry_run {
+        println!("{}", format!("Tagged {} file(s).", tagged).green());
     }
+}
 
-    tx.commit().expect("Failed to commit transaction");
+fn write_artist_title_tags(path: &Path, artist: &str, title: &str) -> lofty::error::Result<()> {
+    use lofty::tag::{Accessor, Tag, TagExt};
+
+    let mut tagged_file = lofty::probe::Probe::open(path)?.guess_file_type()?.read()?;
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+    let tag = tagged_file.primary_tag_mut().expect("primary tag was just inserted");
+    if tag.artist().is_none_or(|a| a.is_empty()) {
+        tag.set_artist(artist.to_string());
+    }
+    if tag.title().is_none_or(|t| t.is_empty()) {
+        tag.set_title(title.to_string());
+    }
+    tag.save_to_path(path, lofty::config::WriteOptions::default())
 }
 
-fn find_duplicates(db_path: &str, fix: bool) {
-    let db_path = expand_tilde(db_path);
-    let conn = rusqlite::Connection::open(db_path).expect("Failed to open database");
+// Album-level fields Doctor --album-consistency compares across an album's tracks
+const ALBUM_CONSISTENCY_FIELDS: &[&str] = &["albumartist", "year", "genre"];
 
-    // Create table to track duplicates the user wants to keep
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS kept_duplicates (
-            id INTEGER PRIMARY KEY,
-            artist TEXT NOT NULL,
-            title TEXT NOT NULL,
-            UNIQUE(artist, title)
-        )",
-        [],
-    ).expect("Failed to create kept_duplicates table");
+// A track's path and its ALBUM_CONSISTENCY_FIELDS values
+type AlbumFieldRow = (String, [String; 3]);
 
-    let mut stmt = conn.prepare(
-        "SELECT artist, title, COUNT(*) as count FROM tracks \
-         WHERE artist != '' AND title != '' \
-         GROUP BY artist, title HAVING count > 1",
-    ).expect("Failed to prepare statement");
+// Set (field, value) pairs in the file's primary tag and save it once. Fields are tracks
+// columns: artist, albumartist, album, title, year or genre.
+fn write_tag_fields(path: &Path, fields: &[(&str, &str)]) -> lofty::error::Result<()> {
+    use lofty::tag::{Accessor, Tag, TagExt};
 
-    let mut rows = stmt.query([]).expect("Failed to execute query");
+    let mut tagged_file = lofty::probe::Probe::open(path)?.guess_file_type()?.read()?;
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+    let tag = tagged_file.primary_tag_mut().expect("primary tag was just inserted");
+    for &(field, value) in fields {
+        match field {
+            "artist" => tag.set_artist(value.to_string()),
+            "album" => tag.set_album(value.to_string()),
+            "title" => tag.set_title(value.to_string()),
+            "albumartist" => {
+                tag.insert_text(ItemKey::AlbumArtist, value.to_string());
+            }
+            "year" => {
+                if let Ok(year) = value.parse() {
+                    tag.set_year(year);
+                }
+            }
+            "genre" => tag.set_genre(value.to_string()),
+            _ => {}
+        }
+    }
+    tag.save_to_path(path, lofty::config::WriteOptions::default())
+}
 
-    let mut found_duplicates = false;
-    while let Some(row) = rows.next().expect("Failed to fetch row") {
-        found_duplicates = true;
-        let artist: String = row.get(0).expect("Failed to get artist");
-        let title: String = row.get(1).expect("Failed to get title");
-        let count: i32 = row.get(2).expect("Failed to get count");
+// Flag files whose audio properties fall below the [quality] floors: lossy files encoded at a low
+// bitrate, and lossless files with a sample rate or bit depth that points at a poor source.
+// Upsampled lossy audio inside a lossless container needs spectral analysis and isn't detected.
+// Returns whether any file was flagged.
+fn check_low_quality(db_path: &str, quality: &QualityConfig) -> bool {
+    let min_bitrate: HashMap<String, u32> = match &quality.min_bitrate {
+        Some(floors) => floors.iter().map(|(format, kbps)| (format.to_lowercase(), *kbps)).collect(),
+        None => DEFAULT_MIN_BITRATES.iter().map(|(format, kbps)| (format.to_string(), *kbps)).collect(),
+    };
+    let min_sample_rate = quality.min_sample_rate.unwrap_or(44100);
+    let min_bit_depth = quality.min_bit_depth.unwrap_or(16);
 
-        // Check if this duplicate is marked as "keep both"
-        let is_kept: bool = conn.query_row(
-            "SELECT 1 FROM kept_duplicates WHERE artist = ?1 AND title = ?2",
-            [&artist, &title],
-            |_| Ok(true)
-        ).unwrap_or(false);
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+    let mut stmt = conn.prepare("SELECT path, LOWER(COALESCE(real_format, ext, '')) FROM tracks ORDER BY path")
+        .expect("Failed to prepare statement");
+    let tracks: Vec<(String, String)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .expect("Failed to execute query")
+        .filter_map(Result::ok)
+        .collect();
 
-        let keep_tag = if is_kept { "[Keep All] ".green() } else { "".green() };
-        println!("{}{} {}", keep_tag, format!("{} - {}", artist, title).cyan(),format!("(x{})", count).yellow());
+    let pb = make_progress_bar(tracks.len());
+    let flagged: Vec<Vec<String>> = tracks
+        .par_iter()
+        .filter_map(|(path, format)| {
+            pb.inc(1);
+            let tagged_file = lofty::read_from_path(path).ok()?;
+            let properties = tagged_file.properties();
+            let bitrate = properties.audio_bitrate().filter(|&kbps| kbps > 0);
+            let sample_rate = properties.sample_rate();
+            let mut problems = Vec::new();
+            if let (Some(kbps), Some(floor)) = (bitrate, min_bitrate.get(format))
+                && kbps < *floor
+            {
+                problems.push(format!("bitrate under {}k", floor));
+            }
+            if sample_rate.is_some_and(|hz| hz < min_sample_rate) {
+                problems.push(format!("sample rate under {} Hz", min_sample_rate));
+            }
+            if LOSSLESS_FORMATS.contains(&format.as_str()) && properties.bit_depth().is_some_and(|bits| bits < min_bit_depth) {
+                problems.push(format!("bit depth under {}", min_bit_depth));
+            }
+            (!problems.is_empty()).then(|| vec![
+                path.clone(),
+                format.to_uppercase(),
+                bitrate.map(|kbps| format!("{}k", kbps)).unwrap_or_else(|| "?".to_string()),
+                sample_rate.map(|hz| hz.to_string()).unwrap_or_else(|| "?".to_string()),
+                problems.join(", "),
+            ])
+        })
+        .collect();
+    pb.finish_and_clear();
 
-        // Query for file paths of this duplicate track
-        let mut path_stmt = conn.prepare(
-            "SELECT id, path FROM tracks WHERE artist = ?1 AND title = ?2"
-        ).expect("Failed to prepare path statement");
+    if flagged.is_empty() {
+        println!("{}", "No low-quality files found.".green());
+        return false;
+    }
+    print_table(&["Path", "Format", "Bitrate", "Sample rate", "Problem"], &flagged);
+    println!("{}", format!("{} low-quality file(s).", flagged.len()).yellow());
+    true
+}
 
-        let mut path_rows = path_stmt.query([&artist, &title]).expect("Failed to execute path query");
-        let mut paths = Vec::new();
-        while let Some(path_row) = path_rows.next().expect("Failed to fetch path row") {
-            let id: i64 = path_row.get(0).expect("Failed to get id");
-            let path: String = path_row.get(1).expect("Failed to get path");
-            println!("  {}", path);
-            paths.push((id, path));
-        }
+// Tracks form an album when they share an album title and folder, since the album artist is
+// one of the fields being compared. Returns whether any album disagreed.
+fn check_album_consistency(db_path: &str, fix: bool) -> bool {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
 
-        if fix && paths.len() > 1 && !is_kept {
-            // Make "Skip" and "Keep both" the first options
-            let mut options: Vec<String> = vec!["Skip".to_string(), "Keep both".to_string()];
-            options.extend(paths.iter().map(|(_, p)| p.clone()));
-            match inquire::Select::new(
-                &format!("Which file do you want to keep for '{} - {}'?", artist, title),
-                options.clone(),
-            ).prompt() {
-                Ok(selected) if selected != "Skip" && selected != "Keep both" => {
-                    // Remove all except the selected one
-                    for (id, path) in &paths {
-                        if path != &selected {
-                            // Delete from database
-                            conn.execute("DELETE FROM tracks WHERE id = ?1", [id]).expect("Failed to delete duplicate");
-                            println!("  Removed duplicate from database: {}", path);
-                            // Delete from filesystem
-                            match std::fs::remove_file(path) {
-                                Ok(_) => println!("  Deleted file from filesystem: {}", path),
-                                Err(e) => eprintln!("  Failed to delete file '{}': {}", path, e),
-                            }
-                        }
-                    }
-                }
-                Ok(selected) if selected == "Keep both" => {
-                    conn.execute(
-                        "INSERT OR IGNORE INTO kept_duplicates (artist, title) VALUES (?1, ?2)",
-                        [&artist, &title],
-                    ).expect("Failed to save kept duplicate");
-                    println!("  Keeping all copies of '{} - {}' (won't show again)", artist, title);
+    let mut stmt = conn.prepare(
+        "SELECT path, album, COALESCE(albumartist, ''), COALESCE(CAST(NULLIF(year, 0) AS TEXT), ''), COALESCE(genre, '') \
+         FROM tracks WHERE COALESCE(album, '') != '' ORDER BY path"
+    ).expect("Failed to prepare statement");
+    let mut albums: std::collections::BTreeMap<(String, String), Vec<AlbumFieldRow>> = std::collections::BTreeMap::new();
+    for (path, album, albumartist, year, genre) in stmt
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get(2)?, row.get(3)?, row.get(4)?)))
+        .expect("Failed to execute query")
+        .filter_map(Result::ok)
+    {
+        let folder = Path::new(&path).parent().map(|p| p.to_string_lossy().to_string()).unwrap_or_default();
+        albums.entry((folder, album)).or_default().push((path, [albumartist, year, genre]));
+    }
+
+    let mut inconsistent = 0;
+    let mut fixed = 0;
+    for ((folder, album), tracks) in &albums {
+        for (i, field) in ALBUM_CONSISTENCY_FIELDS.iter().enumerate() {
+            // Distinct values with their counts, most common first
+            let mut counts: Vec<(&str, usize)> = Vec::new();
+            for (_, values) in tracks {
+                match counts.iter_mut().find(|(v, _)| *v == values[i]) {
+                    Some((_, count)) => *count += 1,
+                    None => counts.push((&values[i], 1)),
                 }
-                Ok(_) | Err(_) => {
-                    println!("  Skipped fixing '{} - {}'", artist, title);
+            }
+            if counts.len() < 2 {
+                continue;
+            }
+            counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+            inconsistent += 1;
+
+            let spellings: Vec<String> = counts.iter()
+                .map(|(v, n)| format!("{} ({})", if v.is_empty() { "<empty>" } else { v }, n))
+                .collect();
+            println!(
+                "{} has {} {} values: {}",
+                format!("Album '{}' in {}", album, folder).cyan(),
+                counts.len(),
+                field,
+                spellings.join(", ")
+            );
+
+            let Some((target, _)) = counts.iter().find(|(v, _)| !v.is_empty()) else {
+                continue;
+            };
+            if !fix {
+                continue;
+            }
+            for (path, values) in tracks.iter().filter(|(_, values)| values[i] != *target) {
+                let result = write_tag_fields(Path::new(path), &[(field, target)]);
+                log_mutation("tag", &[Path::new(path)], &result);
+                match result {
+                    Ok(()) => {
+                        conn.execute(&format!("UPDATE tracks SET {} = ?1 WHERE path = ?2", field), [target, &path.as_str()])
+                            .expect("Failed to update track");
+                        println!("  Set {} of {} to {} (was {})", field, path, target, if values[i].is_empty() { "<empty>" } else { &values[i] });
+                        fixed += 1;
+                    }
+                    Err(e) => eprintln!("{}", format!("  Failed to tag {}: {}", path, e).red()),
                 }
             }
         }
     }
 
-    if !found_duplicates {
-        println!("{}", "No duplicate tracks found.".green());
+    if inconsistent == 0 {
+        println!("{}", "All albums have consistent album artist, year and genre tags.".green());
+    } else if fix {
+        println!("{}", format!("Fixed {} track(s) across {} inconsistent field(s).", fixed, inconsistent).green());
+    } else {
+        println!("{}", format!("{} inconsistent album field(s); pass --fix to apply the most common values.", inconsistent).yellow());
     }
+    inconsistent > 0
+}
 
-    // Identify tracks where a lower quality version exists (FLAC > M4A > MP3)
-    println!("\nTracks with lower quality duplicates (FLAC > M4A > MP3):");
+// Text fields Normalize cleans; the_prefix only applies to the artist ones
+const NORMALIZE_FIELDS: &[&str] = &["artist", "albumartist", "album", "title", "genre"];
 
-    let mut stmt = conn.prepare(
-        "SELECT artist, title, GROUP_CONCAT(path) as paths FROM tracks \
-         WHERE artist != '' AND title != '' \
-         GROUP BY artist, title HAVING COUNT(*) > 1"
-    ).expect("Failed to prepare statement for quality check");
+// Separators left over from sloppy filename-to-tag conversions
+const JUNK_CHARS: &[char] = &['-', '_', '|', '~', ',', ';', ':'];
 
-    let mut rows = stmt.query([]).expect("Failed to execute quality check query");
+// Noise removed from titles when title stripping is on. The track number needs a "-" or "."
+// after it so titles such as "99 Luftballons" survive.
+const TITLE_NOISE_PATTERNS: &[&str] = &[
+    r"^\d{1,3}\s*[-.]\s*",
+    r"\s*\[[^\]]*\]\s*$",
+    r"(?i)\s*\(official[^)]*\)\s*$",
+];
 
-    let mut found_quality_dupes = false;
-    while let Some(row) = rows.next().expect("Failed to fetch row") {
-        let artist: String = row.get(0).expect("Failed to get artist");
-        let title: String = row.get(1).expect("Failed to get title");
-        let paths: String = row.get(2).expect("Failed to get paths");
-        let files: Vec<&str> = paths.split(',').collect();
+// Built-in plus configured title noise patterns, or none when stripping is off
+fn title_noise_regexes(rules: &NormalizeConfig, strip_title_noise: bool) -> Result<Vec<regex::Regex>, String> {
+    if !(strip_title_noise || rules.strip_title_noise) {
+        return Ok(Vec::new());
+    }
+    TITLE_NOISE_PATTERNS
+        .iter()
+        .copied()
+        .chain(rules.title_noise_patterns.iter().map(String::as_str))
+        .map(|pattern| {
+            regex::Regex::new(pattern).map_err(|e| format!("Invalid title_noise_patterns entry '{}': {}", pattern, e))
+        })
+        .collect()
+}
 
-        // Map extensions to quality rank (lower is better)
-        fn quality_rank(ext: &str) -> u8 {
-            match ext.to_lowercase().as_str() {
-                "flac" => 1,
-                "m4a" => 2,
-                "mp3" => 3,
-                _ => 100,
-            }
+// UTF-8 text that was decoded as CP1252 (or Latin-1) and saved again, possibly more than once.
+// Encoding back to CP1252 only yields valid UTF-8 for such text: a genuine "é" becomes the lone
+// byte 0xE9, which isn't, so correctly encoded tags are left alone.
+fn repair_mojibake(value: &str) -> Option<String> {
+    let mut repaired = value.to_string();
+    while !repaired.is_ascii() {
+        let (bytes, _, unmappable) = encoding_rs::WINDOWS_1252.encode(&repaired);
+        if unmappable {
+            break;
         }
+        match String::from_utf8(bytes.into_owned()) {
+            Ok(decoded) if decoded != repaired => repaired = decoded,
+            _ => break,
+        }
+    }
+    (repaired != value).then_some(repaired)
+}
 
-        let mut qualities: Vec<(u8, &str)> = files.iter()
-            .filter_map(|p| {
-                std::path::Path::new(p)
-                    .extension()
-                    .and_then(|e| e.to_str())
-                    .map(|ext| (quality_rank(ext), *p))
+// A "feat."/"ft."/"featuring" credit as a whole word, so "Defeated" or "Feather" don't count
+fn featuring_regex() -> &'static regex::Regex {
+    static FEATURING: OnceLock<regex::Regex> = OnceLock::new();
+    FEATURING.get_or_init(|| {
+        regex::Regex::new(r"(?i)(^|[\s(\[])(?:featuring|feat|ft)\.?(\s)").expect("valid featuring regex")
+    })
+}
+
+// The guests a title already credits, e.g. "Guest" in "Song (feat. Guest)"
+fn title_featured_guests(title: &str) -> Option<&str> {
+    let credit = featuring_regex().find(title)?;
+    let rest = &title[credit.end()..];
+    let guests = rest.split([')', ']']).next().unwrap_or(rest).trim();
+    (!guests.is_empty()).then_some(guests)
+}
+
+fn normalize_value(
+    value: &str,
+    field: &str,
+    rules: &NormalizeConfig,
+    the_prefix: ThePrefix,
+    title_noise: &[regex::Regex],
+    fix_encoding: bool,
+) -> String {
+    let mut value = value.to_string();
+    if fix_encoding && let Some(repaired) = repair_mojibake(&value) {
+        value = repaired;
+    }
+    if rules.collapse_spaces {
+        value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+    }
+    if rules.trim {
+        value = value.trim().to_string();
+    }
+    if field == "title" && !title_noise.is_empty() {
+        let stripped = title_noise
+            .iter()
+            .fold(value.clone(), |title, noise| noise.replace_all(&title, "").into_owned());
+        // A title that is nothing but noise is left alone rather than emptied
+        if !stripped.trim().is_empty() {
+            value = stripped.trim().to_string();
+        }
+    }
+    if rules.strip_junk {
+        value = value.trim_matches(|c: char| JUNK_CHARS.contains(&c) || c.is_whitespace()).to_string();
+    }
+    if rules.featuring && matches!(field, "artist" | "albumartist" | "title") {
+        value = featuring_regex().replace_all(&value, "${1}feat.${2}").to_string();
+    }
+    if matches!(field, "artist" | "albumartist") {
+        let leading_the = value.get(..4).is_some_and(|p| p.eq_ignore_ascii_case("the ")) && value.len() > 4;
+        let trailing_the = value.len().checked_sub(5)
+            .filter(|&split| split > 0 && value.get(split..).is_some_and(|s| s.eq_ignore_ascii_case(", the")));
+        value = match (the_prefix, trailing_the) {
+            (ThePrefix::Strip, _) if leading_the => value[4..].to_string(),
+            (ThePrefix::Suffix, _) if leading_the => format!("{}, {}", &value[4..], &value[..3]),
+            (ThePrefix::Prefix, Some(split)) => format!("{} {}", &value[split + 2..], &value[..split]),
+            _ => value,
+        };
+    }
+    value
+}
+
+fn normalize_tags(settings: &Settings, dry_run: bool, yes: bool, strip_title_noise: bool, fix_encoding: bool, canonicalize_feat: bool) {
+    let rules = &settings.normalize;
+    let exceptions = settings.artist_exceptions.as_deref().unwrap_or_default();
+    let the_prefix = ThePrefix::from_config(rules.the_prefix.as_deref())
+        .unwrap_or_else(|e| exit_with_error(EXIT_CONFIG, &e));
+    let title_noise = title_noise_regexes(rules, strip_title_noise)
+        .unwrap_or_else(|e| exit_with_error(EXIT_CONFIG, &e));
+    let fix_encoding = fix_encoding || rules.fix_encoding;
+    let db_path = expand_tilde(&settings.files.database_name);
+    let conn = open_db(&db_path);
+
+    let mut stmt = conn.prepare(&format!("SELECT path, {} FROM tracks ORDER BY path", NORMALIZE_FIELDS.join(", ")))
+        .expect("Failed to prepare statement");
+    let tracks: Vec<(String, Vec<String>)> = stmt
+        .query_map([], |row| {
+            let values = (1..=NORMALIZE_FIELDS.len())
+                .map(|i| row.get::<_, Option<String>>(i).map(Option::unwrap_or_default))
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            Ok((row.get(0)?, values))
+        })
+        .expect("Failed to execute query")
+        .filter_map(Result::ok)
+        .collect();
+
+    let (mut proposed, mut applied) = (0, 0);
+    let field_index = |name: &str| NORMALIZE_FIELDS.iter().position(|field| *field == name).expect("a normalized field");
+    let (artist_field, title_field) = (field_index("artist"), field_index("title"));
+    for (path, values) in tracks {
+        let mut cleaned = values.clone();
+        if canonicalize_feat
+            && let Some((primary, guests)) = split_featured_artist(&values[artist_field], exceptions)
+        {
+            // The guests leave the artist only once the title credits them
+            let title = &values[title_field];
+            match title_featured_guests(title) {
+                None => {
+                    cleaned[title_field] = format!("{} (feat. {})", title.trim(), guests);
+                    cleaned[artist_field] = primary.to_string();
+                }
+                Some(credited) if credited.eq_ignore_ascii_case(guests) => cleaned[artist_field] = primary.to_string(),
+                // The title credits someone else; moving the guests would lose one of the credits
+                Some(_) => {}
+            }
+        }
+        let changes: Vec<(&str, &String, String)> = NORMALIZE_FIELDS
+            .iter()
+            .zip(values.iter().zip(&cleaned))
+            .filter_map(|(field, (old, cleaned))| {
+                let new = normalize_value(cleaned, field, rules, the_prefix, &title_noise, fix_encoding);
+                (new != *old).then_some((*field, old, new))
             })
             .collect();
+        if changes.is_empty() {
+            continue;
+        }
+        proposed += 1;
 
-        qualities.sort_by_key(|q| q.0);
+        println!("{}", path.cyan());
+        for (field, old, new) in &changes {
+            println!("  {:<12} '{}' -> '{}'", field, old, new);
+        }
+        if dry_run {
+            continue;
+        }
+        if !yes {
+            match inquire::Confirm::new("Apply these changes?").with_default(true).prompt() {
+                Ok(true) => {}
+                Ok(false) => continue,
+                // Esc or Ctrl-C stops the run; what was applied so far stays
+                Err(_) => break,
+            }
+        }
 
-        // If there are at least two files and the best quality is not the only one
-        if qualities.len() > 1 && qualities[0].0 < qualities[1].0 {
-            found_quality_dupes = true;
-            println!("{}", format!("{} - {}", artist, title).cyan());
-            for (rank, path) in &qualities {
-                let label = match rank {
-                    1 => "FLAC",
-                    2 => "M4A",
-                    3 => "MP3",
-                    _ => "OTHER",
-                };
-                println!("  [{}] {}", label, path);
+        let fields: Vec<(&str, &str)> = changes.iter().map(|(field, _, new)| (*field, new.as_str())).collect();
+        let result = write_tag_fields(Path::new(&path), &fields);
+        log_mutation("tag", &[Path::new(&path)], &result);
+        match result {
+            Ok(()) => {
+                for (field, value) in &fields {
+                    conn.execute(&format!("UPDATE tracks SET {} = ?1 WHERE path = ?2", field), [value, &path.as_str()])
+                        .expect("Failed to update track");
+                }
+                applied += 1;
             }
+            Err(e) => eprintln!("{}", format!("  Failed to tag {}: {}", path, e).red()),
         }
     }
 
-    if !found_quality_dupes {
-        println!("{}", "No lower quality duplicates found.".green());
+    if proposed == 0 {
+        println!("{}", "All tags are already normalized.".green());
+    } else if dry_run {
+        println!("{}", format!("Would normalize {} track(s).", proposed).green());
+    } else {
+        println!("{}", format!("Normalized {} of {} track(s).", applied, proposed).green());
     }
 }
 
-fn load_settings() -> Settings {
-    let config_path = expand_tilde("~/.config/apollo-music/config.toml");
-    app_config::Config::builder()
-        .add_source(app_config::File::with_name(&config_path))
-        .add_source(app_config::Environment::with_prefix("APP"))
-        .build()
-        .unwrap()
-        .try_deserialize()
-        .unwrap()
-}
-
-fn index_playlists(music_dir: &str, db_path: &str) {
-    // loads and indexes .m3u or .m3u8 playlists in the given directory and stores them in a database
-    // create or open the database
-    let db_path = expand_tilde(&db_path);
-    let mut conn = rusqlite::Connection::open(&db_path).expect("Failed to open database");
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS playlists (
-            id INTEGER PRIMARY KEY,
-            name TEXT NOT NULL,
-            path TEXT NOT NULL UNIQUE
-        )",
-        [],
-    ).expect("Failed to create playlists table");
+// Remove tracks at or below either threshold. With a trash_directory configured the files are
+// moved there instead of deleted, so a bad threshold can be undone from the mutation log.
+fn trim_tracks(settings: &Settings, max_size: Option<u64>, max_duration: Option<u64>, dry_run: bool, yes: bool) {
+    let music_dir = expand_tilde(&settings.files.music_directory);
+    let trash_dir = settings.files.trash_directory.as_deref().map(expand_tilde);
+    let db_path = expand_tilde(&settings.files.database_name);
+    let conn = open_db(&db_path);
 
-    let tx = conn.transaction().expect("Failed to start transaction");
+    let mut stmt = conn.prepare(
+        "SELECT id, path, COALESCE(size, 0), COALESCE(duration, 0) FROM tracks \
+         WHERE (?1 IS NOT NULL AND size <= ?1) OR (?2 IS NOT NULL AND duration > 0 AND duration <= ?2) \
+         ORDER BY path"
+    ).expect("Failed to prepare statement");
+    let candidates: Vec<(i64, String, i64, i64)> = stmt
+        .query_map(rusqlite::params![max_size.map(|n| n as i64), max_duration.map(|n| n as i64)], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })
+        .expect("Failed to execute query")
+        .filter_map(Result::ok)
+        .collect();
 
-    // Remove playlists from the database that no longer exist on the filesystem
-    let mut stmt = tx.prepare("SELECT path FROM playlists").expect("Failed to prepare select statement");
-    let mut rows = stmt.query([]).expect("Failed to query playlists");
+    if candidates.is_empty() {
+        println!("{}", "No tracks below the given thresholds.".green());
+        return;
+    }
+    let rows: Vec<Vec<String>> = candidates
+        .iter()
+        .map(|(_, path, size, duration)| {
+            let duration = if *duration > 0 { format!("{}s", duration) } else { "?".to_string() };
+            vec![format_bytes(*size as f64), duration, path.clone()]
+        })
+        .collect();
+    print_table(&["Size", "Duration", "Path"], &rows);
 
-    let mut to_remove = Vec::new();
-    while let Some(row) = rows.next().expect("Failed to fetch row") {
-        let path: String = row.get(0).expect("Failed to get path");
-        if !std::path::Path::new(&path).exists() {
-            to_remove.push(path);
+    let action = if trash_dir.is_some() { "Move" } else { "Delete" };
+    if dry_run {
+        println!("{}", format!("Would remove {} track(s).", candidates.len()).green());
+        return;
+    }
+    if !yes {
+        let question = format!("{} these {} track(s)?", action, candidates.len());
+        if !inquire::Confirm::new(&question).with_default(false).prompt().unwrap_or(false) {
+            println!("Nothing removed.");
+            return;
         }
     }
-    drop(rows);
-    drop(stmt);
 
-    for path in to_remove {
-        println!("Removing missing playlist from database: {}", path);
-        tx.execute("DELETE FROM playlists WHERE path = ?1", [&path]).ok();
+    let mut removed = 0;
+    for (id, path, _, _) in &candidates {
+        let path = Path::new(path);
+        let result = match &trash_dir {
+            Some(trash_dir) => {
+                let rel_path = path.strip_prefix(&music_dir).unwrap_or(path.file_name().map_or(path, Path::new));
+                let trashed = Path::new(trash_dir).join(rel_path);
+                let result = place_file(path, &trashed, OrganizeMode::Move);
+                log_mutation("trash", &[path, &trashed], &result);
+                result
+            }
+            None => {
+                let result = fs::remove_file(path);
+                log_mutation("delete", &[path], &result);
+                result
+            }
+        };
+        // A file that is already gone only needs its row removed
+        match result {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                eprintln!("{}", format!("  Failed to remove {}: {}", path.display(), e).red());
+            }
+            _ => {
+                conn.execute("DELETE FROM tracks WHERE id = ?1", [id]).expect("Failed to delete track");
+                println!("  Removed {}", path.display());
+                removed += 1;
+            }
+        }
     }
+    println!("{}", format!("Removed {} of {} track(s).", removed, candidates.len()).green());
+}
 
-    println!("Indexing playlists in directory: {}", music_dir);
+// Folder images used by EmbedArt, in order of preference (matched case-insensitively)
+const COVER_FILE_NAMES: &[&str] = &["cover.jpg", "folder.jpg"];
+
+fn folder_cover(dir: &Path) -> Option<PathBuf> {
+    let files: Vec<PathBuf> = fs::read_dir(dir).ok()?.filter_map(Result::ok).map(|e| e.path()).collect();
+    COVER_FILE_NAMES.iter().find_map(|name| {
+        files
+            .iter()
+            .find(|f| f.is_file() && f.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.eq_ignore_ascii_case(name)))
+            .cloned()
+    })
+}
 
-    // Load all tracks once to avoid repeated database queries for missing file suggestions
-    // This significantly improves performance when dealing with playlists that have missing files
-    let all_tracks: Vec<(String, String)> = {
-        let tracks_conn = rusqlite::Connection::open(&db_path).expect("Failed to open database for tracks");
-        let mut stmt = tracks_conn.prepare("SELECT title, path FROM tracks").expect("Failed to prepare statement");
-        let mut rows = stmt.query([]).expect("Failed to execute query");
-        let mut tracks = Vec::new();
-        while let Some(row) = rows.next().expect("Failed to fetch row") {
-            let title: String = row.get(0).expect("Failed to get title");
-            let path: String = row.get(1).expect("Failed to get path");
-            tracks.push((title, path));
-        }
-        tracks
+fn has_embedded_art(path: &Path) -> bool {
+    lofty::read_from_path(path)
+        .map(|f| f.tags().iter().any(|tag| !tag.pictures().is_empty()))
+        .unwrap_or(false)
+}
+
+// Store `cover` as the front cover, replacing any existing one
+fn write_front_cover(path: &Path, cover: &[u8]) -> lofty::error::Result<()> {
+    use lofty::picture::{Picture, PictureType};
+    use lofty::tag::{Tag, TagExt, TagType};
+
+    let mut tagged_file = lofty::probe::Probe::open(path)?.guess_file_type()?.read()?;
+    // RIFF INFO chunks can't hold pictures; WAV files carry art in an ID3v2 tag instead
+    let tag_type = match tagged_file.primary_tag_type() {
+        TagType::RiffInfo => TagType::Id3v2,
+        tag_type => tag_type,
     };
+    if tagged_file.tag(tag_type).is_none() {
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+    let tag = tagged_file.tag_mut(tag_type).expect("tag was just inserted");
+    let mut picture = Picture::from_reader(&mut &cover[..])?;
+    picture.set_pic_type(PictureType::CoverFront);
+    tag.remove_picture_type(PictureType::CoverFront);
+    tag.push_picture(picture);
+    tag.save_to_path(path, lofty::config::WriteOptions::default())
+}
 
-    for entry in walkdir::WalkDir::new(&music_dir)
-        .into_iter()
+fn embed_folder_art(db_path: &str, dry_run: bool, force: bool) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+
+    let mut stmt = conn.prepare("SELECT path FROM tracks ORDER BY path").expect("Failed to prepare statement");
+    let paths: Vec<String> = stmt
+        .query_map([], |row| row.get(0))
+        .expect("Failed to execute query")
         .filter_map(Result::ok)
-        .filter(|e| e.file_type().is_file())
-    {
-        let path = entry.path();
-        if let Some(ext) = path.extension() {
-            if ext == "m3u" || ext == "m3u8" {
-                let path_str = path.to_string_lossy();
-                let name = path.file_stem().unwrap_or_default().to_string_lossy();
-                tx.execute(
-                    "INSERT OR IGNORE INTO playlists (name, path) VALUES (?1, ?2)",
-                    [&name as &dyn rusqlite::ToSql, &path_str]
-                ).ok();
-
-                // Check for missing files in the playlist
-                if let Ok(content) = std::fs::read_to_string(path) {
-                    let playlist_dir = path.parent().unwrap_or_else(|| std::path::Path::new(""));
-                    for line in content.lines() {
-                        let trimmed = line.trim();
-                        if trimmed.is_empty() || trimmed.starts_with('#') {
-                            continue;
-                        }
-                        // Handle relative and absolute paths
-                        let song_path = if std::path::Path::new(trimmed).is_absolute() {
-                            std::path::PathBuf::from(trimmed)
-                        } else {
-                            playlist_dir.join(trimmed)
-                        };
-                        if !song_path.exists() {
-                            println!(
-                                "Missing file in playlist '{}': {}",
-                                name,
-                                song_path.display()
-                            );
-
-                            // Suggest similar files in the music directory
-                            let song_file_name = song_path.file_name().and_then(|f| f.to_str()).unwrap_or("");
-                            let song_name = extract_song_name_from_filename(song_file_name)
-                                .unwrap_or_else(|| song_file_name.to_string());
-                            println!("  Suggested song name: {}", song_name);
-                            if !song_file_name.is_empty() {
-                                // Use cached tracks instead of opening a new connection
-                                let mut suggestions = Vec::new();
-                                for (candidate_title, candidate_path) in &all_tracks {
-                                    let score = strsim::jaro(candidate_title, &song_name);
-                                    suggestions.push((score, candidate_path.clone()));
-                                }
-                                // Sort by descending similarity score and take top 5
-                                suggestions.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
-                                let top_suggestions: Vec<_> = suggestions.into_iter().take(5).collect();
-                                if !top_suggestions.is_empty() {
-                                    let mut options: Vec<String> = top_suggestions
-                                        .iter()
-                                        .map(|(score, suggestion)| format!("({:.3}) {} ", score, suggestion))
-                                        .collect();
-                                    options.push("Remove".to_string());
-                                    options.push("Skip".to_string());
-
-                                    // Auto-replace if top suggestion is very similar
-                                    let (top_score, top_path) = &top_suggestions[0];
-                                    if *top_score >= 0.9 {
-                                        println!("  Auto-replacing '{}' with '{}' (similarity {:.3})", song_path.display(), top_path, top_score);
-                                        update_playlist_line(&path_str, &song_path.display().to_string(), top_path).expect("Failed to update playlist");
-                                    } else {
-                                        // Use inquire to let user select a replacement or skip
-                                        match inquire::Select::new(
-                                            &format!("Select a replacement for '{}':", song_file_name),
-                                            options.clone(),
-                                        ).prompt() {
-                                            Ok(selected) if selected != "Skip" && selected != "Remove" => {
-                                                // Extract the path from the selected option (before the space)
-                                                // Extract the path from the selected option: format is "(score) path"
-                                                let selected_path = selected
-                                                    .splitn(2, ')')
-                                                    .nth(1)
-                                                    .map(|s| s.trim())
-                                                    .unwrap_or(&selected);
-                                                println!("  Replacing '{}' with '{}'", song_path.display(), selected_path);
-                                                update_playlist_line(&path_str, &song_path.display().to_string(), selected_path).expect("Failed to update playlist");
-                                            }
-                                            Ok(selected) if selected == "Remove" => {
-                                                // Remove the missing song from the playlist file
-                                                println!("  Removing '{}' from playlist", song_path.display());
-                                                // Use update_playlist_line with new_line as empty string to indicate removal
-                                                if let Err(e) = update_playlist_line(&path_str, &song_path.display().to_string(), "") {
-                                                    eprintln!("Failed to update playlist file: {}", e);
-                                                }
-                                            }
-                                            Ok(_) | Err(_) => {
-                                                println!("  Skipped replacement for '{}'", song_path.display());
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
+        .collect();
+
+    // Group tracks by folder so each cover image is read once
+    let mut folders: std::collections::BTreeMap<PathBuf, Vec<PathBuf>> = std::collections::BTreeMap::new();
+    for path in paths {
+        let path = PathBuf::from(path);
+        if let Some(dir) = path.parent() {
+            folders.entry(dir.to_path_buf()).or_default().push(path);
+        }
+    }
+
+    let (mut embedded, mut skipped) = (0, 0);
+    for (dir, tracks) in &folders {
+        let Some(cover_path) = folder_cover(dir) else {
+            continue;
+        };
+        let cover = match fs::read(&cover_path) {
+            Ok(cover) => cover,
+            Err(e) => {
+                eprintln!("{}", format!("Failed to read {}: {}", cover_path.display(), e).red());
+                continue;
+            }
+        };
+        for track in tracks.iter().filter(|t| t.exists()) {
+            if !force && has_embedded_art(track) {
+                skipped += 1;
+                continue;
+            }
+            if dry_run {
+                println!("[dry-run] Would embed {} into {}", cover_path.display(), track.display());
+                embedded += 1;
+                continue;
+            }
+            let result = write_front_cover(track, &cover);
+            log_mutation("embed-art", &[track, &cover_path], &result);
+            match result {
+                Ok(()) => {
+                    println!("Embedded {} into {}", cover_path.display(), track.display());
+                    embedded += 1;
                 }
+                Err(e) => eprintln!("{}", format!("Failed to embed art into {}: {}", track.display(), e).red()),
             }
         }
     }
-    tx.commit().expect("Failed to commit transaction");
+
+    let verb = if dry_run { "Would embed" } else { "Embedded" };
+    println!("{}", format!("{} art into {} file(s).", verb, embedded).green());
+    if skipped > 0 {
+        println!("Skipped {} file(s) that already have embedded art (use --force to replace it).", skipped);
+    }
 }
 
-fn search_db(db_path: &str, statement: &str, query: &str) -> Vec<(String, String, String)> {
-    let db_path = expand_tilde(db_path);
-    let conn = rusqlite::Connection::open(&db_path).expect("Failed to open database");
+struct MatchedTrack {
+    id: i64,
+    path: String,
+    artist: String,
+    title: String,
+    duration: i64,
+}
 
-    let mut stmt = conn.prepare(statement).expect("Failed to prepare statement");
+// Pick the track best matching `query` by similarity to "artist - title" or the bare title.
+// A confident match is taken directly; otherwise the top candidates are offered in a prompt.
+fn select_track(conn: &rusqlite::Connection, query: &str, prompt: &str) -> Option<MatchedTrack> {
+    let query_lower = query.to_lowercase();
+    let mut stmt = conn.prepare("SELECT id, path, artist, title, duration FROM tracks").expect("Failed to prepare statement");
+    let mut candidates: Vec<(f64, MatchedTrack)> = stmt
+        .query_map([], |row| {
+            Ok(MatchedTrack {
+                id: row.get(0)?,
+                path: row.get(1)?,
+                artist: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                title: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                duration: row.get::<_, Option<i64>>(4)?.unwrap_or(0),
+            })
+        })
+        .expect("Failed to execute query")
+        .filter_map(Result::ok)
+        .map(|track| {
+            let full = format!("{} - {}", track.artist, track.title).to_lowercase();
+            let score = strsim::jaro(&full, &query_lower).max(strsim::jaro(&track.title.to_lowercase(), &query_lower));
+            (score, track)
+        })
+        .collect();
+    candidates.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+    candidates.truncate(5);
 
-    let mut results = Vec::new();
-    // Only pass a parameter if the statement contains ?1
-    if statement.contains("?1") {
-        let pattern = format!("%{}%", query);
-        let mut rows = stmt.query([&pattern]).expect("Failed to execute query");
-        while let Some(row) = rows.next().expect("Failed to fetch row") {
-            let artist: String = row.get(0).unwrap_or_default();
-            let album: String = row.get(1).unwrap_or_default();
-            let title: String = row.get(2).unwrap_or_default();
-            results.push((artist, album, title));
-        }
-    } else {
-        let mut rows = stmt.query([]).expect("Failed to execute query");
-        while let Some(row) = rows.next().expect("Failed to fetch row") {
-            let artist: String = row.get(0).unwrap_or_default();
-            let album: String = row.get(1).unwrap_or_default();
-            let title: String = row.get(2).unwrap_or_default();
-            results.push((artist, album, title));
-        }
+    if candidates.is_empty() {
+        println!("{}", "No tracks found.".yellow());
+        return None;
     }
-    results
+
+    if candidates[0].0 >= 0.9 {
+        return Some(candidates.swap_remove(0).1);
+    }
+    let options: Vec<String> = candidates
+        .iter()
+        .map(|(score, track)| format!("({:.3}) {}", score, track.path))
+        .collect();
+    let selected = inquire::Select::new(prompt, options.clone()).prompt().ok()?;
+    let index = options.iter().position(|o| o == &selected).unwrap_or(0);
+    Some(candidates.swap_remove(index).1)
+}
+
+// An m3u entry with an #EXTINF line, probing the duration if it isn't indexed yet
+fn extinf_entry(track_path: &str, artist: &str, title: &str, duration: i64, entry_path: &str) -> String {
+    let duration = if duration > 0 { duration } else { get_duration_with_lofty(Path::new(track_path)) };
+    let extinf_duration = if duration > 0 { duration } else { -1 };
+    format!("#EXTINF:{},{} - {}\n{}\n", extinf_duration, artist, title, entry_path)
 }
 
-fn search_tracks(db_path: &str, query: Option<String>) {
+// Report missing files and, with `checksums`, files whose contents changed while their mtime
+// did not (a sign of corruption). Returns whether any problems were found.
+fn verify_library(db_path: &str, checksums: bool, fail_fast: bool) -> bool {
     let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
 
-    // Display Tracks (flat list for search)
-    println!("{} {}", "Tracks".bold().underline(), "(Track - Album - Artist)");
-    let statement = "SELECT artist, album, title FROM tracks WHERE title LIKE ?1 ORDER BY artist, album, title";
-    let results = if let Some(ref q) = query {
-        search_db(&db_path, statement, q)
-    } else {
-        search_db(&db_path, statement, "")
-    };
-    if results.is_empty() {
-        println!("{}", "No tracks found.".yellow());
+    let mut stmt = conn.prepare("SELECT path, mtime, checksum FROM tracks ORDER BY path")
+        .expect("Failed to prepare statement");
+    let tracks: Vec<(String, Option<i64>, Option<String>)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .expect("Failed to execute query")
+        .filter_map(Result::ok)
+        .collect();
+
+    let missing: Vec<&String> = tracks.iter().map(|(path, _, _)| path).filter(|p| !Path::new(p).exists()).collect();
+    if missing.is_empty() {
+        println!("{}", "All indexed files are present.".green());
     } else {
-        for (artist, album, title) in results {
-            println!("{} - {} - {}", title, album, artist);
+        println!("{}", format!("{} indexed file(s) are missing:", missing.len()).yellow());
+        for path in &missing {
+            println!("  {}", path);
         }
     }
-    println!("");
+    if !checksums {
+        return !missing.is_empty();
+    }
 
-    println!("{}", "Albums".bold().underline());
-    let statement = "SELECT album, artist, title FROM tracks WHERE album LIKE ?1 ORDER BY album, artist, title";
-    let results = if let Some(ref q) = query {
-        search_db(&db_path, statement, q)
+    // Only files with a stored checksum and an unchanged mtime can reveal corruption
+    let candidates: Vec<_> = tracks
+        .iter()
+        .filter(|(path, mtime, checksum)| {
+            checksum.is_some()
+                && mtime.is_some()
+                && fs::metadata(path).ok().as_ref().and_then(file_mtime) == *mtime
+        })
+        .collect();
+    let unchecked = tracks.iter().filter(|(_, _, checksum)| checksum.is_none()).count();
+
+    println!("Verifying checksums of {} file(s)...", candidates.len());
+    let pb = make_progress_bar(candidates.len());
+    // With --fail-fast the first mismatch tells the other workers to skip their remaining files
+    let abort = AtomicBool::new(false);
+    let mut corrupted: Vec<&String> = candidates
+        .par_iter()
+        .filter_map(|(path, _, checksum)| {
+            if abort.load(Ordering::Relaxed) {
+                return None;
+            }
+            let changed = file_checksum(Path::new(path)).ok().as_ref() != checksum.as_ref();
+            if changed && fail_fast {
+                abort.store(true, Ordering::Relaxed);
+            }
+            pb.inc(1);
+            changed.then_some(path)
+        })
+        .collect();
+    corrupted.sort();
+
+    if abort.load(Ordering::Relaxed) {
+        pb.abandon();
+        println!("{}", "Stopped at the first changed file (--fail-fast).".red());
     } else {
-        search_db(&db_path, statement, "")
-    };
-    if results.is_empty() {
-        println!("{}", "No albums found.".yellow());
+        pb.finish_with_message("Checksum verification complete");
+    }
+    if corrupted.is_empty() {
+        println!("{}", "No unexpected content changes found.".green());
     } else {
-        let unique_albums = results.iter().map(|(album, _, _)| album).collect::<std::collections::HashSet<_>>();
-        for album in unique_albums {
-            println!("{}", album);
+        println!("{}", format!("{} file(s) changed without a new modification time:", corrupted.len()).red());
+        for path in &corrupted {
+            println!("  {}", path);
         }
     }
-    println!("");
+    if unchecked > 0 {
+        println!("{} file(s) have no stored checksum; run 'apollo index --checksum' to add them.", unchecked);
+    }
+    !missing.is_empty() || !corrupted.is_empty()
+}
 
-    println!("{}", "Artists".bold().underline());
-    let statement = "SELECT album, artist, title FROM tracks WHERE artist LIKE ?1 ORDER BY album, artist, title";
-    let results = if let Some(ref q) = query {
-        search_db(&db_path, statement, q)
+fn create_favorites_table(conn: &rusqlite::Connection) {
+    create_table(conn,
+        "CREATE TABLE IF NOT EXISTS favorites (
+            track_id INTEGER PRIMARY KEY,
+            added_at INTEGER NOT NULL
+        )",
+    ).expect("Failed to create favorites table");
+}
+
+fn favorite_add(db_path: &str, query: &str) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+    create_favorites_table(&conn);
+
+    let Some(track) = select_track(&conn, query, "Select the track to favorite:") else {
+        return;
+    };
+    let added = conn.execute(
+        "INSERT OR IGNORE INTO favorites (track_id, added_at) VALUES (?1, strftime('%s', 'now'))",
+        [track.id],
+    ).expect("Failed to save favorite");
+    if added == 0 {
+        println!("{}", format!("'{} - {}' is already a favorite", track.artist, track.title).yellow());
     } else {
-        search_db(&db_path, statement, "")
+        println!("Added '{} - {}' to favorites", track.artist, track.title);
+    }
+}
+
+fn favorite_remove(db_path: &str, query: &str) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+    create_favorites_table(&conn);
+
+    let Some(track) = select_track(&conn, query, "Select the track to remove from favorites:") else {
+        return;
     };
-    if results.is_empty() {
-        println!("{}", "No artists found.".yellow());
+    let removed = conn.execute("DELETE FROM favorites WHERE track_id = ?1", [track.id])
+        .expect("Failed to remove favorite");
+    if removed == 0 {
+        println!("{}", format!("'{} - {}' is not a favorite", track.artist, track.title).yellow());
     } else {
-        let unique_artists = results.iter().map(|(_, artist, _)| artist).collect::<std::collections::HashSet<_>>();
-        for artist in unique_artists {
-            println!("{}", artist);
-        }
+        println!("Removed '{} - {}' from favorites", track.artist, track.title);
     }
+}
 
+// Favorites in the order they were added; rows for tracks no longer indexed are skipped
+fn load_favorites(conn: &rusqlite::Connection) -> Vec<MatchedTrack> {
+    let mut stmt = conn.prepare(
+        "SELECT t.id, t.path, t.artist, t.title, t.duration FROM favorites f \
+         JOIN tracks t ON t.id = f.track_id ORDER BY f.added_at, f.track_id"
+    ).expect("Failed to prepare favorites statement");
+    stmt.query_map([], |row| {
+        Ok(MatchedTrack {
+            id: row.get(0)?,
+            path: row.get(1)?,
+            artist: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+            title: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+            duration: row.get::<_, Option<i64>>(4)?.unwrap_or(0),
+        })
+    })
+    .expect("Failed to query favorites")
+    .filter_map(Result::ok)
+    .collect()
 }
 
-fn print_grouped_tracks(results: Vec<(String, String, String)>) {
-    if results.is_empty() {
-        println!("{}", "No tracks found.".yellow());
+fn list_favorites(db_path: &str) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+    create_favorites_table(&conn);
+
+    let favorites = load_favorites(&conn);
+    if favorites.is_empty() {
+        println!("{}", "No favorites yet. Add one with 'apollo fav add <query>'.".yellow());
         return;
     }
-    let mut last_artist = String::new();
-    let mut last_album = String::new();
-    for (artist, album, title) in results {
-        if artist != last_artist {
-            println!("\n{}:", artist.bold());
-            last_artist = artist.clone();
-            last_album.clear();
-        }
-        if album != last_album {
-            println!("  {}:", album.cyan());
-            last_album = album.clone();
-        }
-        println!("    {}", title);
+    let rows: Vec<Vec<String>> = favorites
+        .into_iter()
+        .map(|track| vec![format!("#{}", track.id), track.artist, track.title])
+        .collect();
+    print_table(&["Id", "Artist", "Title"], &rows);
+}
+
+fn write_favorites_playlist(db_path: &str, output: &str, absolute_paths: bool) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+    create_favorites_table(&conn);
+
+    let output = PathBuf::from(expand_tilde(output));
+    let output_dir = output.parent().unwrap_or_else(|| Path::new(""));
+    let favorites = load_favorites(&conn);
+
+    let mut content = String::from("#EXTM3U\n");
+    for track in &favorites {
+        let entry_path = if absolute_paths {
+            track.path.clone()
+        } else {
+            Path::new(&track.path)
+                .strip_prefix(output_dir)
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or_else(|_| track.path.clone())
+        };
+        content.push_str(&extinf_entry(&track.path, &track.artist, &track.title, track.duration, &entry_path));
     }
+
+    if !output_dir.as_os_str().is_empty() {
+        fs::create_dir_all(output_dir).expect("Failed to create playlist directory");
+    }
+    fs::write(&output, content).expect("Failed to write favorites playlist");
+    println!("Wrote {} favorite(s) to {}", favorites.len(), output.display());
 }
 
-fn list_tracks(db_path: &str, query: Option<String>, genre: Option<String>) {
+fn note_set(db_path: &str, query: &str, text: &str) {
     let db_path = expand_tilde(db_path);
-    let conn = rusqlite::Connection::open(&db_path).expect("Failed to open database");
+    let conn = open_db(&db_path);
 
-    // Print genre header if filtering
-    if let Some(ref g) = genre {
-        println!("{} {}", "Genre:".bold(), g.cyan());
+    let Some(track) = select_track(&conn, query, "Select the track to annotate:") else {
+        return;
+    };
+    let text = text.trim();
+    let note = if text.is_empty() { None } else { Some(text) };
+    conn.execute("UPDATE tracks SET notes = ?1 WHERE id = ?2", rusqlite::params![note, track.id])
+        .expect("Failed to save note");
+    match note {
+        Some(_) => println!("Saved note for '{} - {}'", track.artist, track.title),
+        None => println!("Removed the note from '{} - {}'", track.artist, track.title),
+    }
+}
+
+fn note_show(db_path: &str, query: &str) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+
+    let Some(track) = select_track(&conn, query, "Select the track whose note to show:") else {
+        return;
+    };
+    let note: Option<String> = conn
+        .query_row("SELECT notes FROM tracks WHERE id = ?1", [track.id], |row| row.get(0))
+        .expect("Failed to read note");
+    println!("{} - {}", track.artist.cyan(), track.title.cyan());
+    match note {
+        Some(note) => println!("  {}", note),
+        None => println!("{}", "  No note.".yellow()),
     }
+}
 
-    let results: Vec<(String, String, String)> = match (&query, &genre) {
-        // No filters — list everything
-        (None, None) => {
-            let mut stmt = conn.prepare(
-                "SELECT artist, album, title FROM tracks ORDER BY artist, album, title"
-            ).expect("Failed to prepare statement");
-            let mut rows = stmt.query([]).expect("Failed to execute query");
-            let mut out = Vec::new();
-            while let Some(row) = rows.next().expect("Failed to fetch row") {
-                out.push((
-                    row.get(0).unwrap_or_default(),
-                    row.get(1).unwrap_or_default(),
-                    row.get(2).unwrap_or_default(),
-                ));
-            }
-            out
+fn preview_track(db_path: &str, query: &str, seconds: u32, from: u32) {
+    let db_path = expand_tilde(db_path);
+    let conn = open_db(&db_path);
+
+    let by_id = query.strip_prefix('#').and_then(|id| id.parse::<i64>().ok());
+    let track = match by_id {
+        Some(id) => conn.query_row(
+            "SELECT path, COALESCE(artist, ''), COALESCE(title, ''), COALESCE(duration, 0) FROM tracks WHERE id = ?1",
+            [id],
+            |row| Ok(MatchedTrack { id, path: row.get(0)?, artist: row.get(1)?, title: row.get(2)?, duration: row.get(3)? }),
+        ).ok(),
+        None => select_track(&conn, query, "Select the track to preview:"),
+    };
+    let Some(track) = track else {
+        if by_id.is_some() {
+            println!("{}", format!("No track with id {}.", query).yellow());
         }
+        return;
+    };
 
-        // Genre only
-        (None, Some(g)) => {
-            let pattern = format!("%{}%", g);
-            let mut stmt = conn.prepare(
-                "SELECT artist, album, title FROM tracks \
-                 WHERE genre LIKE ?1 \
-                 ORDER BY artist, album, title"
-            ).expect("Failed to prepare statement");
-            let mut rows = stmt.query([&pattern]).expect("Failed to execute query");
-            let mut out = Vec::new();
-            while let Some(row) = rows.next().expect("Failed to fetch row") {
-                out.push((
-                    row.get(0).unwrap_or_default(),
-                    row.get(1).unwrap_or_default(),
-                    row.get(2).unwrap_or_default(),
-                ));
-            }
-            out
+    // Short tracks play their last stretch rather than starting past the end
+    let duration = track.duration.max(0) as u32;
+    let start = if duration > 0 && from >= duration { duration.saturating_sub(seconds) } else { from };
+    println!("Previewing {} - {} ({}s from {}s)", track.artist.cyan(), track.title.cyan(), seconds, start);
+
+    let status = std::process::Command::new("ffplay")
+        .args(["-nodisp", "-autoexit", "-loglevel", "error", "-ss"])
+        .arg(start.to_string())
+        .arg("-t")
+        .arg(seconds.to_string())
+        .arg(&track.path)
+        .status();
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => exit_with_error(EXIT_PLAYBACK, &format!("ffplay failed on {} ({})", track.path, status)),
+        Err(_) => exit_with_error(
+            EXIT_PLAYBACK,
+            "ffplay is not installed or not in PATH. Please install ffmpeg to use the preview command",
+        ),
+    }
+}
+
+// A playlist as listed by the Serve API
+#[derive(Serialize)]
+struct PlaylistSummary {
+    name: String,
+    path: String,
+    entries: usize,
+}
+
+// Serve's JSON API: plain HTTP/1.1 on a std TcpListener, answering one request per connection
+// in turn. Every endpoint only reads the database.
+fn serve(settings: &Settings, host: &str, port: u16) {
+    let listener = std::net::TcpListener::bind((host, port))
+        .unwrap_or_else(|e| exit_with_error(EXIT_ERROR, &format!("cannot listen on {}:{}: {}", host, port, e)));
+    println!("Serving the library on http://{}:{} (Ctrl-C to stop)", host, port);
+    println!("Endpoints: /tracks?q=&genre=&min_rating=, /search?q=, /stats, /playlists, /peaks?path=");
+
+    for stream in listener.incoming() {
+        let result = stream.and_then(|stream| handle_api_request(stream, settings));
+        if let Err(e) = result {
+            eprintln!("{}", format!("Warning: request failed: {}", e).yellow());
         }
+    }
+}
 
-        // Query only
-        (Some(q), None) => {
-            let pattern = format!("%{}%", q);
-            let mut stmt = conn.prepare(
-                "SELECT artist, album, title FROM tracks \
-                 WHERE album LIKE ?1 OR artist LIKE ?1 OR title LIKE ?1 \
-                 ORDER BY artist, album, title"
-            ).expect("Failed to prepare statement");
-            let mut rows = stmt.query([&pattern]).expect("Failed to execute query");
-            let mut out = Vec::new();
-            while let Some(row) = rows.next().expect("Failed to fetch row") {
-                out.push((
-                    row.get(0).unwrap_or_default(),
-                    row.get(1).unwrap_or_default(),
-                    row.get(2).unwrap_or_default(),
-                ));
-            }
-            out
+fn handle_api_request(mut stream: std::net::TcpStream, settings: &Settings) -> std::io::Result<()> {
+    use std::io::BufRead;
+
+    // A client that connects and says nothing must not hold up everyone else
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = std::io::BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // No endpoint takes headers or a body
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
         }
+    }
 
-        // Both query and genre
-        (Some(q), Some(g)) => {
-            let q_pattern = format!("%{}%", q);
-            let g_pattern = format!("%{}%", g);
-            let mut stmt = conn.prepare(
-                "SELECT artist, album, title FROM tracks \
-                 WHERE genre LIKE ?1 \
-                 AND (album LIKE ?2 OR artist LIKE ?2 OR title LIKE ?2) \
-                 ORDER BY artist, album, title"
-            ).expect("Failed to prepare statement");
-            let mut rows = stmt.query([&g_pattern, &q_pattern]).expect("Failed to execute query");
-            let mut out = Vec::new();
-            while let Some(row) = rows.next().expect("Failed to fetch row") {
-                out.push((
-                    row.get(0).unwrap_or_default(),
-                    row.get(1).unwrap_or_default(),
-                    row.get(2).unwrap_or_default(),
-                ));
-            }
-            out
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or_default(), parts.next().unwrap_or("/"));
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let params: HashMap<String, String> = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .filter(|(key, _)| !key.is_empty())
+        .map(|(key, value)| (percent_decode(&key.replace('+', " ")), percent_decode(&value.replace('+', " "))))
+        .collect();
+
+    let (status, body) = if method == "GET" {
+        api_response(path, &params, settings)
+    } else {
+        (405, api_error("only GET is supported"))
+    };
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        _ => "Method Not Allowed",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, reason, body.len(), body
+    )?;
+    stream.flush()
+}
+
+fn api_error(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+// Status code and JSON body for a GET of `path`
+fn api_response(path: &str, params: &HashMap<String, String>, settings: &Settings) -> (u16, String) {
+    let db_path = expand_tilde(&settings.files.database_name);
+    let param = |name: &str| params.get(name).map(String::as_str).filter(|value| !value.is_empty());
+    let body = match path.trim_end_matches('/') {
+        "/tracks" => {
+            let Ok(min_rating) = param("min_rating").map_or(Ok(0), str::parse::<u8>) else {
+                return (400, api_error("min_rating must be a number from 0 to 5"));
+            };
+            let labels = &settings.unknown_label;
+            let tracks: Vec<ListedTrack> = query_tracks(&open_db(&db_path), param("q"), param("genre"), min_rating)
+                .into_iter()
+                .map(|(artist, album, title, gain, notes)| ListedTrack {
+                    artist: labels.artist_or(&artist).to_string(),
+                    album: labels.album_or(&album).to_string(),
+                    title: labels.title_or(&title).to_string(),
+                    gain,
+                    notes,
+                })
+                .collect();
+            serde_json::to_string(&tracks)
+        }
+        "/search" => {
+            let Some(query) = param("q") else {
+                return (400, api_error("missing q"));
+            };
+            let Ok(pattern) = SearchPattern::new(query, false) else {
+                return (400, api_error("invalid query"));
+            };
+            let hits: Vec<SearchHit> = search_db(&db_path, ["artist", "album", "title"], 2, &pattern)
+                .into_iter()
+                .map(|(artist, album, title)| SearchHit { title, album, artist })
+                .collect();
+            serde_json::to_string(&hits)
+        }
+        "/stats" => {
+            let music_dir = expand_tilde(&settings.files.music_directory);
+            let (totals, _) = library_totals(&open_db(&db_path), &music_dir, false, stats_cache_ttl_secs(settings));
+            serde_json::to_string(&totals)
+        }
+        "/playlists" => {
+            let conn = open_db(&db_path);
+            create_playlists_table(&conn);
+            let mut stmt = conn.prepare("SELECT name, path FROM playlists ORDER BY name").expect("Failed to prepare statement");
+            let playlists: Vec<PlaylistSummary> = stmt
+                .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+                .expect("Failed to query playlists")
+                .filter_map(Result::ok)
+                .map(|(name, path)| PlaylistSummary { entries: parse_playlist(Path::new(&path)).len(), name, path })
+                .collect();
+            serde_json::to_string(&playlists)
         }
+        "/peaks" => {
+            let Some(track_path) = param("path") else {
+                return (400, api_error("missing path"));
+            };
+            let conn = open_db(&db_path);
+            create_peaks_table(&conn);
+            let stored: Option<(i64, String)> = conn.query_row(
+                "SELECT p.resolution, p.peaks FROM track_peaks p JOIN tracks t ON t.id = p.track_id WHERE t.path = ?1",
+                [track_path],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            ).ok();
+            let Some((resolution, peaks)) = stored else {
+                return (404, api_error("no peaks for that path; run 'apollo analyze' first"));
+            };
+            let peaks: serde_json::Value = serde_json::from_str(&peaks).unwrap_or_default();
+            serde_json::to_string(&serde_json::json!({ "path": track_path, "resolution": resolution, "peaks": peaks }))
+        }
+        _ => return (404, api_error("not found")),
     };
-
-    print_grouped_tracks(results);
+    (200, body.expect("Failed to serialize output"))
 }
 
-fn export_tracks(db_path: &str) {
+fn add_to_playlist(db_path: &str, playlist: &str, track_query: &str, absolute_paths: bool) {
     let db_path = expand_tilde(db_path);
-    let conn = rusqlite::Connection::open(&db_path).expect("Failed to open database");
+    let conn = open_db(&db_path);
+
+    let playlist_path: String = match conn.query_row(
+        "SELECT path FROM playlists WHERE name = ?1 COLLATE NOCASE",
+        [playlist],
+        |row| row.get(0),
+    ) {
+        Ok(path) => path,
+        Err(_) => exit_with_error(
+            EXIT_ERROR,
+            &format!("playlist '{}' not found. Run 'apollo index' to index playlists.", playlist),
+        ),
+    };
+    if PlaylistFormat::from_path(Path::new(&playlist_path)) != Some(PlaylistFormat::M3u) {
+        exit_with_error(
+            EXIT_ERROR,
+            &format!("playlist '{}' is not an m3u playlist; only m3u playlists can be appended to", playlist),
+        );
+    }
 
-    let mut stmt = conn.prepare("SELECT artist, album, title FROM tracks").expect("Failed to prepare statement");
-    let mut rows = stmt.query([]).expect("Failed to execute query");
+    let Some(MatchedTrack { path: track_path, artist, title, duration, .. }) =
+        select_track(&conn, track_query, &format!("Select the track to add to '{}':", playlist))
+    else {
+        println!("  Skipped adding to '{}'", playlist);
+        return;
+    };
 
-    // Write CSV to a file in the same directory as the database, named "tracks_export.csv"
-    let db_folder = std::path::Path::new(&db_path).parent().unwrap_or_else(|| std::path::Path::new("."));
-    let csv_path = db_folder.join("tracks_export.csv");
-    let mut file = std::fs::File::create(&csv_path).expect("Failed to create CSV file");
+    let content = std::fs::read_to_string(&playlist_path).unwrap_or_default();
+    let playlist_dir = Path::new(&playlist_path).parent().unwrap_or_else(|| Path::new(""));
 
-    // Write CSV header
-    write_csv_row(&mut file, &["Artist", "Album", "Title"]).expect("Failed to write CSV header");
+    // Refuse to add a track that is already listed
+    if parse_playlist(Path::new(&playlist_path)).iter().any(|entry| entry == Path::new(&track_path)) {
+        println!("{}", format!("'{}' is already in playlist '{}'", track_path, playlist).yellow());
+        return;
+    }
 
-    while let Some(row) = rows.next().expect("Failed to fetch row") {
-        let artist: String = row.get(0).unwrap_or_default();
-        let album: String = row.get(1).unwrap_or_default();
-        let title: String = row.get(2).unwrap_or_default();
-        write_csv_row(&mut file, &[&artist, &album, &title]).expect("Failed to write CSV record");
+    let entry_path = if absolute_paths {
+        track_path.clone()
+    } else {
+        Path::new(&track_path)
+            .strip_prefix(playlist_dir)
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_else(|_| track_path.clone())
+    };
+    let mut new_content = content;
+    if !new_content.is_empty() && !new_content.ends_with('\n') {
+        new_content.push('\n');
     }
+    new_content.push_str(&extinf_entry(&track_path, &artist, &title, duration, &entry_path));
 
-    println!("Exported tracks to {}", csv_path.display());
-}
+    let result = std::fs::write(&playlist_path, new_content);
+    log_mutation("write-playlist", &[Path::new(&playlist_path)], &result);
+    if let Err(e) = result {
+        eprintln!("Failed to update playlist file: {}", e);
+        return;
+    }
 
-fn get_stats(music_dir: &str, db_path: &str) {
-    let db_path = expand_tilde(db_path);
-    let conn = rusqlite::Connection::open(&db_path).expect("Failed to open database");
+    let name = Path::new(&playlist_path).file_stem().unwrap_or_default().to_string_lossy().to_string();
+    conn.execute(
+        "INSERT OR IGNORE INTO playlists (name, path) VALUES (?1, ?2)",
+        [&name, &playlist_path],
+    ).ok();
 
-    let total_tracks: i64 = conn.query_row("SELECT COUNT(*) FROM tracks", [], |row| row.get(0)).unwrap_or(0);
-    let total_artists: i64 = conn.query_row("SELECT COUNT(DISTINCT artist) FROM tracks", [], |row| row.get(0)).unwrap_or(0);
-    let total_albums: i64 = conn.query_row("SELECT COUNT(DISTINCT album) FROM tracks", [], |row| row.get(0)).unwrap_or(0);
-    
-    // update durations if they are zero
-    let mut stmt = conn.prepare("SELECT id, path, duration FROM tracks WHERE duration = 0").expect("Failed to prepare statement");
-    let mut rows = stmt.query([]).expect("Failed to execute query");
-    // Collect all rows first to know the total count for the progress bar
-    let mut rows_vec = Vec::new();
-    while let Some(row) = rows.next().expect("Failed to fetch row") {
-        let id: i64 = row.get(0).expect("Failed to get id");
-        let path: String = row.get(1).expect("Failed to get path");
-        rows_vec.push((id, path));
-    }
+    println!("Added '{} - {}' to playlist '{}'", artist, title, playlist);
+}
 
-    let pb = ProgressBar::new(rows_vec.len() as u64);
-    pb.set_style(ProgressStyle::with_template("[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}")
-        .unwrap()
-        .progress_chars("##-"));
+// Playlist formats understood by the indexer, detected by extension
+#[derive(Clone, Copy, PartialEq)]
+enum PlaylistFormat {
+    M3u,
+    Pls,
+    Xspf,
+}
 
-    for (id, path) in rows_vec {
-        let duration: f64 = get_duration_with_lofty(std::path::Path::new(&path)) as f64;
-        if duration > 0.0 {
-            conn.execute("UPDATE tracks SET duration = ?1 WHERE id = ?2", [duration, id as f64]).expect("Failed to update duration");
+impl PlaylistFormat {
+    fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "m3u" | "m3u8" => Some(PlaylistFormat::M3u),
+            "pls" => Some(PlaylistFormat::Pls),
+            "xspf" => Some(PlaylistFormat::Xspf),
+            _ => None,
         }
-        pb.inc(1);
-        pb.set_message(format!("{}", path));
     }
-    pb.finish_with_message("Duration update complete");
-    
-    let total_duration: f64 = conn.query_row(
-        "SELECT SUM(duration) FROM tracks",
-        [],
-        |row| row.get(0)
-    ).unwrap_or(0.0);
-
-    fn format_duration(secs: f64) -> String {
-        let months: f64 = secs / 2592000.0;
-        let weeks: f64 = secs / 604800.0;
-        let days: f64 = secs / 86400.0;
-        let hours: f64 = secs / 3600.0;
-        let minutes: f64 = secs / 60.0;
-        if months > 1.0 {
-            format!("{:.2} months", months)
-        } else if weeks > 1.0 {
-            format!("{:.2} weeks", weeks)
-        } else if days > 1.0 {
-            format!("{:.2} days", days)
-        } else if hours > 1.0 {
-            format!("{:.2} hours", hours)
-        } else if minutes > 1.0 {
-            format!("{:.2} minutes", minutes)
-        } else {
-            format!("{:.2} seconds", secs)
+}
+
+// Byte range of every track entry in a playlist's text, paired with the entry as a path string
+fn playlist_entry_spans(format: PlaylistFormat, content: &str) -> Vec<(std::ops::Range<usize>, String)> {
+    let mut spans = Vec::new();
+    match format {
+        PlaylistFormat::M3u | PlaylistFormat::Pls => {
+            let mut offset = 0;
+            for line in content.split_inclusive('\n') {
+                let line_start = offset;
+                offset += line.len();
+                let line = line.trim_end_matches(['\n', '\r']);
+                let value_start = match format {
+                    PlaylistFormat::M3u if !line.trim_start().starts_with('#') => 0,
+                    // Only `FileN=path` keys hold tracks; the header, TitleN and LengthN are skipped
+                    PlaylistFormat::Pls => match line.split_once('=') {
+                        Some((key, _)) if key.trim().to_lowercase().starts_with("file") => key.len() + 1,
+                        _ => continue,
+                    },
+                    _ => continue,
+                };
+                let raw = &line[value_start..];
+                let trimmed = raw.trim();
+                if trimmed.is_empty() {
+                    continue;
+                }
+                let start = line_start + value_start + (raw.len() - raw.trim_start().len());
+                spans.push((start..start + trimmed.len(), trimmed.to_string()));
+            }
+        }
+        PlaylistFormat::Xspf => {
+            spans.extend(xspf_locations(content).into_iter().map(|location| (location.span, location.path)));
         }
     }
+    spans
+}
 
-    let folder_size: String = format_bytes(get_dir_size(&music_dir).unwrap() as f64);
-
-
-
-    println!("Total tracks: {}", total_tracks);
-    println!("Total artists: {}", total_artists);
-    println!("Total albums: {}", total_albums);
-    println!("Total size: {}", folder_size);
-    println!("Total time: {}", format_duration(total_duration));
-
-    // --- Date Histogram ---
-    println!("\nTracks by Year:");
-    let mut stmt = conn.prepare(
-        "SELECT year, COUNT(*) FROM tracks WHERE year IS NOT NULL AND year > 0 GROUP BY year ORDER BY year"
-    ).expect("Failed to prepare year histogram statement");
-    let mut rows = stmt.query([]).expect("Failed to execute year histogram query");
+// A track's <location> in an XSPF playlist: the byte range of its text or CDATA, the whole
+// enclosing <track> element, and the path it names
+struct XspfLocation {
+    span: std::ops::Range<usize>,
+    track: std::ops::Range<usize>,
+    path: String,
+}
 
-    // Collect year counts
-    let mut year_counts = Vec::new();
-    let mut max_count = 0;
-    while let Some(row) = rows.next().expect("Failed to fetch year row") {
-        let year: i64 = row.get(0).unwrap_or(0);
-        let count: i64 = row.get(1).unwrap_or(0);
-        if count > max_count {
-            max_count = count;
+// Only locations inside <track> count; a playlist-level <location> names the playlist itself.
+// Parsing stops at the first malformed markup, keeping the entries read before it.
+fn xspf_locations(content: &str) -> Vec<XspfLocation> {
+    use quick_xml::events::Event;
+
+    let mut reader = quick_xml::Reader::from_str(content);
+    let mut locations = Vec::new();
+    let mut track_start = None;
+    let mut track_locations = Vec::new();
+    // The span and unescaped text of the <location> being read
+    let mut location: Option<(Option<std::ops::Range<usize>>, String)> = None;
+    loop {
+        let before = reader.buffer_position() as usize;
+        let Ok(event) = reader.read_event() else { break };
+        let after = reader.buffer_position() as usize;
+        let piece = match &event {
+            Event::Start(tag) if tag.local_name().as_ref() == "track" => {
+                track_start = Some(before);
+                track_locations.clear();
+                None
+            }
+            Event::Start(tag) if tag.local_name().as_ref() == "location" && track_start.is_some() => {
+                location = Some((None, String::new()));
+                None
+            }
+            Event::End(tag) if tag.local_name().as_ref() == "location" => {
+                if let Some((Some(span), value)) = location.take() {
+                    // Surrounding whitespace in a text location isn't part of the URI
+                    let raw = &content[span.clone()];
+                    let start = span.start + (raw.len() - raw.trim_start().len());
+                    let end = span.end - (raw.len() - raw.trim_end().len());
+                    track_locations.push((start..end, decode_file_uri(value.trim())));
+                }
+                None
+            }
+            Event::End(tag) if tag.local_name().as_ref() == "track" => {
+                if let Some(start) = track_start.take() {
+                    locations.extend(track_locations.drain(..).map(|(span, path)| XspfLocation { span, track: start..after, path }));
+                }
+                None
+            }
+            Event::Text(text) => Some(text.xml10_content().to_string()),
+            Event::CData(cdata) => Some(cdata.xml10_content().to_string()),
+            Event::GeneralRef(reference) if reference.is_char_ref() => {
+                reference.resolve_char_ref().ok().flatten().map(String::from)
+            }
+            Event::GeneralRef(reference) => {
+                quick_xml::escape::resolve_predefined_entity(&reference.xml10_content()).map(String::from)
+            }
+            Event::Eof => break,
+            _ => None,
+        };
+        if let (Some(piece), Some((span, value))) = (piece, location.as_mut()) {
+            let start = span.as_ref().map_or(before, |span| span.start);
+            *span = Some(start..after);
+            value.push_str(&piece);
         }
-        year_counts.push((year, count));
     }
+    locations
+}
 
-    // Print histogram
-    for (year, count) in year_counts {
-        let bar_len = if max_count > 0 { (count * 40 / max_count) as usize } else { 0 };
-        let bar = "█".repeat(bar_len);
-        println!("{:4}: {:4} {}", year, count, bar);
-    }
+// Decode an XSPF location (a percent-encoded file URI, already XML-unescaped) into a path
+fn decode_file_uri(location: &str) -> String {
+    percent_decode(location.strip_prefix("file://").unwrap_or(location))
 }
 
-fn get_duration_with_lofty(path: &std::path::Path) -> i64 {
-    match lofty::read_from_path(path) {
-        Ok(tagged_file) => {
-            tagged_file.properties().duration().as_secs() as i64
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(byte) = bytes.get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            decoded.push(byte);
+            i += 3;
+            continue;
         }
-        Err(_) => 0,
+        decoded.push(bytes[i]);
+        i += 1;
     }
+    String::from_utf8_lossy(&decoded).to_string()
 }
 
-fn extract_song_name_from_filename(filename: &str) -> Option<String> {
-    // Remove extension
-    let file_stem = std::path::Path::new(filename)
-        .file_stem()
-        .and_then(|s| s.to_str())?;
-    // Split on " - " and take the second part as song name
-    let parts1: Vec<&str> = file_stem.split(" - ").collect();
-    let parts2: Vec<&str> = file_stem.split(" － ").collect();
-    if parts1.len() > 1 {
-        return Some(parts1[1].to_string());
-    }
-    else if parts2.len() > 1 {
-        return Some(parts2[1].to_string());
-        
+fn encode_file_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            uri.push(byte as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", byte));
+        }
     }
-    else {
-        return None;
+    uri
+}
+
+fn resolve_playlist_entry(playlist_dir: &Path, entry: &str) -> PathBuf {
+    // Handle relative and absolute paths
+    let entry = Path::new(entry);
+    if entry.is_absolute() {
+        entry.to_path_buf()
+    } else {
+        playlist_dir.join(entry)
     }
 }
 
+// Resolve the track paths referenced by a playlist of any supported format
+fn parse_playlist(path: &Path) -> Vec<PathBuf> {
+    let Some(format) = PlaylistFormat::from_path(path) else {
+        return Vec::new();
+    };
+    let content = std::fs::read_to_string(path).unwrap_or_default();
+    let playlist_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    playlist_entry_spans(format, &content)
+        .into_iter()
+        .map(|(_, entry)| resolve_playlist_entry(playlist_dir, &entry))
+        .collect()
+}
+
+// Replace the entry for `target_line` with `new_line`, or remove it when `new_line` is empty
 fn update_playlist_line(playlist_path: &str, target_line: &str, new_line: &str) -> std::io::Result<()> {
     let content = std::fs::read_to_string(playlist_path)?;
     let playlist_dir = Path::new(playlist_path).parent().unwrap_or_else(|| Path::new(""));
+    let format = PlaylistFormat::from_path(Path::new(playlist_path)).unwrap_or(PlaylistFormat::M3u);
 
     // Convert target_line and new_line to relative paths (if possible)
     let target_path = Path::new(target_line);
@@ -1061,27 +7220,95 @@ fn update_playlist_line(playlist_path: &str, target_line: &str, new_line: &str)
     let new_path = Path::new(new_line);
     let new_rel = new_path.strip_prefix(playlist_dir).unwrap_or(new_path);
 
-    let mut replaced = false;
-    let mut new_lines = Vec::new();
-    for line in content.lines() {
-        let line_path = Path::new(line.trim());
-        let line_rel = line_path.strip_prefix(playlist_dir).unwrap_or(line_path);
-
-        if !replaced && line_rel == target_rel {
-            new_lines.push(new_rel.to_string_lossy().to_string());
-            replaced = true;
-        } else {
-            new_lines.push(line.to_string());
-        }
-    }
-    let new_content = new_lines.join("\n");
+    let target = playlist_entry_spans(format, &content).into_iter().find(|(_, entry)| {
+        let entry_path = Path::new(entry);
+        entry_path.strip_prefix(playlist_dir).unwrap_or(entry_path) == target_rel
+    });
     println!("Updating playlist: {} -> {}", target_rel.display(), new_rel.display());
-    if !replaced {
+    let Some((span, _)) = target else {
         println!("{}", format!("Warning: Target line '{}' not found in playlist '{}'", target_rel.display(), playlist_path).yellow());
         return Ok(());
+    };
+
+    let (range, replacement) = if new_line.is_empty() {
+        (playlist_removal_range(format, &content, span), String::new())
+    } else if format == PlaylistFormat::Xspf {
+        (span, encode_file_uri(new_path))
+    } else {
+        (span, new_rel.to_string_lossy().to_string())
+    };
+
+    let mut new_content = String::with_capacity(content.len());
+    new_content.push_str(&content[..range.start]);
+    new_content.push_str(&replacement);
+    new_content.push_str(&content[range.end..]);
+    let result = std::fs::write(playlist_path, new_content);
+    log_mutation("write-playlist", &[Path::new(playlist_path)], &result);
+    result
+}
+
+// The text to delete when removing an entry: its whole line (plus a preceding #EXTINF), or
+// the enclosing <track> element for XSPF
+fn playlist_removal_range(format: PlaylistFormat, content: &str, span: std::ops::Range<usize>) -> std::ops::Range<usize> {
+    if format == PlaylistFormat::Xspf {
+        return xspf_locations(content)
+            .into_iter()
+            .find(|location| location.span == span)
+            .map_or(span, |location| location.track);
+    }
+
+    let mut start = content[..span.start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let end = content[span.end..].find('\n').map(|i| span.end + i + 1).unwrap_or(content.len());
+    if format == PlaylistFormat::M3u && start > 0 {
+        let previous_start = content[..start - 1].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        if content[previous_start..start].trim_start().starts_with("#EXTINF") {
+            start = previous_start;
+        }
+    }
+    start..end
+}
+
+// Tracks belong to the same album when they share an album title and album artist, or,
+// without an album artist, an album title and source folder
+fn album_group_key(track: &IndexedTrack) -> (String, String) {
+    let owner = if track.albumartist.trim().is_empty() {
+        Path::new(&track.path).parent().map(|p| p.to_string_lossy().to_string()).unwrap_or_default()
+    } else {
+        track.albumartist.trim().to_lowercase()
+    };
+    (track.album.trim().to_lowercase(), owner)
+}
+
+// The directory artist for each compilation album: its album artist, or the various artists
+// label when the album has none but its tracks have different artists
+fn compilation_album_artists(tracks: &[IndexedTrack], settings: &Settings) -> HashMap<(String, String), String> {
+    let mut albums: HashMap<(String, String), Vec<&IndexedTrack>> = HashMap::new();
+    for track in tracks.iter().filter(|t| !t.album.trim().is_empty()) {
+        albums.entry(album_group_key(track)).or_default().push(track);
+    }
+
+    albums
+        .into_iter()
+        .filter_map(|(key, album_tracks)| {
+            let albumartist = album_tracks[0].albumartist.trim();
+            let mixed_artists = album_tracks
+                .iter()
+                .any(|t| !t.artist.trim().eq_ignore_ascii_case(album_tracks[0].artist.trim()));
+            if albumartist.is_empty() || settings.is_various_artists(albumartist) {
+                mixed_artists.then(|| (key, settings.various_artists_label().to_string()))
+            } else {
+                mixed_artists.then(|| (key, albumartist.to_string()))
+            }
+        })
+        .collect()
+}
+
+// Use the album artist in place of the track artist in the directory part of a pattern
+fn compilation_pattern(pattern: &str) -> String {
+    match pattern.rsplit_once('/') {
+        Some((dirs, file)) => format!("{}/{}", dirs.replace("{artist}", "{albumartist}"), file),
+        None => pattern.to_string(),
     }
-    std::fs::write(playlist_path, new_content)?;
-    Ok(())
 }
 
 fn generate_path_from_pattern(
@@ -1094,8 +7321,8 @@ fn generate_path_from_pattern(
     replacements: &Option<HashMap<String, String>>,
 ) -> String {
     let artist_sanitized = sanitize_filename_component(artist, replacements);
-    // Use artist as albumartist if albumartist is empty or "Various Artists", otherwise use albumartist
-    let albumartist_sanitized = if albumartist.trim().is_empty() || albumartist.trim().eq_ignore_ascii_case("Various Artists") {
+    // Use artist as albumartist if albumartist is empty, otherwise use albumartist
+    let albumartist_sanitized = if albumartist.trim().is_empty() {
         sanitize_filename_component(artist, replacements)
     } else {
         sanitize_filename_component(albumartist, replacements)
@@ -1112,9 +7339,25 @@ fn generate_path_from_pattern(
         .replace("{ext}", &ext_sanitized)
 }
 
+#[derive(Serialize)]
+struct GenreCount {
+    genre: String,
+    tracks: usize,
+}
+
+impl Renderable for GenreCount {
+    fn headers() -> &'static [&'static str] {
+        &["Genre", "Tracks"]
+    }
+
+    fn cells(&self) -> Vec<String> {
+        vec![self.genre.clone(), self.tracks.to_string()]
+    }
+}
+
 fn list_genres(db_path: &str) {
     let db_path = expand_tilde(db_path);
-    let conn = rusqlite::Connection::open(&db_path).expect("Failed to open database");
+    let conn = open_db(&db_path);
 
     let mut stmt = conn.prepare(
         "SELECT genre FROM tracks WHERE genre != ''"
@@ -1133,14 +7376,23 @@ fn list_genres(db_path: &str) {
         }
     }
 
-    if counts.is_empty() {
+    let mut sorted: Vec<(String, usize)> = counts.into_iter().collect();
+    sorted.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let format = output_format();
+    if format != OutputFormat::Text {
+        let genres: Vec<GenreCount> = sorted
+            .into_iter()
+            .map(|(genre, tracks)| GenreCount { genre, tracks })
+            .collect();
+        render(&genres, format);
+        return;
+    }
+    if sorted.is_empty() {
         println!("{}", "No genres found.".yellow());
         return;
     }
 
-    let mut sorted: Vec<(String, usize)> = counts.into_iter().collect();
-    sorted.sort_by(|a, b| a.0.cmp(&b.0));
-
     for (genre, count) in sorted {
         println!("{:<30} {}", genre.bold(), format!("({} tracks)", count).yellow());
     }
@@ -1187,9 +7439,16 @@ fn export_playlists_for_compressed(
         let playlist_path_obj = Path::new(&playlist_path);
         let playlist_dir = playlist_path_obj.parent().unwrap_or_else(|| Path::new(""));
 
+        // Other formats are flattened to their track paths since the export is always m3u
+        let lines: Vec<String> = if PlaylistFormat::from_path(playlist_path_obj) == Some(PlaylistFormat::M3u) {
+            content.lines().map(str::to_string).collect()
+        } else {
+            parse_playlist(playlist_path_obj).iter().map(|p| p.to_string_lossy().to_string()).collect()
+        };
+
         // Process each line and update paths
         let mut updated_lines = Vec::new();
-        for line in content.lines() {
+        for line in &lines {
             let trimmed = line.trim();
 
             // Keep comments and empty lines as-is
@@ -1198,12 +7457,7 @@ fn export_playlists_for_compressed(
                 continue;
             }
 
-            // Resolve the path (handle relative and absolute paths)
-            let song_path = if Path::new(trimmed).is_absolute() {
-                PathBuf::from(trimmed)
-            } else {
-                playlist_dir.join(trimmed)
-            };
+            let song_path = resolve_playlist_entry(playlist_dir, trimmed);
 
             // Try to make it relative to music_dir to get the relative structure
             let relative_to_music = match song_path.strip_prefix(music_dir) {
@@ -1234,11 +7488,11 @@ fn export_playlists_for_compressed(
         let output_playlist_path = PathBuf::from(output_dir).join(format!("{}.m3u", name));
 
         // Create parent directory if needed
-        if let Some(parent) = output_playlist_path.parent() {
-            if let Err(e) = std::fs::create_dir_all(parent) {
-                eprintln!("Failed to create directory for playlist '{}': {}", name, e);
-                continue;
-            }
+        if let Some(parent) = output_playlist_path.parent()
+            && let Err(e) = std::fs::create_dir_all(parent)
+        {
+            eprintln!("Failed to create directory for playlist '{}': {}", name, e);
+            continue;
         }
 
         match std::fs::write(&output_playlist_path, updated_lines.join("\n") + "\n") {
@@ -1248,6 +7502,7 @@ fn export_playlists_for_compressed(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn compress_tracks(
     music_dir: &str,
     db_path: &str,
@@ -1270,9 +7525,7 @@ fn compress_tracks(
         .status()
         .is_err()
     {
-        eprintln!("{}", "Error: ffmpeg is not installed or not in PATH".red());
-        eprintln!("Please install ffmpeg to use the compress command");
-        return;
+        exit_with_error(EXIT_ERROR, "ffmpeg is not installed or not in PATH. Please install ffmpeg to use the compress command");
     }
 
     // Set up thread pool if jobs specified
@@ -1283,7 +7536,7 @@ fn compress_tracks(
             .ok();
     }
 
-    let conn = rusqlite::Connection::open(&db_path).expect("Failed to open database");
+    let conn = open_db(&db_path);
 
     // Query tracks based on optional filter
     let (query_sql, pattern) = if let Some(ref q) = query {
@@ -1316,7 +7569,7 @@ fn compress_tracks(
         return;
     }
 
-    let thread_count = jobs.unwrap_or_else(|| num_cpus::get());
+    let thread_count = jobs.unwrap_or_else(num_cpus::get);
     println!(
         "Compressing {} tracks to {} as {} at {} bitrate (using {} threads)...\n",
         paths.len(), output_dir, format, bitrate, thread_count
@@ -1326,12 +7579,7 @@ fn compress_tracks(
     let multi_progress = Arc::new(MultiProgress::new());
 
     // Main progress bar
-    let main_pb = Arc::new(multi_progress.add(ProgressBar::new(paths.len() as u64)));
-    main_pb.set_style(
-        ProgressStyle::with_template("[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({per_sec}) - {msg}")
-            .unwrap()
-            .progress_chars("##-"),
-    );
+    let main_pb = Arc::new(multi_progress.add(make_progress_bar(paths.len())));
 
     // Worker status bars (limit to 8 for cleaner display)
     let worker_count = thread_count.min(8);
@@ -1393,13 +7641,13 @@ fn compress_tracks(
         output_path.set_extension(format);
 
         // Create parent directory if needed
-        if let Some(parent) = output_path.parent() {
-            if let Err(_) = std::fs::create_dir_all(parent) {
-                *failed_count.lock().unwrap() += 1;
-                failed_files.lock().unwrap().push(source_path.clone());
-                main_pb_clone.inc(1);
-                return;
-            }
+        if let Some(parent) = output_path.parent()
+            && std::fs::create_dir_all(parent).is_err()
+        {
+            *failed_count.lock().unwrap() += 1;
+            failed_files.lock().unwrap().push(source_path.clone());
+            main_pb_clone.inc(1);
+            return;
         }
 
         // Skip if output already exists (unless force is enabled)
@@ -1420,7 +7668,7 @@ fn compress_tracks(
 
         // Build ffmpeg command
         let mut cmd = std::process::Command::new("ffmpeg");
-        cmd.arg("-i").arg(&source_path);
+        cmd.arg("-i").arg(source_path);
 
         // Set codec and bitrate based on format
         match format {
@@ -1509,42 +7757,353 @@ fn compress_tracks(
 }
 
 fn main() {
-    let settings = load_settings();
+    let args = Cli::parse();
+    if args.no_color || std::env::var_os("NO_COLOR").is_some() || !std::io::stdout().is_terminal() {
+        COLOR_ENABLED.store(false, Ordering::Relaxed);
+    }
+    OUTPUT_FORMAT.set(OutputFormat::from_arg(&args.output_format)).ok();
+    let mut command = args.command;
+    // First run: offer the wizard instead of failing on the missing config
+    let setup_requested = matches!(command, Commands::Setup);
+    if setup_requested || (!config_file().exists() && std::io::stdin().is_terminal()) {
+        match run_setup() {
+            Some(true) => command = Commands::Index {
+                path: None,
+                organize: false,
+                dry_run: false,
+                preview_tree: false,
+                keep_going: false,
+                fail_fast: false,
+                checksum: false,
+                parallel_io_limit: None,
+                confirm_auto: false,
+                strict: false,
+                on_conflict: None,
+            },
+            Some(false) if setup_requested => std::process::exit(EXIT_SUCCESS),
+            Some(false) => {}
+            None => exit_with_error(EXIT_CONFIG, "setup cancelled"),
+        }
+    }
+
+    let mut settings = load_settings();
+    if let Some(db) = &args.db {
+        settings.files.database_name = expand_tilde(db);
+    }
+    if let Some(music_dir) = &args.music_dir {
+        settings.files.music_directory = expand_tilde(music_dir);
+    }
+    if let Some(separator) = args.artist_sep {
+        settings.artist_separator = Some(separator);
+    }
+    if args.backup_db || args.no_backup {
+        settings.files.backup_db = Some(args.backup_db);
+    }
+    PROGRESS_TEMPLATE.set(progress_template(&settings.ui)).ok();
+    BUSY_TIMEOUT.set(Duration::from_millis(settings.files.busy_timeout_ms.unwrap_or(DEFAULT_BUSY_TIMEOUT_MS))).ok();
+
+    // Changes to the in-memory copy would be thrown away on exit
+    if args.in_memory {
+        if command.mutates_library() || command.is_destructive() {
+            exit_with_error(EXIT_ERROR, "--in-memory only works with read-only commands");
+        }
+        load_db_into_memory(&expand_tilde(&settings.files.database_name));
+    }
+    if args.read_only {
+        if command.mutates_library() || command.is_destructive() {
+            exit_with_error(EXIT_ERROR, "--read-only can't be used with a command that changes the library");
+        }
+        READ_ONLY.store(true, Ordering::Relaxed);
+    }
+
+    let _lock = command.mutates_library().then(acquire_instance_lock);
+    if command.is_destructive() && settings.files.backup_db == Some(true) {
+        backup_database(&expand_tilde(&settings.files.database_name));
+    }
+
+    // A panic has already printed its message; report it as a generic error
+    let code = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| run(command, &settings)))
+        .unwrap_or(EXIT_ERROR);
+    std::process::exit(code);
+}
+
+fn similarity_metric(settings: &Settings) -> SimilarityMetric {
+    SimilarityMetric::from_config(settings.similarity_metric.as_deref())
+        .unwrap_or_else(|e| exit_with_error(EXIT_CONFIG, &e))
+}
 
+fn run(command: Commands, settings: &Settings) -> i32 {
     let music_dir = expand_tilde(&settings.files.music_directory);
     let db_path = expand_tilde(&settings.files.database_name);
 
     let db_folder = std::path::Path::new(&db_path).parent().unwrap();
     if !std::path::Path::new(&db_folder).exists() {
-        fs::create_dir_all(&db_folder).expect("Failed to create music directory");
+        fs::create_dir_all(db_folder).expect("Failed to create music directory");
     }
 
-    let args = Cli::parse();
-    match args.command {
-        Commands::Index { dry_run } => {
-            index_library(&settings, dry_run);
-            index_playlists(&music_dir, &db_path);
+    let dupe_key = settings.dupe_key.clone()
+        .unwrap_or_else(|| vec!["artist".to_string(), "title".to_string()]);
+    let artist_sep = settings.artist_separator.as_deref().filter(|sep| !sep.is_empty());
+    if command.mutates_library() {
+        invalidate_stats_cache(&db_path);
+    }
+
+    match command {
+        Commands::Index { path, organize, dry_run, preview_tree, fail_fast, checksum, parallel_io_limit, confirm_auto, strict, on_conflict, .. } => {
+            // Every file read while indexing happens on the rayon pool, so its size caps concurrent I/O
+            if let Some(limit) = parallel_io_limit.or(settings.files.parallel_io_limit) {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(limit)
+                    .build_global()
+                    .ok();
+            }
+            let scope = path.map(|path| index_scope(&music_dir, &path).unwrap_or_else(|e| exit_with_error(EXIT_ERROR, &e)));
+            let on_conflict = OnConflict::from_arg(on_conflict.as_deref());
+            let summary = index_library(settings, scope.as_deref(), organize, dry_run, preview_tree, fail_fast, checksum, strict, on_conflict);
+            if scope.is_none() {
+                let repair = PlaylistRepair { confirm_auto, artist_sep, metric: similarity_metric(settings), ..Default::default() };
+                index_playlists(&music_dir, &db_path, repair);
+            }
+            if let Some(hook) = settings.post_index_hook.as_deref().filter(|hook| !hook.trim().is_empty()) {
+                run_post_index_hook(hook, &db_path, &summary);
+            }
+        }
+        Commands::Dupes { ignore, clear_ignores, .. } if !ignore.is_empty() || clear_ignores => {
+            update_dupe_ignores(&db_path, &ignore, clear_ignores);
+        }
+        Commands::Dupes { promote: true, dry_run, .. } => {
+            promote_quality_dupes(settings, &dupe_key, dry_run);
+        }
+        Commands::Dupes { by_filename: true, min_count, sort_by_count, .. } => {
+            if print_duplicates_by_filename(&db_path, DupeGroups { min_count, sort_by_count }) {
+                return EXIT_ISSUES_FOUND;
+            }
+        }
+        Commands::Dupes { json, fix: false, min_count, sort_by_count, .. } if json || output_format() != OutputFormat::Text => {
+            let format = if json { OutputFormat::Json } else { output_format() };
+            if print_duplicates_structured(&db_path, &dupe_key, format, DupeGroups { min_count, sort_by_count }) {
+                return EXIT_ISSUES_FOUND;
+            }
+        }
+        Commands::Dupes { by_folder: true, min_count, sort_by_count, .. } => {
+            if print_duplicates_by_folder(&db_path, &dupe_key, DupeGroups { min_count, sort_by_count }) {
+                return EXIT_ISSUES_FOUND;
+            }
+        }
+        Commands::Dupes { fix, min_count, sort_by_count, .. } => {
+            // After --fix the remaining groups were reviewed, so only a plain scan reports issues
+            if find_duplicates(&db_path, fix, &dupe_key, DupeGroups { min_count, sort_by_count }) && !fix {
+                return EXIT_ISSUES_FOUND;
+            }
+        }
+        Commands::Backfill { since, before } => {
+            backfill(&db_path, since.as_deref(), before.as_deref());
+        }
+        Commands::Ls { query, genre, min_rating, group_albums: true, .. } if output_format() == OutputFormat::Text => {
+            list_albums(&db_path, query.as_deref(), genre.as_deref(), min_rating.unwrap_or(0), &settings.unknown_label);
+        }
+        Commands::Ls { query, genre, show_gain, notes, min_rating, .. } => {
+            list_tracks(&db_path, query, genre, show_gain, notes, min_rating.unwrap_or(0), &settings.unknown_label);
         }
-        Commands::Dupes { fix } => {
-            find_duplicates(&db_path, fix);
+        Commands::Export { archive: Some(archive), .. } => {
+            export_archive(&db_path, &archive);
         }
-        Commands::Ls { query, genre } => {
-            list_tracks(&db_path, query, genre);
+        Commands::Export { bom, delimiter, columns, archive: None } => {
+            export_tracks(&db_path, bom, delimiter, columns.as_deref());
         }
-        Commands::Export => {
-            export_tracks(&db_path);
+        Commands::Import { archive, force, relocate, on_duplicate } => {
+            import_archive(&db_path, &archive, force, &relocate, OnDuplicate::from_arg(&on_duplicate));
         }
-        Commands::Stats => {
-            get_stats(&music_dir, &db_path);
+        Commands::Stats { outliers, formats, activity, weeks, duplicates_summary, artists_without_album, per_year_added, by_decade, by_artist, primary_artist, incomplete_albums, longest, shortest, refresh, histogram, silence, silence_limit } => {
+            // A structured document holds one kind of row: the totals, or the one view asked for
+            let views = [
+                formats, activity, duplicates_summary, artists_without_album, per_year_added, by_decade, by_artist,
+                incomplete_albums, histogram.is_some(), silence, longest.is_some(), shortest.is_some(), outliers,
+            ];
+            let view_count = views.iter().filter(|view| **view).count();
+            if output_format() == OutputFormat::Text || view_count == 0 {
+                get_stats(&music_dir, &db_path, refresh, stats_cache_ttl_secs(settings));
+            } else if view_count > 1 {
+                exit_with_error(EXIT_ERROR, "--output-format prints one Stats view at a time; pick a single view flag");
+            }
+            if formats {
+                print_format_breakdown(&db_path);
+            }
+            if activity {
+                print_activity(&db_path, weeks);
+            }
+            if duplicates_summary {
+                print_duplicates_summary(&db_path, &dupe_key);
+            }
+            if artists_without_album {
+                print_artists_without_album(&db_path);
+            }
+            if per_year_added {
+                print_time_added_per_year(&db_path);
+            }
+            if by_decade {
+                print_time_by_decade(&db_path);
+            }
+            if by_artist {
+                print_tracks_by_artist(&db_path, primary_artist, settings.artist_exceptions.as_deref().unwrap_or_default());
+            }
+            if incomplete_albums {
+                print_incomplete_albums(&db_path);
+            }
+            if let Some(dimension) = histogram {
+                print_histogram(&db_path, &dimension);
+            }
+            if silence {
+                print_silence_total(&db_path, silence_limit);
+            }
+            if let Some(limit) = longest {
+                print_tracks_by_length(&db_path, limit, true);
+            }
+            if let Some(limit) = shortest {
+                print_tracks_by_length(&db_path, limit, false);
+            }
+            if outliers {
+                let stats_config = settings.stats.as_ref();
+                let min_secs = stats_config.and_then(|s| s.outlier_min_secs).unwrap_or(30);
+                let max_secs = stats_config.and_then(|s| s.outlier_max_secs).unwrap_or(20 * 60);
+                print_duration_outliers(&db_path, min_secs, max_secs);
+            }
         }
-        Commands::Search { query } => {
-            search_tracks(&db_path, Some(query));
+        Commands::Search { query, regex } => {
+            search_tracks(&db_path, &query, regex);
         }
         Commands::Genres => {
             list_genres(&db_path);
         }
+        // Handled in main before the config is loaded
+        Commands::Setup => {}
         Commands::Compress { output_dir, format, bitrate, jobs, force, query } => {
             compress_tracks(&music_dir, &db_path, &output_dir, &format, &bitrate, jobs, force, query);
         }
+        Commands::PlaylistAdd { playlist, track_query } => {
+            let absolute_paths = settings.files.playlist_paths.as_deref() == Some("absolute");
+            add_to_playlist(&db_path, &playlist, &track_query, absolute_paths);
+        }
+        Commands::Playlists { top } => {
+            print_playlist_overlap(&db_path, top);
+        }
+        Commands::Verify { checksums, fail_fast } => {
+            if verify_library(&db_path, checksums, fail_fast) {
+                return EXIT_ISSUES_FOUND;
+            }
+        }
+        Commands::Doctor { album_consistency, low_quality, fix } => {
+            // With no check picked, run them all
+            let run_all = !album_consistency && !low_quality;
+            let mut issues = false;
+            if (album_consistency || run_all) && check_album_consistency(&db_path, fix) && !fix {
+                issues = true;
+            }
+            if (low_quality || run_all) && check_low_quality(&db_path, &settings.quality) {
+                issues = true;
+            }
+            if issues {
+                return EXIT_ISSUES_FOUND;
+            }
+        }
+        Commands::Fav { action } => match action {
+            FavAction::Add { query } => favorite_add(&db_path, &query),
+            FavAction::Rm { query } => favorite_remove(&db_path, &query),
+            FavAction::Ls => list_favorites(&db_path),
+            FavAction::Playlist { output } => {
+                let absolute_paths = settings.files.playlist_paths.as_deref() == Some("absolute");
+                write_favorites_playlist(&db_path, &output, absolute_paths);
+            }
+        },
+        Commands::Note { action } => match action {
+            NoteAction::Set { query, text } => note_set(&db_path, &query, &text),
+            NoteAction::Show { query } => note_show(&db_path, &query),
+        },
+        Commands::Preview { query, seconds, from } => {
+            preview_track(&db_path, &query, seconds, from);
+        }
+        Commands::RepairPlaylists { auto, dry_run, after_move } => {
+            repair_playlists(&db_path, PlaylistRepair {
+                auto_only: auto,
+                dry_run,
+                after_move,
+                artist_sep,
+                metric: similarity_metric(settings),
+                ..Default::default()
+            });
+        }
+        Commands::ReviewAutofixes { revert } => {
+            review_autofixes(&db_path, &revert);
+        }
+        Commands::TagFromFilename { dry_run } => {
+            tag_from_filename(&db_path, dry_run, artist_sep);
+        }
+        Commands::Normalize { dry_run, yes, strip_disc_from_title, fix_encoding, canonicalize_feat } => {
+            normalize_tags(settings, dry_run, yes, strip_disc_from_title, fix_encoding, canonicalize_feat);
+        }
+        Commands::Trim { max_size, max_duration, dry_run, yes } => {
+            trim_tracks(settings, max_size, max_duration, dry_run, yes);
+        }
+        Commands::EmbedArt { dry_run, force } => {
+            embed_folder_art(&db_path, dry_run, force);
+        }
+        Commands::Analyze { resolution, force } => {
+            analyze_peaks(&db_path, resolution as usize, force);
+        }
+        Commands::Serve { host, port } => {
+            serve(settings, &host, port);
+        }
     }
-}
\ No newline at end of file
+    EXIT_SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dupe_group_paths_keep_commas() {
+        let conn = open_db(":memory:");
+        let paths = [
+            "/music/Crosby, Stills & Nash/Suite, Judy Blue Eyes.flac",
+            "/music/Crosby, Stills & Nash/Suite, Judy Blue Eyes.mp3",
+        ];
+        for path in paths {
+            conn.execute(
+                "INSERT INTO tracks (path, artist, title) VALUES (?1, 'Crosby, Stills & Nash', 'Suite, Judy Blue Eyes')",
+                [path],
+            ).unwrap();
+        }
+
+        let key = dupe_key_sql(&["artist".to_string(), "title".to_string()]).unwrap();
+        let values = ["Crosby, Stills & Nash".to_string(), "Suite, Judy Blue Eyes".to_string()];
+        assert_eq!(dupe_group_paths(&conn, &key.matcher, &values), paths);
+    }
+
+    #[test]
+    fn xspf_locations_read_cdata_attributes_and_skip_comments() {
+        let content = r#"<?xml version="1.0" encoding="UTF-8"?>
+<playlist version="1" xmlns="http://xspf.org/ns/0/">
+  <location>file:///playlists/mix.xspf</location>
+  <trackList>
+    <track id="1">
+      <location>file:///music/Simon%20&amp;%20Garfunkel/America.flac</location>
+    </track>
+    <!-- <track><location>file:///music/Removed.flac</location></track> -->
+    <track >
+      <location xml:base="file:///music/"><![CDATA[file:///music/A&B/Song.mp3]]></location>
+    </track>
+  </trackList>
+</playlist>
+"#;
+        let locations = xspf_locations(content);
+        let paths: Vec<_> = locations.iter().map(|location| location.path.as_str()).collect();
+        assert_eq!(paths, ["/music/Simon & Garfunkel/America.flac", "/music/A&B/Song.mp3"]);
+
+        let cdata = &locations[1];
+        assert_eq!(&content[cdata.span.clone()], "<![CDATA[file:///music/A&B/Song.mp3]]>");
+        assert!(content[cdata.track.clone()].starts_with("<track >"));
+        assert!(content[cdata.track.clone()].ends_with("</track>"));
+        assert_eq!(playlist_removal_range(PlaylistFormat::Xspf, content, cdata.span.clone()), cdata.track);
+    }
+}